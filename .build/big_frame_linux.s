@@ -0,0 +1,31 @@
+.text
+.global fill
+.type fill, %function
+.align 2
+fill:
+    mov x16, #32
+label_1:
+    sub sp, sp, #1, lsl #12
+    str x16, [sp]
+    sub x16, x16, #1
+    cbnz x16, label_1
+    sub sp, sp, #16
+    str x16, [sp]
+    mov x16, #8
+    movk x16, #2, lsl #16
+    str x0, [sp, x16]
+    add x9, sp, #8
+    mov x16, #8
+    movk x16, #2, lsl #16
+    ldr x8, [sp, x16]
+    str x8, [x9]
+    ldr x0, [x9]
+    b label_0
+label_0:
+    add sp, sp, #32, lsl #12
+    add sp, sp, #16
+    ret
+.size fill, .-fill
+
+
+.section .note.GNU-stack,"",%progbits
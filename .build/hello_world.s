@@ -3,8 +3,9 @@
 _main:
     sub sp, sp, #16
     stp x29, x30, [sp]
-    add x29, sp, #16
-    adr x0, local_data_0
+    add x29, sp, #0
+    adrp x0, local_data_0@PAGE
+    add x0, x0, local_data_0@PAGEOFF
     bl _printf
     mov w0, #0
     b label_0
@@ -13,6 +14,8 @@ label_0:
     add sp, sp, #16
     ret
 
+.section __TEXT,__cstring,cstring_literals
 local_data_0:
     .asciz "Hello, World!\n"
+.text
 
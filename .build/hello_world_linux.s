@@ -0,0 +1,25 @@
+.text
+.global main
+.type main, %function
+.align 2
+main:
+    sub sp, sp, #16
+    stp x29, x30, [sp]
+    add x29, sp, #0
+    adrp x0, local_data_0
+    add x0, x0, :lo12:local_data_0
+    bl printf
+    mov w0, #0
+    b label_0
+label_0:
+    ldp x29, x30, [sp]
+    add sp, sp, #16
+    ret
+.size main, .-main
+
+.section .rodata
+local_data_0:
+    .asciz "Hello, World!\n"
+.text
+
+.section .note.GNU-stack,"",%progbits
@@ -0,0 +1,22 @@
+.intel_syntax noprefix
+.text
+
+.global main
+main:
+    push rbp
+    mov rbp, rsp
+    lea rdi, [rip + local_data_0]
+    xor eax, eax
+    call printf
+    mov eax, 0
+    jmp label_0
+label_0:
+    pop rbp
+    ret
+
+.section .rodata
+local_data_0:
+    .asciz "Hello, World!\n"
+.text
+
+.section .note.GNU-stack,"",@progbits
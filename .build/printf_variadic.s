@@ -0,0 +1,27 @@
+.global _main
+.align 2
+_main:
+    sub sp, sp, #48
+    stp x29, x30, [sp, #32]
+    add x29, sp, #32
+    adrp x0, local_data_0@PAGE
+    add x0, x0, local_data_0@PAGEOFF
+    mov w1, #2
+    mov w2, #3
+    mov x3, #5
+    str w1, [sp]
+    str w2, [sp, #8]
+    str x3, [sp, #16]
+    bl _printf
+    mov w0, #0
+    b label_0
+label_0:
+    ldp x29, x30, [sp, #32]
+    add sp, sp, #48
+    ret
+
+.section __TEXT,__cstring,cstring_literals
+local_data_0:
+    .asciz "%d + %d = %ld\n"
+.text
+
@@ -0,0 +1,31 @@
+.text
+.global offset
+.type offset, %function
+.align 2
+offset:
+    sub sp, sp, #48
+    str x0, [sp, #16]
+    str x1, [sp, #24]
+    str x2, [sp, #8]
+    add x11, sp, #16
+    ldr x10, [sp, #8]
+    ldr x9, [x11]
+    add x8, x9, x10
+    str x8, [x11]
+    ldr x9, [x11, #8]
+    add x8, x9, x10
+    str x8, [x11, #8]
+    ldr x16, [x11]
+    str x16, [sp, #32]
+    ldr x16, [x11, #8]
+    str x16, [sp, #40]
+    ldr x0, [sp, #32]
+    ldr x1, [sp, #40]
+    b label_0
+label_0:
+    add sp, sp, #48
+    ret
+.size offset, .-offset
+
+
+.section .note.GNU-stack,"",%progbits
@@ -0,0 +1,82 @@
+.text
+.global sum_3
+.type sum_3, %function
+.align 2
+sum_3:
+    sub sp, sp, #240
+    stp x0, x1, [sp, #48]
+    stp x2, x3, [sp, #64]
+    stp x4, x5, [sp, #80]
+    stp x6, x7, [sp, #96]
+    str d0, [sp, #112]
+    str d1, [sp, #128]
+    str d2, [sp, #144]
+    str d3, [sp, #160]
+    str d4, [sp, #176]
+    str d5, [sp, #192]
+    str d6, [sp, #208]
+    str d7, [sp, #224]
+    str w0, [sp, #44]
+    add x9, sp, #8
+    add x16, sp, #240
+    str x16, [x9]
+    add x16, sp, #112
+    str x16, [x9, #8]
+    add x16, sp, #240
+    str x16, [x9, #16]
+    mov w16, #65480
+    movk w16, #65535, lsl #16
+    str w16, [x9, #24]
+    mov w16, #65408
+    movk w16, #65535, lsl #16
+    str w16, [x9, #28]
+    ldrsw x17, [x9, #24]
+    tbz w17, #31, label_1
+    add w16, w17, #8
+    str w16, [x9, #24]
+    ldr x16, [x9, #8]
+    add x16, x16, x17
+    b label_2
+label_1:
+    ldr x16, [x9]
+    add x17, x16, #8
+    str x17, [x9]
+label_2:
+    ldr x10, [x16]
+    ldrsw x17, [x9, #24]
+    tbz w17, #31, label_3
+    add w16, w17, #8
+    str w16, [x9, #24]
+    ldr x16, [x9, #8]
+    add x16, x16, x17
+    b label_4
+label_3:
+    ldr x16, [x9]
+    add x17, x16, #8
+    str x17, [x9]
+label_4:
+    ldr x8, [x16]
+    ldrsw x17, [x9, #28]
+    tbz w17, #31, label_5
+    add w16, w17, #16
+    str w16, [x9, #28]
+    ldr x16, [x9, #16]
+    add x16, x16, x17
+    b label_6
+label_5:
+    ldr x16, [x9]
+    add x17, x16, #8
+    str x17, [x9]
+label_6:
+    ldr d16, [x16]
+    add x9, x10, x8
+    fcvtzs x8, d16
+    add x0, x9, x8
+    b label_0
+label_0:
+    add sp, sp, #240
+    ret
+.size sum_3, .-sum_3
+
+
+.section .note.GNU-stack,"",%progbits
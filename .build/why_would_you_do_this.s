@@ -9,10 +9,10 @@ _why_would_you_do_this:
     str x5, [sp, #32]
     strh w6, [sp, #30]
     str w7, [sp, #24]
-    ldr x9, [sp, #64]
-    str x9, [sp, #16]
-    ldrsh w9, [sp, #72]
-    strh w9, [sp, #14]
+    ldr x16, [sp, #64]
+    str x16, [sp, #16]
+    ldrsh w16, [sp, #72]
+    strh w16, [sp, #14]
     b label_0
 label_0:
     add sp, sp, #64
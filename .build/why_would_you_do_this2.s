@@ -9,10 +9,10 @@ _why_would_you_do_this:
     str w5, [sp, #24]
     str w6, [sp, #20]
     str w7, [sp, #16]
-    ldr w9, [sp, #48]
-    str w9, [sp, #12]
-    ldr w9, [sp, #52]
-    str w9, [sp, #8]
+    ldr w16, [sp, #48]
+    str w16, [sp, #12]
+    ldr w16, [sp, #52]
+    str w16, [sp, #8]
     b label_0
 label_0:
     add sp, sp, #48
@@ -21,8 +21,8 @@ label_0:
 
 .align 2
 _main:
-    sub sp, sp, #16
-    stp x29, x30, [sp]
+    sub sp, sp, #32
+    stp x29, x30, [sp, #16]
     add x29, sp, #16
     mov w0, #0
     mov w1, #1
@@ -32,16 +32,16 @@ _main:
     mov w5, #5
     mov w6, #6
     mov w7, #7
-    mov w8, #8
-    mov w9, #9
-    str w8, [sp]
-    str w9, [sp, #4]
+    mov w9, #8
+    mov w8, #9
+    str w9, [sp]
+    str w8, [sp, #4]
     bl _why_would_you_do_this
     mov w0, #0
     b label_1
 label_1:
-    ldp x29, x30, [sp]
-    add sp, sp, #16
+    ldp x29, x30, [sp, #16]
+    add sp, sp, #32
     ret
 
 
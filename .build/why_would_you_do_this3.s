@@ -9,7 +9,7 @@ label_0:
 _why_would_you_do_this:
     sub sp, sp, #64
     stp x29, x30, [sp, #48]
-    add x29, sp, #16
+    add x29, sp, #48
     str w0, [sp, #44]
     str w1, [sp, #40]
     str w2, [sp, #36]
@@ -18,10 +18,10 @@ _why_would_you_do_this:
     str w5, [sp, #24]
     str w6, [sp, #20]
     str w7, [sp, #16]
-    ldr w9, [sp, #64]
-    str w9, [sp, #12]
-    ldr w9, [sp, #68]
-    str w9, [sp, #8]
+    ldr w16, [sp, #64]
+    str w16, [sp, #12]
+    ldr w16, [sp, #68]
+    str w16, [sp, #8]
     bl _dummy
     b label_1
 label_1:
@@ -32,8 +32,8 @@ label_1:
 
 .align 2
 _main:
-    sub sp, sp, #16
-    stp x29, x30, [sp]
+    sub sp, sp, #32
+    stp x29, x30, [sp, #16]
     add x29, sp, #16
     mov w0, #0
     mov w1, #1
@@ -43,16 +43,16 @@ _main:
     mov w5, #5
     mov w6, #6
     mov w7, #7
-    mov w8, #8
-    mov w9, #9
-    str w8, [sp]
-    str w9, [sp, #4]
+    mov w9, #8
+    mov w8, #9
+    str w9, [sp]
+    str w8, [sp, #4]
     bl _why_would_you_do_this
     mov w0, #0
     b label_2
 label_2:
-    ldp x29, x30, [sp]
-    add sp, sp, #16
+    ldp x29, x30, [sp, #16]
+    add sp, sp, #32
     ret
 
 
@@ -1,76 +1,253 @@
 use core::fmt;
-use std::{
-    collections::HashMap,
-    fs::File,
-    io::{self, Write},
-};
+use std::collections::HashMap;
 
 use crate::{
-    ir::{self, DataAddr, Size},
-    util::{Iota, RegisterAllocator},
+    ir::{self, Data, DataAddr, InstructionKind, Section, Size},
+    target::{
+        self, DataDirective, DataEmitter, DataSymbol, Emitter, FrameLayout, MachineRegister,
+        RegisterClass, Target,
+    },
+    util::Iota,
 };
 
-pub struct Asm {
-    instructions: Vec<Instruction>,
-    lbl_iota: Iota,
+/// Object file format, and with it the assembler dialect, the assembly is written for
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub enum ObjectFormat {
+    /// Apple platforms
+    MachO,
+    /// Linux and other ELF platforms
+    Elf,
 }
 
-impl Asm {
-    pub(crate) fn new() -> Self {
+/// The arm64 target
+pub struct Arm64 {
+    format: ObjectFormat,
+    stack_guard_size: u32,
+}
+
+impl Arm64 {
+    pub fn new(format: ObjectFormat) -> Self {
         Self {
-            instructions: Vec::new(),
-            lbl_iota: Iota::new(),
+            format,
+            stack_guard_size: 4096,
         }
     }
 
-    pub(crate) fn from_module(module: &mut ir::Module) -> Self {
-        let mut asm = Self::new();
+    /// Frames bigger than `size` are probed every `size` bytes as they are allocated, so they
+    /// can not jump over a guard region of that size below the stack. Defaults to 4 KiB
+    pub fn with_stack_guard_size(mut self, size: u32) -> Self {
+        assert!(
+            size != 0 && size.is_multiple_of(4096) && size >> 24 == 0,
+            "Stack guard size has to be a multiple of 4 KiB below 16 MiB, not {size}"
+        );
+
+        self.stack_guard_size = size;
+        self
+    }
+}
 
-        for func in module.funcs() {
-            let reg_map =
-                RegisterAllocator::new().allocate(func.instructions(), usable_registers());
+impl Target for Arm64 {
+    fn allocatable_registers(&self, class: RegisterClass) -> Vec<u8> {
+        let registers = match class {
+            RegisterClass::Integer => usable_registers(),
+            RegisterClass::Float => usable_float_registers(),
+        };
 
-            let offsets = func.generate_stack_slot_offsets();
-            let return_label = Label::new(asm.lbl_iota.next());
+        registers.into_iter().map(|number| number as u8).collect()
+    }
 
-            // func prologue
-            asm.generate_func_prologue(&func, &offsets);
+    fn arg_register(&self, arg_num: usize, class: RegisterClass) -> Option<u8> {
+        let size = match class {
+            RegisterClass::Integer => Size::QuadWord,
+            RegisterClass::Float => Size::F64,
+        };
 
-            for inst in func.instructions() {
-                asm.add_inst(func.is_leaf(), inst, &reg_map, &offsets, return_label);
-            }
+        arg_register(arg_num as u8, size).map(|reg| reg.number() as u8)
+    }
 
-            // func epilogue
-            asm.generate_func_epilogue(&func, return_label);
+    fn return_register(&self, class: RegisterClass) -> u8 {
+        match class {
+            RegisterClass::Integer => RegisterNumber::R0 as u8,
+            RegisterClass::Float => RegisterNumber::V0 as u8,
+        }
+    }
 
-            // Spacing between functions
-            asm.instructions.push(Instruction::Empty);
+    fn clobbered_registers(&self, kind: InstructionKind) -> Vec<u8> {
+        match kind {
+            InstructionKind::Call => caller_saved_registers()
+                .into_iter()
+                .map(|number| number as u8)
+                .collect(),
+            _ => Vec::new(),
         }
+    }
+
+    fn callee_saved_registers(&self) -> Vec<u8> {
+        callee_saved_registers()
+            .into_iter()
+            .map(|number| number as u8)
+            .collect()
+    }
 
-        let data = module.data();
+    fn emitter(&self) -> Box<dyn Emitter> {
+        Box::new(Asm::new(self.format, self.stack_guard_size))
+    }
+}
+
+struct Asm {
+    format: ObjectFormat,
+    stack_guard_size: u32,
+    instructions: Vec<Instruction>,
+    lbl_iota: Iota,
+    // Labels of the function currently being generated
+    func_labels: HashMap<ir::Label, Label>,
+    local_data_iota: Iota,
+    // Local data of the function currently being generated
+    func_local_data: HashMap<DataAddr, DataSymbol>,
+    // The comparison whose flags are still set, used to branch with b.cond
+    last_cmp: Option<(ir::Temporary, ir::Condition)>,
+}
 
-        if data.len() != 0 {
-            // Start Data section
-            asm.instructions.push(Instruction::DataSection);
+impl Emitter for Asm {
+    fn generate_function(
+        &mut self,
+        func: &ir::Function,
+        registers: &HashMap<ir::Temporary, u8>,
+        layout: &FrameLayout,
+    ) {
+        let reg_map: HashMap<ir::Temporary, Register> = registers
+            .iter()
+            .map(|(tmp, reg)| {
+                (
+                    *tmp,
+                    Register::new(RegisterNumber::from_u8(*reg), tmp.size()),
+                )
+            })
+            .collect();
+
+        let frame = Frame::new(func, layout, self.format);
+        // Slots sit above the outgoing stack args
+        let offsets = layout
+            .slot_offsets
+            .iter()
+            .map(|(slot, offset)| (*slot, frame.slots_offset() + offset))
+            .collect();
+        let return_label = Label::new(self.lbl_iota.next());
+        self.func_labels.clear();
+        self.func_local_data.clear();
+
+        // func prologue
+        self.generate_func_prologue(func, &frame, &offsets);
+
+        for inst in func.instructions() {
+            self.add_inst(inst, &frame, &reg_map, &offsets, return_label);
+        }
 
-            for (addr, data) in data {
-                asm.instructions.push(Instruction::data_label(addr.id()));
+        // func epilogue
+        self.generate_func_epilogue(func, &frame, return_label);
 
-                match data {
-                    ir::Data::StringNullTerminated(value) => asm
-                        .instructions
-                        .push(Instruction::asciz_data(value.clone())),
-                }
+        // Spacing between functions
+        self.instructions.push(Instruction::Empty);
+    }
+
+    fn generate_data(&mut self, data: &HashMap<DataAddr, (Section, Data)>) {
+        self.add_data_items(data);
+    }
+
+    fn finish(mut self: Box<Self>) -> target::Asm {
+        // Mark the stack as non executable
+        if self.format == ObjectFormat::Elf {
+            self.instructions.push(Instruction::custom(
+                ".section .note.GNU-stack,\"\",%progbits".to_string(),
+            ));
+        }
+
+        target::Asm::new(
+            self.instructions
+                .iter()
+                .map(|inst| inst.to_string())
+                .collect(),
+        )
+    }
+}
+
+impl DataEmitter for Asm {
+    fn section_for(&self, section: Section, data: &Data) -> &'static str {
+        match (self.format, section) {
+            // Pointers are written by the loader, so they can not be in a read-only segment
+            (ObjectFormat::MachO, Section::ReadOnly) if data.has_pointers() => {
+                ".section __DATA,__const"
+            }
+            (ObjectFormat::MachO, Section::ReadOnly) => match data {
+                Data::StringNullTerminated(_) => ".section __TEXT,__cstring,cstring_literals",
+                _ => ".section __TEXT,__const",
+            },
+            (ObjectFormat::MachO, Section::Mutable) => ".data",
+            (ObjectFormat::MachO, Section::Uninitialized) => ".section __DATA,__bss",
+            // Made read-only after the loader relocates the pointers
+            (ObjectFormat::Elf, Section::ReadOnly) if data.has_pointers() => {
+                ".section .data.rel.ro,\"aw\""
             }
+            (ObjectFormat::Elf, Section::ReadOnly) => ".section .rodata",
+            (ObjectFormat::Elf, Section::Mutable) => ".data",
+            (ObjectFormat::Elf, Section::Uninitialized) => ".bss",
         }
+    }
 
-        asm
+    #[rustfmt::skip]
+    fn integer_directive(&self, size: Size) -> &'static str {
+        match size {
+            Size::Byte                   => ".byte",
+            Size::Word                   => ".hword",
+            Size::DoubleWord | Size::F32 => ".word",
+            Size::QuadWord | Size::F64   => ".quad",
+        }
+    }
+
+    fn data_symbol_for(&mut self, addr: DataAddr) -> DataSymbol {
+        if addr.is_global() {
+            return DataSymbol::Global(addr.id());
+        }
+
+        *self
+            .func_local_data
+            .entry(addr)
+            .or_insert_with(|| DataSymbol::Local(self.local_data_iota.next()))
+    }
+
+    fn push_section(&mut self, name: &'static str) {
+        self.instructions.push(Instruction::section(name));
+    }
+
+    fn push_data(&mut self, directive: DataDirective) {
+        self.instructions.push(Instruction::Data { directive });
+    }
+}
+
+impl Asm {
+    fn new(format: ObjectFormat, stack_guard_size: u32) -> Self {
+        let instructions = match format {
+            ObjectFormat::MachO => Vec::new(),
+            ObjectFormat::Elf => vec![Instruction::section(".text")],
+        };
+
+        Self {
+            format,
+            stack_guard_size,
+            instructions,
+            lbl_iota: Iota::new(),
+            func_labels: HashMap::new(),
+            local_data_iota: Iota::new(),
+            func_local_data: HashMap::new(),
+            last_cmp: None,
+        }
     }
 
     fn generate_func_prologue(
         &mut self,
         func: &ir::Function,
-        stack_slot_offsets: &HashMap<ir::StackSlot, u16>,
+        frame: &Frame,
+        stack_slot_offsets: &HashMap<ir::StackSlot, u32>,
     ) {
         // .global func_name
         if func.is_public() {
@@ -78,6 +255,14 @@ impl Asm {
                 .push(Instruction::custom(format!(".global {}", func.name())));
         }
 
+        // .type func_name, %function
+        if self.format == ObjectFormat::Elf {
+            self.instructions.push(Instruction::custom(format!(
+                ".type {}, %function",
+                func.name()
+            )));
+        }
+
         // .align 2
         self.instructions
             .push(Instruction::custom(".align 2".to_string()));
@@ -87,144 +272,548 @@ impl Asm {
             .push(Instruction::custom(format!("{}:", func.name())));
 
         // sub sp, stack size
-        let stack_size = func.stack_size();
-        let func_stack_size = stack_size + if !func.is_leaf() { 16 } else { 0 };
+        // The callee saved registers are stored first when stp can not reach them from the bottom
+        let func_stack_size = frame.size();
+        let lower_size = frame.lower_size();
+        self.allocate_stack(func_stack_size - lower_size);
 
-        if func_stack_size != 0 {
-            self.instructions.push(Instruction::sub_imm(
-                Register::sp(),
+        // Store x29, x30 if needed
+        if frame.saves_fp_lr() {
+            let fp_offset = frame.fp_offset() - lower_size;
+
+            // stp x29, x30, [sp, fp offset]
+            let inst = Instruction::stp(
+                Register::x29(),
+                Register::x30(),
                 Register::sp(),
-                func_stack_size,
-            ));
-        }
+                fp_offset as u16,
+            );
+            self.instructions.push(inst);
 
-        // Store x29, x30 if needed
-        if !func.is_leaf() {
-            // stp x29, x30, [sp, stack size]
-            let inst =
-                Instruction::stp(Register::x29(), Register::x30(), Register::sp(), stack_size);
+            // add x29, sp, fp offset
+            let inst = Instruction::add_imm(Register::x29(), Register::sp(), fp_offset);
             self.instructions.push(inst);
+        }
 
-            // add x29, sp, stack size
-            let inst = Instruction::add_imm(Register::x29(), Register::sp(), 16);
+        // Store callee saved registers
+        for (regs, offset) in frame.saved_reg_pairs() {
+            let offset = (offset - lower_size) as u16;
+            let inst = match regs {
+                [reg_1, reg_2] => Instruction::stp(*reg_1, *reg_2, Register::sp(), offset),
+                [reg] => Instruction::str(*reg, Register::sp(), offset),
+                _ => unreachable!(),
+            };
             self.instructions.push(inst);
         }
 
-        // Store args in stack slots
-        let mut arg_num = 0;
-        let mut additional_args_offset = 0;
-        #[allow(clippy::explicit_counter_loop)]
-        for arg in func.args() {
-            let offset = stack_slot_offsets.get(arg).unwrap();
-            if let Some(arg_reg) = arg_register(arg_num, arg.size()) {
-                self.instructions
-                    .push(Instruction::str(arg_reg, Register::sp(), *offset));
+        self.allocate_stack(lower_size);
+
+        // Save the arg registers for va_arg, the ones holding named args are saved as well
+        if let Some(offset) = frame.va_save_offset() {
+            // stp only reaches 504 bytes up, str of a d register further
+            let (base, offset) = if offset + 48 > 504 {
+                let base = Register::r16(Size::QuadWord);
+                self.add_offset(base, Register::sp(), offset);
+                (base, 0)
             } else {
-                while (stack_size + additional_args_offset) % arg.size().in_bytes() != 0 {
-                    additional_args_offset += 1;
-                }
+                (Register::sp(), offset as u16)
+            };
+
+            for (arg_num, pair_offset) in (0..8).step_by(2).zip((offset..).step_by(16)) {
+                let inst = Instruction::stp(
+                    arg_register(arg_num, Size::QuadWord).unwrap(),
+                    arg_register(arg_num + 1, Size::QuadWord).unwrap(),
+                    base,
+                    pair_offset,
+                );
+                self.instructions.push(inst);
+            }
 
-                self.instructions.push(Instruction::ldr(
-                    Register::r9(arg.size()),
-                    Register::sp(),
-                    func_stack_size + additional_args_offset,
-                    arg.is_signed(),
-                ));
+            // Each v register gets 16 bytes, of which the low 8 hold the double
+            for (arg_num, reg_offset) in (0..8).zip((offset + 64..).step_by(16)) {
+                let reg = arg_register(arg_num, Size::F64).unwrap();
+                self.instructions
+                    .push(Instruction::str(reg, base, reg_offset));
+            }
+        }
 
-                additional_args_offset += arg.size().in_bytes();
+        // x8 holds where to return an aggregate, it is free to be used until then
+        if let Some(offset) = frame.indirect_result_offset() {
+            self.store(Register::x8(), Register::sp(), offset);
+        }
 
-                self.instructions.push(Instruction::str(
-                    Register::r9(arg.size()),
-                    Register::sp(),
-                    *offset,
-                ));
+        // Store args in stack slots, the caller's outgoing stack args start right above our frame
+        let arg_types = func.arg_types();
+        for ((arg, ty), location) in func
+            .args()
+            .iter()
+            .zip(&arg_types)
+            .zip(&frame.args.locations)
+        {
+            let offset = *stack_slot_offsets.get(arg).unwrap();
+            match (location, ty) {
+                (ArgLocation::Register(arg_reg), _) => {
+                    self.store(*arg_reg, Register::sp(), offset);
+                }
+                (ArgLocation::Stack(stack_offset), ir::Type::Scalar(size, signed)) => {
+                    let scratch = Register::scratch(*size);
+                    self.load(
+                        scratch,
+                        Register::sp(),
+                        func_stack_size + stack_offset,
+                        *signed,
+                    );
+                    self.store(scratch, Register::sp(), offset);
+                }
+                (ArgLocation::Stack(stack_offset), ir::Type::Aggregate(aggregate)) => {
+                    self.copy_memory(
+                        Register::sp(),
+                        offset,
+                        Register::sp(),
+                        func_stack_size + stack_offset,
+                        aggregate.size(),
+                    );
+                }
+                (ArgLocation::Registers(regs), _) => {
+                    for (reg, reg_offset) in regs {
+                        self.store(*reg, Register::sp(), offset + u32::from(*reg_offset));
+                    }
+                }
+                (ArgLocation::Indirect(addr), ir::Type::Aggregate(aggregate)) => {
+                    // The caller made a copy for us, which is copied again into the stack slot.
+                    // Nothing lives in x9 before the body, which leaves x16, x17 to the copy
+                    let addr = match addr {
+                        Ok(reg) => *reg,
+                        Err(stack_offset) => {
+                            let addr = Register::new(RegisterNumber::R9, Size::QuadWord);
+                            self.load(addr, Register::sp(), func_stack_size + stack_offset, false);
+                            addr
+                        }
+                    };
+                    self.copy_memory(Register::sp(), offset, addr, 0, aggregate.size());
+                }
+                (ArgLocation::Indirect(_), ir::Type::Scalar(..)) => unreachable!(),
             }
-
-            arg_num += 1;
         }
     }
 
-    fn generate_func_epilogue(&mut self, func: &ir::Function, return_label: Label) {
-        let stack_size = func.stack_size();
-
+    fn generate_func_epilogue(&mut self, func: &ir::Function, frame: &Frame, return_label: Label) {
         // return_label:
         self.instructions.push(Instruction::label(return_label));
 
+        // Free everything below the callee saved registers first if they were stored first
+        let func_stack_size = frame.size();
+        let lower_size = frame.lower_size();
+        if lower_size != 0 {
+            self.add_offset(Register::sp(), Register::sp(), lower_size);
+        }
+
+        // Load callee saved registers
+        for (regs, offset) in frame.saved_reg_pairs() {
+            let offset = (offset - lower_size) as u16;
+            let inst = match regs {
+                [reg_1, reg_2] => Instruction::ldp(*reg_1, *reg_2, Register::sp(), offset),
+                [reg] => Instruction::ldr(*reg, Register::sp(), offset, false),
+                _ => unreachable!(),
+            };
+            self.instructions.push(inst);
+        }
+
         // Load x29, x30 if needed
-        if !func.is_leaf() {
-            // ldp x29, x30, [sp, stack size]
-            let inst =
-                Instruction::ldp(Register::x29(), Register::x30(), Register::sp(), stack_size);
+        if frame.saves_fp_lr() {
+            // ldp x29, x30, [sp, fp offset]
+            let inst = Instruction::ldp(
+                Register::x29(),
+                Register::x30(),
+                Register::sp(),
+                (frame.fp_offset() - lower_size) as u16,
+            );
             self.instructions.push(inst);
         }
 
         // add sp, stack size
-        let stack_size = func.stack_size();
-        let func_stack_size = stack_size + if !func.is_leaf() { 16 } else { 0 };
-        if func_stack_size != 0 {
-            self.instructions.push(Instruction::add_imm(
-                Register::sp(),
-                Register::sp(),
-                func_stack_size,
-            ));
+        if func_stack_size != lower_size {
+            self.add_offset(Register::sp(), Register::sp(), func_stack_size - lower_size);
         }
 
         // ret
         self.instructions.push(Instruction::ret());
 
+        // .size func_name, .-func_name
+        if self.format == ObjectFormat::Elf {
+            self.instructions.push(Instruction::custom(format!(
+                ".size {}, .-{}",
+                func.name(),
+                func.name()
+            )));
+        }
+
         // Empty Spacer
         self.instructions.push(Instruction::Empty);
 
         // Function local Data
-        for (addr, data) in func.data() {
-            self.instructions
-                .push(Instruction::locla_data_label(addr.id()));
-
-            match data {
-                ir::Data::StringNullTerminated(value) => {
-                    self.instructions.push(Instruction::AscizData {
-                        value: value.clone(),
-                    })
-                }
-            }
+        if !func.data().is_empty() {
+            self.add_data_items(func.data());
+            self.instructions.push(Instruction::section(".text"));
         }
     }
 
     fn add_inst(
         &mut self,
-        func_is_leaf: bool,
         inst: &ir::Instruction,
+        frame: &Frame,
         reg_map: &HashMap<ir::Temporary, Register>,
-        stack_slot_offsets: &HashMap<ir::StackSlot, u16>,
+        stack_slot_offsets: &HashMap<ir::StackSlot, u32>,
         return_label: Label,
     ) {
+        let last_cmp = self.last_cmp.take();
+
         match inst {
             ir::Instruction::Set { dest, src } => {
-                let reg = reg_map.get(&dest).unwrap();
-                let inst = Instruction::mov_imm(*reg, src.as_u64());
-                self.instructions.extend(inst);
+                let reg = reg_map.get(dest).unwrap();
+
+                if reg.is_float() {
+                    // Floats are built in x16 and then moved over bit for bit
+                    let size = match reg.size() {
+                        Size::F32 => Size::DoubleWord,
+                        _ => Size::QuadWord,
+                    };
+                    let scratch = Register::r16(size);
+                    self.instructions
+                        .extend(Instruction::mov_imm(scratch, src.as_u64()));
+                    self.instructions.push(Instruction::MovReg {
+                        dest: *reg,
+                        src: scratch,
+                    });
+                } else {
+                    let inst = Instruction::mov_imm(*reg, src.as_u64());
+                    self.instructions.extend(inst);
+                }
             }
             ir::Instruction::Load { dest, src } => {
-                let dest_reg = reg_map.get(&dest).unwrap();
-                let offset = stack_slot_offsets.get(&src).unwrap();
-                let offset = get_correct_offset(func_is_leaf, *offset);
+                let dest_reg = reg_map.get(dest).unwrap();
+                let offset = *stack_slot_offsets.get(src).unwrap();
 
-                let inst = Instruction::ldr(*dest_reg, Register::sp(), offset, src.is_signed());
-                self.instructions.push(inst);
+                self.load(*dest_reg, Register::sp(), offset, src.is_signed());
             }
             ir::Instruction::Add { dest, src_1, src_2 } => {
-                let src_1_reg = reg_map.get(&src_1).unwrap();
-                let src_2_reg = reg_map.get(&src_2).unwrap();
-                let dest_reg = reg_map.get(&dest).unwrap();
+                let src_1_reg = reg_map.get(src_1).unwrap();
+                let src_2_reg = reg_map.get(src_2).unwrap();
+                let dest_reg = reg_map.get(dest).unwrap();
 
                 let inst = Instruction::add(*dest_reg, *src_1_reg, *src_2_reg);
                 self.instructions.push(inst);
             }
-            ir::Instruction::Return { src } => {
+            ir::Instruction::Sub { dest, src_1, src_2 } => {
+                let src_1_reg = reg_map.get(src_1).unwrap();
+                let src_2_reg = reg_map.get(src_2).unwrap();
+                let dest_reg = reg_map.get(dest).unwrap();
+
+                let inst = Instruction::sub(*dest_reg, *src_1_reg, *src_2_reg);
+                self.instructions.push(inst);
+            }
+            ir::Instruction::Mul { dest, src_1, src_2 } => {
+                let src_1_reg = reg_map.get(src_1).unwrap();
+                let src_2_reg = reg_map.get(src_2).unwrap();
+                let dest_reg = reg_map.get(dest).unwrap();
+
+                let inst = Instruction::mul(*dest_reg, *src_1_reg, *src_2_reg);
+                self.instructions.push(inst);
+            }
+            ir::Instruction::Div { dest, src_1, src_2 } => {
+                let src_1_reg = *reg_map.get(src_1).unwrap();
+                let src_2_reg = *reg_map.get(src_2).unwrap();
+                let dest_reg = reg_map.get(dest).unwrap();
+
+                let signed = dest.is_signed();
+                let src_1_reg =
+                    self.extend_narrow(src_1_reg, signed, Register::r16(Size::DoubleWord));
+                let src_2_reg =
+                    self.extend_narrow(src_2_reg, signed, Register::r17(Size::DoubleWord));
+
+                let inst = Instruction::div(*dest_reg, src_1_reg, src_2_reg, signed);
+                self.instructions.push(inst);
+            }
+            ir::Instruction::Rem { dest, src_1, src_2 } => {
+                let src_1_reg = *reg_map.get(src_1).unwrap();
+                let src_2_reg = *reg_map.get(src_2).unwrap();
+                let dest_reg = *reg_map.get(dest).unwrap();
+
+                let signed = dest.is_signed();
+                let narrow = matches!(dest.size(), Size::Byte | Size::Word);
+                let src_1_reg =
+                    self.extend_narrow(src_1_reg, signed, Register::r16(Size::DoubleWord));
+                let src_2_reg =
+                    self.extend_narrow(src_2_reg, signed, Register::r17(Size::DoubleWord));
+
+                // dest = src_1 - (src_1 / src_2) * src_2
+                // Narrow operands were moved to x16, x17, which leaves dest free for the quotient
+                let quotient = if narrow {
+                    dest_reg
+                } else {
+                    Register::r16(dest_reg.size())
+                };
+
+                let inst = Instruction::div(quotient, src_1_reg, src_2_reg, signed);
+                self.instructions.push(inst);
+
+                let inst = Instruction::msub(dest_reg, quotient, src_2_reg, src_1_reg);
+                self.instructions.push(inst);
+            }
+            ir::Instruction::Neg { dest, src } => {
+                let src_reg = reg_map.get(src).unwrap();
+                let dest_reg = reg_map.get(dest).unwrap();
+
+                let inst = Instruction::neg(*dest_reg, *src_reg);
+                self.instructions.push(inst);
+            }
+            ir::Instruction::Sqrt { dest, src } => {
+                let src_reg = reg_map.get(src).unwrap();
+                let dest_reg = reg_map.get(dest).unwrap();
+
+                let inst = Instruction::fsqrt(*dest_reg, *src_reg);
+                self.instructions.push(inst);
+            }
+            ir::Instruction::Convert { dest, src } => {
+                let src_reg = *reg_map.get(src).unwrap();
+                let dest_reg = *reg_map.get(dest).unwrap();
+
+                // Only w and x registers convert to floats
+                let src_reg =
+                    self.extend_narrow(src_reg, src.is_signed(), Register::r16(Size::DoubleWord));
+
+                // Integer sources decide the signedness going to floats, integer dests coming from them
+                let signed = if src.size().is_float() {
+                    dest.is_signed()
+                } else {
+                    src.is_signed()
+                };
+                let inst = Instruction::convert(dest_reg, src_reg, signed);
+                self.instructions.push(inst);
+            }
+            ir::Instruction::Extend { dest, src, signed } => {
+                let src_reg = *reg_map.get(src).unwrap();
+                let dest_reg = *reg_map.get(dest).unwrap();
+                // Writing a w register zeroes the upper half of the x register
+                let dest_w = Register::new(dest_reg.number(), Size::DoubleWord);
+
+                let inst = match (src.size(), signed) {
+                    (_, true) => Instruction::sxt(dest_reg, src_reg),
+                    (Size::DoubleWord, false) => Instruction::MovReg {
+                        dest: dest_w,
+                        src: src_reg,
+                    },
+                    (_, false) => Instruction::uxt(dest_w, src_reg),
+                };
+                self.instructions.push(inst);
+            }
+            ir::Instruction::Trunc { dest, src } => {
+                let src_reg = *reg_map.get(src).unwrap();
+                let dest_reg = *reg_map.get(dest).unwrap();
+                let src_w = Register::new(src_reg.number(), Size::DoubleWord);
+
+                // Narrow values live in w registers, so only the bits above the new size are cleared
+                let inst = match dest.size() {
+                    Size::Byte => Instruction::and_imm(dest_reg, src_w, 0xff),
+                    Size::Word => Instruction::and_imm(dest_reg, src_w, 0xffff),
+                    _ => Instruction::MovReg {
+                        dest: dest_reg,
+                        src: src_w,
+                    },
+                };
+                self.instructions.push(inst);
+            }
+            ir::Instruction::And { dest, src_1, src_2 } => {
+                let src_1_reg = reg_map.get(src_1).unwrap();
+                let src_2_reg = reg_map.get(src_2).unwrap();
+                let dest_reg = reg_map.get(dest).unwrap();
+
+                let inst = Instruction::and(*dest_reg, *src_1_reg, *src_2_reg);
+                self.instructions.push(inst);
+            }
+            ir::Instruction::Or { dest, src_1, src_2 } => {
+                let src_1_reg = reg_map.get(src_1).unwrap();
+                let src_2_reg = reg_map.get(src_2).unwrap();
+                let dest_reg = reg_map.get(dest).unwrap();
+
+                let inst = Instruction::orr(*dest_reg, *src_1_reg, *src_2_reg);
+                self.instructions.push(inst);
+            }
+            ir::Instruction::Xor { dest, src_1, src_2 } => {
+                let src_1_reg = reg_map.get(src_1).unwrap();
+                let src_2_reg = reg_map.get(src_2).unwrap();
+                let dest_reg = reg_map.get(dest).unwrap();
+
+                let inst = Instruction::eor(*dest_reg, *src_1_reg, *src_2_reg);
+                self.instructions.push(inst);
+            }
+            ir::Instruction::Not { dest, src } => {
+                let src_reg = reg_map.get(src).unwrap();
+                let dest_reg = reg_map.get(dest).unwrap();
+
+                let inst = Instruction::mvn(*dest_reg, *src_reg);
+                self.instructions.push(inst);
+            }
+            ir::Instruction::Shl { dest, src, amount } => {
+                let src_reg = reg_map.get(src).unwrap();
+                let amount_reg = reg_map.get(amount).unwrap();
+                let dest_reg = reg_map.get(dest).unwrap();
+
+                // The shift amount register has to be the same width as the operands
+                let amount_reg = Register::new(amount_reg.number(), dest_reg.size());
+
+                let inst = Instruction::lsl(*dest_reg, *src_reg, amount_reg);
+                self.instructions.push(inst);
+            }
+            ir::Instruction::LShr { dest, src, amount } => {
+                let src_reg = *reg_map.get(src).unwrap();
+                let amount_reg = reg_map.get(amount).unwrap();
+                let dest_reg = reg_map.get(dest).unwrap();
+
+                // The shift amount register has to be the same width as the operands
+                let amount_reg = Register::new(amount_reg.number(), dest_reg.size());
+
+                // Bytes and half words are zero extended so the right bits get shifted in
+                let src_reg = self.extend_narrow(src_reg, false, Register::r16(Size::DoubleWord));
+
+                let inst = Instruction::lsr(*dest_reg, src_reg, amount_reg);
+                self.instructions.push(inst);
+            }
+            ir::Instruction::AShr { dest, src, amount } => {
+                let src_reg = *reg_map.get(src).unwrap();
+                let amount_reg = reg_map.get(amount).unwrap();
+                let dest_reg = reg_map.get(dest).unwrap();
+
+                // The shift amount register has to be the same width as the operands
+                let amount_reg = Register::new(amount_reg.number(), dest_reg.size());
+
+                // Bytes and half words are sign extended so the right bits get shifted in
+                let src_reg = self.extend_narrow(src_reg, true, Register::r16(Size::DoubleWord));
+
+                let inst = Instruction::asr(*dest_reg, src_reg, amount_reg);
+                self.instructions.push(inst);
+            }
+            ir::Instruction::Cmp {
+                dest,
+                src_1,
+                src_2,
+                cond,
+            } => {
+                let src_1_reg = *reg_map.get(src_1).unwrap();
+                let src_2_reg = *reg_map.get(src_2).unwrap();
+                let dest_reg = reg_map.get(dest).unwrap();
+
+                // Bytes and half words are extended the way the condition reads them,
+                // equality works either way as long as both sides match
+                let signed = match cond {
+                    ir::Condition::Slt
+                    | ir::Condition::Sle
+                    | ir::Condition::Sgt
+                    | ir::Condition::Sge => true,
+                    ir::Condition::Ult
+                    | ir::Condition::Ule
+                    | ir::Condition::Ugt
+                    | ir::Condition::Uge => false,
+                    _ => src_1.is_signed(),
+                };
+                let src_1_reg =
+                    self.extend_narrow(src_1_reg, signed, Register::r16(Size::DoubleWord));
+                let src_2_reg =
+                    self.extend_narrow(src_2_reg, signed, Register::r17(Size::DoubleWord));
+
+                let inst = Instruction::cmp(src_1_reg, src_2_reg);
+                self.instructions.push(inst);
+
+                let inst = Instruction::cset(*dest_reg, *cond);
+                self.instructions.push(inst);
+
+                self.last_cmp = Some((*dest, *cond));
+            }
+            ir::Instruction::Label { label } => {
+                let label = self.label_for(*label);
+
+                let inst = Instruction::label(label);
+                self.instructions.push(inst);
+            }
+            ir::Instruction::Jump { label } => {
+                let label = self.label_for(*label);
+
+                let inst = Instruction::b(label);
+                self.instructions.push(inst);
+            }
+            ir::Instruction::Branch {
+                cond,
+                then_label,
+                else_label,
+            } => {
+                let then_label = self.label_for(*then_label);
+                let else_label = self.label_for(*else_label);
+
+                match last_cmp {
+                    // Flags of the comparison are still set, so branch on them directly
+                    Some((cmp_dest, cmp_cond)) if cmp_dest == *cond => {
+                        let inst = Instruction::b_cond(cmp_cond, then_label);
+                        self.instructions.push(inst);
+
+                        let inst = Instruction::b(else_label);
+                        self.instructions.push(inst);
+                    }
+                    _ => {
+                        let cond_reg = *reg_map.get(cond).unwrap();
+
+                        // cbz reads the whole w register, so stale upper bits have to go first
+                        let cond_reg =
+                            self.extend_narrow(cond_reg, false, Register::r16(Size::DoubleWord));
+
+                        let inst = Instruction::cbz(cond_reg, else_label);
+                        self.instructions.push(inst);
+
+                        let inst = Instruction::b(then_label);
+                        self.instructions.push(inst);
+                    }
+                }
+            }
+            ir::Instruction::Return {
+                src: Some(src),
+                aggregate: Some(aggregate),
+            } => {
+                let src_reg = *reg_map.get(src).unwrap();
+
+                match return_registers(aggregate) {
+                    // The caller passed where to put it in x8
+                    None => {
+                        let dest = Register::r17(Size::QuadWord);
+                        let offset = frame.indirect_result_offset().unwrap();
+                        self.load(dest, Register::sp(), offset, false);
+
+                        self.copy_memory(dest, 0, src_reg, 0, aggregate.size());
+                    }
+                    Some(regs) if regs[0].0.is_float() => {
+                        for (reg, offset) in regs {
+                            let inst = Instruction::ldr(reg, src_reg, offset, false);
+                            self.instructions.push(inst);
+                        }
+                    }
+                    // x registers are loaded 8 bytes at a time, which can read past the end of
+                    // the aggregate, so it is copied somewhere big enough first
+                    Some(regs) => {
+                        let area = frame.return_area_offset().unwrap();
+                        self.copy_memory(Register::sp(), area, src_reg, 0, aggregate.size());
+
+                        for (reg, offset) in regs {
+                            self.load(reg, Register::sp(), area + u32::from(offset), false);
+                        }
+                    }
+                }
+
+                let inst = Instruction::b(return_label);
+                self.instructions.push(inst);
+            }
+            ir::Instruction::Return { src, .. } => {
                 if let Some(src) = src {
-                    let src_reg = reg_map.get(&src).unwrap();
+                    let src_reg = reg_map.get(src).unwrap();
 
-                    let inst = Instruction::mov(Register::r0(src_reg.size()), *src_reg);
+                    let inst = Instruction::mov(Register::result(src_reg.size()), *src_reg);
                     // Only add if it is not a NOP
                     if let Some(inst) = inst {
                         self.instructions.push(inst);
@@ -235,90 +824,773 @@ impl Asm {
                 self.instructions.push(inst);
             }
             ir::Instruction::Store { dest, src } => {
-                let src_reg = reg_map.get(&src).unwrap();
-                let offset = stack_slot_offsets.get(&dest).unwrap();
-                let offset = get_correct_offset(func_is_leaf, *offset);
-
-                let inst = Instruction::str(*src_reg, Register::sp(), offset);
-                self.instructions.push(inst);
-            }
-            ir::Instruction::Call { func, args } => {
-                // Store args in correct registers/stack
-                let mut arg_num = 0;
-                let mut additional_args_offset = 0;
-                #[allow(clippy::explicit_counter_loop)]
-                for arg in args {
-                    let value_reg = reg_map.get(arg).unwrap();
-                    if let Some(arg_reg) = arg_register(arg_num, arg.size()) {
-                        let inst = Instruction::mov(arg_reg, *value_reg);
-                        // Only add if it is not a NOP
-                        if let Some(inst) = inst {
-                            self.instructions.push(inst);
+                let src_reg = reg_map.get(src).unwrap();
+                let offset = *stack_slot_offsets.get(dest).unwrap();
+
+                self.store(*src_reg, Register::sp(), offset);
+            }
+            ir::Instruction::SlotAddr { dest, slot } => {
+                let dest_reg = reg_map.get(dest).unwrap();
+                let offset = *stack_slot_offsets.get(slot).unwrap();
+
+                self.add_offset(*dest_reg, Register::sp(), offset);
+            }
+            ir::Instruction::LoadPtr { dest, addr, offset } => {
+                let dest_reg = *reg_map.get(dest).unwrap();
+                let addr_reg = *reg_map.get(addr).unwrap();
+                let signed = dest.is_signed();
+
+                let inst = match self.ptr_offset(*offset, dest.size(), reg_map) {
+                    Offset::Scaled(offset) => Instruction::ldr(dest_reg, addr_reg, offset, signed),
+                    Offset::Unscaled(offset) => {
+                        Instruction::ldur(dest_reg, addr_reg, offset, signed)
+                    }
+                    Offset::Index(index) => {
+                        Instruction::ldr_index(dest_reg, addr_reg, index, signed)
+                    }
+                };
+                self.instructions.push(inst);
+            }
+            ir::Instruction::StorePtr { addr, src, offset } => {
+                let src_reg = *reg_map.get(src).unwrap();
+                let addr_reg = *reg_map.get(addr).unwrap();
+
+                let inst = match self.ptr_offset(*offset, src.size(), reg_map) {
+                    Offset::Scaled(offset) => Instruction::str(src_reg, addr_reg, offset),
+                    Offset::Unscaled(offset) => Instruction::stur(src_reg, addr_reg, offset),
+                    Offset::Index(index) => Instruction::str_index(src_reg, addr_reg, index),
+                };
+                self.instructions.push(inst);
+            }
+            ir::Instruction::Call {
+                dest,
+                args,
+                signature,
+                ret_slot,
+                ..
+            }
+            | ir::Instruction::CallIndirect {
+                dest,
+                args,
+                signature,
+                ret_slot,
+                ..
+            } => {
+                // Store args in correct registers/stack, the stack args go in the
+                // outgoing args area at the bottom of the frame
+                let arg_types = signature.arg_types(args);
+                let layout = CallLayout::new(&arg_types, signature.fixed_args(), self.format);
+                let mut moves = Vec::new();
+                for (((arg, ty), location), copy_offset) in args
+                    .iter()
+                    .zip(&arg_types)
+                    .zip(&layout.args.locations)
+                    .zip(&layout.copies)
+                {
+                    let value_reg = *reg_map.get(arg).unwrap();
+                    match (location, ty) {
+                        (ArgLocation::Register(arg_reg), _) => moves.push((*arg_reg, value_reg)),
+                        (ArgLocation::Stack(stack_offset), ir::Type::Scalar(..)) => {
+                            self.store(value_reg, Register::sp(), *stack_offset);
                         }
-                    } else {
-                        let inst =
-                            Instruction::str(*value_reg, Register::sp(), additional_args_offset);
-                        self.instructions.push(inst);
-                        additional_args_offset += arg.size().in_bytes();
+                        (ArgLocation::Stack(stack_offset), ir::Type::Aggregate(aggregate)) => {
+                            self.copy_memory(
+                                Register::sp(),
+                                *stack_offset,
+                                value_reg,
+                                0,
+                                aggregate.size(),
+                            );
+                        }
+                        // Aggregates are copied into the outgoing args area, the copy is loaded
+                        // into registers or has its address passed once the other args are moved
+                        (_, ir::Type::Aggregate(aggregate)) => {
+                            let copy_offset = copy_offset.unwrap();
+                            self.copy_memory(
+                                Register::sp(),
+                                copy_offset,
+                                value_reg,
+                                0,
+                                aggregate.size(),
+                            );
+
+                            if let ArgLocation::Indirect(Err(stack_offset)) = location {
+                                let scratch = Register::r16(Size::QuadWord);
+                                self.add_offset(scratch, Register::sp(), copy_offset);
+                                self.store(scratch, Register::sp(), *stack_offset);
+                            }
+                        }
+                        (_, ir::Type::Scalar(..)) => unreachable!(),
+                    }
+                }
+
+                // blr goes through x17, which is never allocated, so the callee joins the
+                // arg moves rather than risking being overwritten by one of them
+                if let ir::Instruction::CallIndirect { func, .. } = inst {
+                    moves.push((Register::r17(Size::QuadWord), *reg_map.get(func).unwrap()));
+                }
+
+                // The stack args were stored from their registers above, only now are
+                // the x0-x7 and v0-v7 values shuffled into place
+                self.parallel_move(moves);
+
+                // Nothing is read from the arg registers anymore, so the aggregates can fill theirs
+                for (location, copy_offset) in layout.args.locations.iter().zip(&layout.copies) {
+                    match location {
+                        ArgLocation::Registers(regs) => {
+                            for (reg, offset) in regs {
+                                let offset = copy_offset.unwrap() + u32::from(*offset);
+                                self.load(*reg, Register::sp(), offset, false);
+                            }
+                        }
+                        ArgLocation::Indirect(Ok(reg)) => {
+                            self.add_offset(*reg, Register::sp(), copy_offset.unwrap());
+                        }
+                        _ => {}
                     }
+                }
 
-                    arg_num += 1;
+                // A returned aggregate goes in its stack object
+                let ret_aggregate = ret_slot.map(|slot| {
+                    let Some(ir::Type::Aggregate(aggregate)) = signature.ret() else {
+                        unreachable!()
+                    };
+                    (aggregate, *stack_slot_offsets.get(&slot).unwrap())
+                });
+                let ret_registers = ret_aggregate.map(|(aggregate, _)| return_registers(aggregate));
+
+                // If it is not returned in registers, its address is passed in x8
+                if let (Some((_, ret_offset)), Some(None)) = (ret_aggregate, &ret_registers) {
+                    self.add_offset(Register::x8(), Register::sp(), ret_offset);
                 }
 
                 // Call func
-                let inst = Instruction::bl(func.clone());
+                let inst = match inst {
+                    ir::Instruction::CallIndirect { .. } => {
+                        Instruction::blr(Register::r17(Size::QuadWord))
+                    }
+                    ir::Instruction::Call { func, .. } => Instruction::bl(func.clone()),
+                    _ => unreachable!(),
+                };
                 self.instructions.push(inst);
-            }
-            ir::Instruction::CallResult { dest } => {
-                let dest_reg = reg_map.get(&dest).unwrap();
 
-                // mov dest_reg, x0
-                let inst = Instruction::mov(*dest_reg, Register::r0(dest_reg.size()));
-                // Only add if it is not a NOP
-                if let Some(inst) = inst {
-                    self.instructions.push(inst);
+                if let Some((_, ret_offset)) = ret_aggregate {
+                    for (reg, offset) in ret_registers.flatten().into_iter().flatten() {
+                        self.store(reg, Register::sp(), ret_offset + u32::from(offset));
+                    }
+
+                    let dest_reg = reg_map.get(&dest.unwrap()).unwrap();
+                    self.add_offset(*dest_reg, Register::sp(), ret_offset);
+                } else if let Some(dest) = dest {
+                    let dest_reg = reg_map.get(dest).unwrap();
+
+                    // mov dest_reg, x0
+                    let inst = Instruction::mov(*dest_reg, Register::result(dest_reg.size()));
+                    // Only add if it is not a NOP
+                    if let Some(inst) = inst {
+                        self.instructions.push(inst);
+                    }
                 }
             }
-            ir::Instruction::LoadAddr { dest, addr } => {
-                let dest_reg = reg_map.get(&dest).unwrap();
+            ir::Instruction::VaStart { va_list } => {
+                let va_list_reg = *reg_map.get(va_list).unwrap();
+                let scratch = Register::r16(Size::QuadWord);
+
+                // The variadic stack args follow the named ones in the caller's outgoing args
+                let stack_offset = frame.size() + frame.args.stack_size.next_multiple_of(8);
+                self.add_offset(scratch, Register::sp(), stack_offset);
 
-                // adr dest_reg, =addr
-                let inst = Instruction::adr(*dest_reg, *addr);
+                let inst = Instruction::str(scratch, va_list_reg, 0);
                 self.instructions.push(inst);
-            }
-        }
-    }
 
-    pub fn save_to(self, file: &str) -> io::Result<()> {
-        let mut file = File::create(file)?;
+                // Apple's va_list is a plain pointer into the stack args
+                let Some(save_offset) = frame.va_save_offset() else {
+                    return;
+                };
 
-        for inst in self.instructions {
-            writeln!(file, "{inst}")?;
-        }
+                // AAPCS64 va_list:
+                // void *__stack, void *__gr_top, void *__vr_top, int __gr_offs, int __vr_offs
+                self.add_offset(scratch, Register::sp(), save_offset + 64);
 
-        Ok(())
-    }
-}
+                let inst = Instruction::str(scratch, va_list_reg, 8);
+                self.instructions.push(inst);
 
-#[derive(Clone, Copy)]
-struct Label {
-    id: usize,
-}
+                self.add_offset(scratch, Register::sp(), save_offset + 192);
 
-impl Label {
-    fn new(id: usize) -> Self {
-        Self { id }
-    }
+                let inst = Instruction::str(scratch, va_list_reg, 16);
+                self.instructions.push(inst);
 
-    fn id(self) -> usize {
-        self.id
+                // The offsets are negative, counting up to 0 once every saved register is read
+                let scratch = Register::r16(Size::DoubleWord);
+                let gr_offs = -(8 - frame.args.gr as i32) * 8;
+                self.instructions
+                    .extend(Instruction::mov_imm(scratch, gr_offs as u32 as u64));
+
+                let inst = Instruction::str(scratch, va_list_reg, 24);
+                self.instructions.push(inst);
+
+                let vr_offs = -(8 - frame.args.vr as i32) * 16;
+                self.instructions
+                    .extend(Instruction::mov_imm(scratch, vr_offs as u32 as u64));
+
+                let inst = Instruction::str(scratch, va_list_reg, 28);
+                self.instructions.push(inst);
+            }
+            ir::Instruction::VaArg { dest, va_list } => {
+                let dest_reg = *reg_map.get(dest).unwrap();
+                let va_list_reg = *reg_map.get(va_list).unwrap();
+                let addr = Register::r16(Size::QuadWord);
+                let next = Register::r17(Size::QuadWord);
+
+                // On ELF the arg is in the register save area until __gr_offs or __vr_offs
+                // reach 0, after that it is on the stack. va_arg only reads args of up to 8
+                // bytes, each taking a whole save area slot, so unlike the AAPCS64 va_arg the
+                // new offs is not checked for going past 0: an arg can not be split between
+                // the save area and the stack. Args over 8 bytes would need that check
+                // ldrsw x17, [va_list, offs]
+                // tbz w17, #31, on_stack
+                // add w16, w17, reg size
+                // str w16, [va_list, offs]
+                // ldr x16, [va_list, top]
+                // add x16, x16, x17
+                // b done
+                let mut done = None;
+                if self.format == ObjectFormat::Elf {
+                    let (offs_offset, top_offset, reg_size) = if dest.size().is_float() {
+                        (28, 16, 16)
+                    } else {
+                        (24, 8, 8)
+                    };
+                    let on_stack = Label::new(self.lbl_iota.next());
+                    let done = *done.insert(Label::new(self.lbl_iota.next()));
+
+                    self.instructions.push(Instruction::Ldr {
+                        dest: next,
+                        addr: va_list_reg,
+                        offset: offs_offset,
+                        size: Size::DoubleWord,
+                        signed: true,
+                    });
+
+                    let next_w = Register::r17(Size::DoubleWord);
+                    let inst = Instruction::tbz(next_w, 31, on_stack);
+                    self.instructions.push(inst);
+
+                    let addr_w = Register::r16(Size::DoubleWord);
+                    let inst = Instruction::add_imm(addr_w, next_w, reg_size);
+                    self.instructions.push(inst);
+
+                    let inst = Instruction::str(addr_w, va_list_reg, offs_offset);
+                    self.instructions.push(inst);
+
+                    let inst = Instruction::ldr(addr, va_list_reg, top_offset, false);
+                    self.instructions.push(inst);
+
+                    let inst = Instruction::add(addr, addr, next);
+                    self.instructions.push(inst);
+
+                    let inst = Instruction::b(done);
+                    self.instructions.push(inst);
+
+                    let inst = Instruction::label(on_stack);
+                    self.instructions.push(inst);
+                }
+
+                // Every stack arg takes 8 bytes
+                // ldr x16, [va_list]
+                // add x17, x16, #8
+                // str x17, [va_list]
+                let inst = Instruction::ldr(addr, va_list_reg, 0, false);
+                self.instructions.push(inst);
+
+                let inst = Instruction::add_imm(next, addr, 8);
+                self.instructions.push(inst);
+
+                let inst = Instruction::str(next, va_list_reg, 0);
+                self.instructions.push(inst);
+
+                // done:
+                // ldr dest, [x16]
+                if let Some(done) = done {
+                    self.instructions.push(Instruction::label(done));
+                }
+
+                let inst = Instruction::ldr(dest_reg, addr, 0, dest.is_signed());
+                self.instructions.push(inst);
+            }
+            // An AAPCS64 va_list holds nothing that has to be cleaned up
+            ir::Instruction::VaEnd { .. } => {}
+            ir::Instruction::LoadAddr { dest, addr } => {
+                let dest_reg = reg_map.get(dest).unwrap();
+
+                let symbol = self.data_symbol_for(*addr);
+
+                // Data lives in its own section, which can be out of range of adr
+                // adrp dest_reg, addr page
+                // add dest_reg, dest_reg, addr page offset
+                let inst = Instruction::adrp(*dest_reg, symbol, self.format);
+                self.instructions.push(inst);
+
+                let inst = Instruction::add_page_offset(*dest_reg, *dest_reg, symbol, self.format);
+                self.instructions.push(inst);
+            }
+            ir::Instruction::FuncAddr { dest, func } => {
+                let dest_reg = reg_map.get(dest).unwrap();
+
+                // The function can be in another module or a shared library, so its
+                // address is loaded from the GOT
+                // adrp dest_reg, func GOT entry page
+                // ldr dest_reg, [dest_reg, func GOT entry page offset]
+                let inst = Instruction::adrp_got(*dest_reg, func.clone(), self.format);
+                self.instructions.push(inst);
+
+                let inst = Instruction::ldr_got(*dest_reg, *dest_reg, func.clone(), self.format);
+                self.instructions.push(inst);
+            }
+        }
+    }
+
+    /// The upper bits of bytes and half words are not kept clean, so they are extended into the
+    /// w register `scratch` before being used whole. Anything wider is returned as is
+    fn extend_narrow(&mut self, reg: Register, signed: bool, scratch: Register) -> Register {
+        if !matches!(reg.size(), Size::Byte | Size::Word) {
+            return reg;
+        }
+
+        let inst = if signed {
+            Instruction::sxt(scratch, reg)
+        } else {
+            Instruction::uxt(scratch, reg)
+        };
+        self.instructions.push(inst);
+
+        scratch
+    }
+
+    /// Copies `size` bytes from `src` plus `src_offset` to `dest` plus `dest_offset` through x16
+    fn copy_memory(
+        &mut self,
+        dest: Register,
+        dest_offset: u32,
+        src: Register,
+        src_offset: u32,
+        size: u16,
+    ) {
+        let mut copied = 0;
+        while copied < size {
+            // The biggest piece that fits, which keeps every offset a multiple of its size
+            let piece = match size - copied {
+                8.. => Size::QuadWord,
+                4..=7 => Size::DoubleWord,
+                2 | 3 => Size::Word,
+                _ => Size::Byte,
+            };
+            let scratch = Register::r16(piece);
+
+            self.load(scratch, src, src_offset + u32::from(copied), false);
+            self.store(scratch, dest, dest_offset + u32::from(copied));
+
+            copied += piece.in_bytes();
+        }
+    }
+
+    /// Loads from `addr` plus `offset`, which can be out of reach of ldr
+    fn load(&mut self, dest: Register, addr: Register, offset: u32, signed: bool) {
+        let inst = match self.frame_offset(offset, dest.size(), [dest, addr]) {
+            Offset::Scaled(offset) => Instruction::ldr(dest, addr, offset, signed),
+            Offset::Index(index) => Instruction::ldr_index(dest, addr, index, signed),
+            Offset::Unscaled(_) => unreachable!(),
+        };
+        self.instructions.push(inst);
+    }
+
+    /// Stores to `addr` plus `offset`, which can be out of reach of str
+    fn store(&mut self, src: Register, addr: Register, offset: u32) {
+        let inst = match self.frame_offset(offset, src.size(), [src, addr]) {
+            Offset::Scaled(offset) => Instruction::str(src, addr, offset),
+            Offset::Index(index) => Instruction::str_index(src, addr, index),
+            Offset::Unscaled(_) => unreachable!(),
+        };
+        self.instructions.push(inst);
+    }
+
+    /// Offset of an access of `size` bytes in the frame. ldr/str take an unsigned 12 bit offset
+    /// in multiples of the size, anything further is materialized in x16 or x17, whichever
+    /// is not one of the `in_use` registers
+    fn frame_offset(&mut self, offset: u32, size: Size, in_use: [Register; 2]) -> Offset {
+        let bytes = u32::from(size.in_bytes());
+        if offset.is_multiple_of(bytes) && offset / bytes < 4096 {
+            return Offset::Scaled(offset as u16);
+        }
+
+        let scratch = [RegisterNumber::R16, RegisterNumber::R17]
+            .into_iter()
+            .find(|number| in_use.iter().all(|reg| reg.number() != *number))
+            .expect("x16 and x17 are both in use, so there is nowhere to put the offset");
+        let scratch = Register::new(scratch, Size::QuadWord);
+        self.instructions
+            .extend(Instruction::mov_imm(scratch, offset.into()));
+
+        Offset::Index(Index::new(scratch, 0, false))
+    }
+
+    /// dest = src + offset, the offset is split over the 12 bit immediate and the one
+    /// shifted left by 12. From 16 MiB on it is materialized in dest, or x16 if dest is sp
+    fn add_offset(&mut self, dest: Register, src: Register, offset: u32) {
+        self.apply_offset(dest, src, offset, Instruction::add_imm, Instruction::add);
+    }
+
+    /// dest = src - offset, like `add_offset`
+    fn sub_offset(&mut self, dest: Register, src: Register, offset: u32) {
+        self.apply_offset(dest, src, offset, Instruction::sub_imm, Instruction::sub);
+    }
+
+    fn apply_offset(
+        &mut self,
+        dest: Register,
+        src: Register,
+        offset: u32,
+        imm_inst: fn(Register, Register, u32) -> Instruction,
+        reg_inst: fn(Register, Register, Register) -> Instruction,
+    ) {
+        if offset >> 24 != 0 {
+            let scratch = if dest.number() != RegisterNumber::SP && dest != src {
+                dest
+            } else {
+                Register::r16(Size::QuadWord)
+            };
+            self.instructions
+                .extend(Instruction::mov_imm(scratch, offset.into()));
+            self.instructions.push(reg_inst(dest, src, scratch));
+            return;
+        }
+
+        let high = offset & !0xfff;
+        let low = offset & 0xfff;
+        if high != 0 {
+            self.instructions.push(imm_inst(dest, src, high));
+        }
+        if low != 0 || high == 0 {
+            let src = if high != 0 { dest } else { src };
+            self.instructions.push(imm_inst(dest, src, low));
+        }
+    }
+
+    /// Moves sp down by `size` bytes. Past the stack guard size it moves one guard at a time
+    /// and touches the stack after each step, so it can not skip over the guard region
+    fn allocate_stack(&mut self, size: u32) {
+        if size <= self.stack_guard_size {
+            if size != 0 {
+                self.sub_offset(Register::sp(), Register::sp(), size);
+            }
+            return;
+        }
+
+        // x16 counts the guards left to allocate, and is what gets stored to touch the stack
+        let count = Register::r16(Size::QuadWord);
+        self.instructions.extend(Instruction::mov_imm(
+            count,
+            (size / self.stack_guard_size).into(),
+        ));
+
+        let probe_label = Label::new(self.lbl_iota.next());
+        self.instructions.push(Instruction::label(probe_label));
+
+        self.sub_offset(Register::sp(), Register::sp(), self.stack_guard_size);
+        self.instructions
+            .push(Instruction::str(count, Register::sp(), 0));
+        self.instructions
+            .push(Instruction::sub_imm(count, count, 1));
+        self.instructions
+            .push(Instruction::cbnz(count, probe_label));
+
+        // The rest is touched as well, so whatever comes below can take a whole guard
+        let rest = size % self.stack_guard_size;
+        if rest != 0 {
+            self.sub_offset(Register::sp(), Register::sp(), rest);
+            self.instructions
+                .push(Instruction::str(count, Register::sp(), 0));
+        }
+    }
+
+    /// Moves every `(dest, src)` pair as if they happened at the same time
+    fn parallel_move(&mut self, moves: Vec<(Register, Register)>) {
+        for (dest, src) in target::parallel_moves(moves) {
+            self.instructions.push(Instruction::MovReg { dest, src });
+        }
+    }
+
+    fn label_for(&mut self, label: ir::Label) -> Label {
+        *self
+            .func_labels
+            .entry(label)
+            .or_insert_with(|| Label::new(self.lbl_iota.next()))
+    }
+
+    /// Picks the addressing mode for a pointer access of `size`, offsets no
+    /// immediate form can encode are moved into x16 first
+    fn ptr_offset(
+        &mut self,
+        offset: ir::PtrOffset,
+        size: Size,
+        reg_map: &HashMap<ir::Temporary, Register>,
+    ) -> Offset {
+        match offset {
+            ir::PtrOffset::Imm(offset) => {
+                let bytes = size.in_bytes() as i32;
+
+                // ldr/str take an unsigned 12 bit offset in multiples of the size,
+                // ldur/stur a signed 9 bit offset in bytes
+                if offset >= 0 && offset % bytes == 0 && offset / bytes < 4096 {
+                    Offset::Scaled(offset as u16)
+                } else if (-256..256).contains(&offset) {
+                    Offset::Unscaled(offset as i16)
+                } else {
+                    let scratch = Register::r16(Size::QuadWord);
+                    self.instructions
+                        .extend(Instruction::mov_imm(scratch, offset as i64 as u64));
+                    Offset::Index(Index::new(scratch, 0, false))
+                }
+            }
+            ir::PtrOffset::Index { index, scaled } => {
+                let reg = *reg_map.get(&index).unwrap();
+                let shift = if scaled {
+                    size.in_bytes().trailing_zeros() as u8
+                } else {
+                    0
+                };
+                Offset::Index(Index::new(reg, shift, index.is_signed()))
+            }
+        }
+    }
+}
+
+/// Stack frame of a function, from sp upwards it holds the outgoing stack args, the stack
+/// slots, the arg registers of a variadic function on ELF, room for a returned aggregate,
+/// the callee saved registers it uses and then x29, x30 if it is not a leaf
+struct Frame {
+    outgoing_args_size: u32,
+    slots_size: u32,
+    va_save_size: u32,
+    return_area_size: u32,
+    returns_indirect: bool,
+    /// Where the function's own args are passed
+    args: ArgLocations,
+    saved_regs: Vec<Register>,
+    saves_fp_lr: bool,
+}
+
+impl Frame {
+    fn new(func: &ir::Function, layout: &FrameLayout, format: ObjectFormat) -> Self {
+        // Room for the stack args of the call that needs the most
+        let outgoing_args_size = func
+            .instructions()
+            .iter()
+            .filter_map(|inst| match inst {
+                ir::Instruction::Call {
+                    args, signature, ..
+                }
+                | ir::Instruction::CallIndirect {
+                    args, signature, ..
+                } => Some(
+                    CallLayout::new(&signature.arg_types(args), signature.fixed_args(), format)
+                        .size,
+                ),
+                _ => None,
+            })
+            .max()
+            .unwrap_or(0);
+
+        // x0-x7 and 16 bytes for each of v0-v7
+        let va_save_size = if func.is_variadic() && format == ObjectFormat::Elf {
+            64 + 128
+        } else {
+            0
+        };
+
+        // An aggregate returned through x8 needs x8 saved until the return, one returned in
+        // x registers is put together in memory first
+        let returned = func.instructions().iter().find_map(|inst| match inst {
+            ir::Instruction::Return {
+                aggregate: Some(aggregate),
+                ..
+            } => Some(return_registers(aggregate)),
+            _ => None,
+        });
+        let (return_area_size, returns_indirect) = match returned {
+            Some(None) => (16, true),
+            Some(Some(regs)) if !regs[0].0.is_float() => (16, false),
+            _ => (0, false),
+        };
+
+        Self {
+            outgoing_args_size,
+            slots_size: layout.slots_size,
+            va_save_size,
+            return_area_size,
+            returns_indirect,
+            args: arg_locations(&func.arg_types(), None, format),
+            saved_regs: layout
+                .saved_registers
+                .iter()
+                .map(|number| {
+                    let number = RegisterNumber::from_u8(*number);
+                    let size = if number.is_float() {
+                        Size::F64
+                    } else {
+                        Size::QuadWord
+                    };
+                    Register::new(number, size)
+                })
+                .collect(),
+            saves_fp_lr: !func.is_leaf(),
+        }
+    }
+
+    fn saves_fp_lr(&self) -> bool {
+        self.saves_fp_lr
+    }
+
+    /// Offset from sp where the stack slots start
+    fn slots_offset(&self) -> u32 {
+        self.outgoing_args_size
+    }
+
+    /// Offset from sp where the arg registers are saved for va_arg, if they are
+    fn va_save_offset(&self) -> Option<u32> {
+        (self.va_save_size != 0).then_some(self.slots_offset() + self.slots_size)
+    }
+
+    /// Offset from sp of the room for a returned aggregate, if there is any
+    fn return_area_offset(&self) -> Option<u32> {
+        (self.return_area_size != 0)
+            .then_some(self.slots_offset() + self.slots_size + self.va_save_size)
+    }
+
+    /// Offset from sp where x8 is saved, if an aggregate is returned through it
+    fn indirect_result_offset(&self) -> Option<u32> {
+        self.return_area_offset().filter(|_| self.returns_indirect)
+    }
+
+    /// Offset from sp where the callee saved registers start
+    fn saved_regs_offset(&self) -> u32 {
+        self.slots_offset() + self.slots_size + self.va_save_size + self.return_area_size
+    }
+
+    /// Callee saved registers in pairs for stp/ldp, along with their offset from sp.
+    /// x and d registers can not share a pair, so each kind is paired up on its own
+    fn saved_reg_pairs(&self) -> impl Iterator<Item = (&[Register], u32)> {
+        self.saved_regs
+            .chunk_by(|reg_1, reg_2| reg_1.is_float() == reg_2.is_float())
+            .flat_map(|regs| regs.chunks(2))
+            .enumerate()
+            .map(|(index, regs)| (regs, self.saved_regs_offset() + index as u32 * 16))
+    }
+
+    /// Offset from sp where x29, x30 are saved
+    fn fp_offset(&self) -> u32 {
+        // Every pair takes 16 bytes to keep sp aligned
+        self.saved_regs_offset() + self.saved_reg_pairs().count() as u32 * 16
+    }
+
+    fn size(&self) -> u32 {
+        self.fp_offset() + if self.saves_fp_lr { 16 } else { 0 }
+    }
+
+    /// Bytes below the callee saved registers. When stp can not reach the saved registers
+    /// from sp, the bytes above them are allocated first and these after they are stored
+    fn lower_size(&self) -> u32 {
+        if self.fp_offset() > 504 {
+            self.saved_regs_offset()
+        } else {
+            0
+        }
+    }
+}
+
+#[derive(Clone, Copy)]
+struct Label {
+    id: usize,
+}
+
+impl Label {
+    fn new(id: usize) -> Self {
+        Self { id }
+    }
+
+    fn id(self) -> usize {
+        self.id
+    }
+}
+
+/// Addressing mode of a load or store through a pointer
+enum Offset {
+    Scaled(u16),
+    Unscaled(i16),
+    Index(Index),
+}
+
+/// Register offset, shifted left by `shift` and extended to 64 bits if it is a w register
+#[derive(Clone, Copy)]
+struct Index {
+    reg: Register,
+    shift: u8,
+    signed: bool,
+}
+
+impl Index {
+    fn new(reg: Register, shift: u8, signed: bool) -> Self {
+        Self { reg, shift, signed }
+    }
+}
+
+impl fmt::Display for Index {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let extend = match (self.reg.size(), self.signed) {
+            (Size::QuadWord, _) => "lsl",
+            (_, true) => "sxtw",
+            (_, false) => "uxtw",
+        };
+
+        match (extend, self.shift) {
+            ("lsl", 0) => write!(f, "{}", self.reg),
+            (extend, 0) => write!(f, "{}, {extend}", self.reg),
+            (extend, shift) => write!(f, "{}, {extend} #{shift}", self.reg),
+        }
+    }
+}
+
+/// Whether add/sub can take imm, which is 12 bits wide and can be shifted left by 12
+fn is_add_imm(imm: u32) -> bool {
+    imm >> 12 == 0 || (imm & 0xfff == 0 && imm >> 24 == 0)
+}
+
+/// Suffix of ldr for loading into a register of `size`
+fn load_suffix(size: Size, signed: bool) -> &'static str {
+    match (size, signed) {
+        (Size::Byte, true) => "sb",
+        (Size::Byte, false) => "b",
+        (Size::Word, true) => "sh",
+        (Size::Word, false) => "h",
+        _ => "",
+    }
+}
+
+/// Suffix of str for storing a register of `size`
+fn store_suffix(size: Size) -> &'static str {
+    match size {
+        Size::Byte => "b",
+        Size::Word => "h",
+        _ => "",
     }
 }
 
 enum Instruction {
     // Empty line in generated asm code
     Empty,
-    DataSection,
+    Section {
+        name: &'static str,
+    },
     Custom {
         string: String,
     },
@@ -343,22 +1615,110 @@ enum Instruction {
         src_1: Register,
         src_2: Register,
     },
+    Sub {
+        dest: Register,
+        src_1: Register,
+        src_2: Register,
+    },
+    Mul {
+        dest: Register,
+        src_1: Register,
+        src_2: Register,
+    },
+    Div {
+        dest: Register,
+        src_1: Register,
+        src_2: Register,
+        signed: bool,
+    },
+    MSub {
+        dest: Register,
+        src_1: Register,
+        src_2: Register,
+        src_3: Register,
+    },
+    Neg {
+        dest: Register,
+        src: Register,
+    },
+    /// Sign extends src, the extension is picked by the size of src
+    Sxt {
+        dest: Register,
+        src: Register,
+    },
+    /// Zero extends a byte or half word src into the w register dest
+    Uxt {
+        dest: Register,
+        src: Register,
+    },
+    And {
+        dest: Register,
+        src_1: Register,
+        src_2: Register,
+    },
+    Orr {
+        dest: Register,
+        src_1: Register,
+        src_2: Register,
+    },
+    Eor {
+        dest: Register,
+        src_1: Register,
+        src_2: Register,
+    },
+    Mvn {
+        dest: Register,
+        src: Register,
+    },
+    Lsl {
+        dest: Register,
+        src: Register,
+        amount: Register,
+    },
+    Lsr {
+        dest: Register,
+        src: Register,
+        amount: Register,
+    },
+    Asr {
+        dest: Register,
+        src: Register,
+        amount: Register,
+    },
+    /// `src_2` takes 12 bits, which can be shifted left by 12
     AddImm {
         dest: Register,
         src_1: Register,
-        src_2: u16,
+        src_2: u32,
     },
     SubImm {
         dest: Register,
         src_1: Register,
-        src_2: u16,
+        src_2: u32,
     },
+    /// Loads `size` bytes, sign or zero extending them to the size of dest
     Ldr {
         dest: Register,
         addr: Register,
         offset: u16,
+        size: Size,
+        signed: bool,
+    },
+    Fsqrt {
+        dest: Register,
+        src: Register,
+    },
+    /// Converts between integers and floats, or between float sizes
+    Convert {
+        dest: Register,
+        src: Register,
         signed: bool,
     },
+    AndImm {
+        dest: Register,
+        src: Register,
+        imm: u64,
+    },
     Ldp {
         dest_1: Register,
         dest_2: Register,
@@ -370,371 +1730,2201 @@ enum Instruction {
         addr: Register,
         offset: u16,
     },
-    Stp {
-        src_1: Register,
-        src_2: Register,
+    Ldur {
+        dest: Register,
         addr: Register,
-        offset: u16,
+        offset: i16,
+        signed: bool,
     },
-    Br {
-        label: Label,
+    Stur {
+        src: Register,
+        addr: Register,
+        offset: i16,
     },
-    Bl {
-        func: String,
+    LdrIndex {
+        dest: Register,
+        addr: Register,
+        index: Index,
+        signed: bool,
+    },
+    StrIndex {
+        src: Register,
+        addr: Register,
+        index: Index,
+    },
+    Stp {
+        src_1: Register,
+        src_2: Register,
+        addr: Register,
+        offset: u16,
+    },
+    Cmp {
+        src_1: Register,
+        src_2: Register,
+    },
+    CSet {
+        dest: Register,
+        cond: ir::Condition,
+    },
+    Br {
+        label: Label,
+    },
+    BCond {
+        cond: ir::Condition,
+        label: Label,
+    },
+    Cbz {
+        src: Register,
+        label: Label,
+    },
+    Cbnz {
+        src: Register,
+        label: Label,
+    },
+    /// Branches if bit `bit` of src is 0
+    Tbz {
+        src: Register,
+        bit: u8,
+        label: Label,
+    },
+    Bl {
+        func: String,
+    },
+    Blr {
+        func: Register,
     },
     Ret,
-    Adr {
+    Adrp {
+        dest: Register,
+        symbol: DataSymbol,
+        format: ObjectFormat,
+    },
+    AddPageOffset {
+        dest: Register,
+        src: Register,
+        symbol: DataSymbol,
+        format: ObjectFormat,
+    },
+    AdrpGot {
         dest: Register,
-        addr: DataAddr,
+        func: String,
+        format: ObjectFormat,
+    },
+    LdrGot {
+        dest: Register,
+        src: Register,
+        func: String,
+        format: ObjectFormat,
+    },
+
+    Data {
+        directive: DataDirective,
     },
+}
+
+impl Instruction {
+    fn custom(string: String) -> Self {
+        Self::Custom { string }
+    }
+
+    fn section(name: &'static str) -> Self {
+        Self::Section { name }
+    }
+
+    fn mov_imm(dest: Register, value: u64) -> Vec<Instruction> {
+        let mut asm = Vec::with_capacity(4);
+
+        let flag_16 = 0x000000000000FFFF;
+        let flag_32 = 0x00000000FFFF0000;
+        let flag_48 = 0x0000FFFF00000000;
+        let flag_64 = 0xFFFF000000000000;
+
+        let imm = (value & flag_16) as u16;
+        asm.push(Instruction::MovImm { dest, imm });
+
+        if value >> 16 == 0 || dest.size() == Size::Byte || dest.size() == Size::Word {
+            return asm;
+        }
+
+        let imm = ((value & flag_32) >> 16) as u16;
+        asm.push(Instruction::MovKImm {
+            dest,
+            imm,
+            offset: 16,
+        });
+
+        if value >> 32 == 0 || dest.size() == Size::DoubleWord {
+            return asm;
+        }
+
+        let imm = ((value & flag_48) >> 32) as u16;
+        asm.push(Instruction::MovKImm {
+            dest,
+            imm,
+            offset: 32,
+        });
+
+        if value >> 48 == 0 {
+            return asm;
+        }
+
+        let imm = ((value & flag_64) >> 48) as u16;
+        asm.push(Instruction::MovKImm {
+            dest,
+            imm,
+            offset: 48,
+        });
+
+        asm
+    }
+
+    fn mov(dest: Register, src: Register) -> Option<Self> {
+        // No need to return the instruction if it is a NOP
+        if dest == src {
+            None
+        } else {
+            Some(Self::MovReg { dest, src })
+        }
+    }
+
+    fn add(dest: Register, src_1: Register, src_2: Register) -> Self {
+        Self::Add { dest, src_1, src_2 }
+    }
+
+    fn sub(dest: Register, src_1: Register, src_2: Register) -> Self {
+        Self::Sub { dest, src_1, src_2 }
+    }
+
+    fn mul(dest: Register, src_1: Register, src_2: Register) -> Self {
+        Self::Mul { dest, src_1, src_2 }
+    }
+
+    fn div(dest: Register, src_1: Register, src_2: Register, signed: bool) -> Self {
+        Self::Div {
+            dest,
+            src_1,
+            src_2,
+            signed,
+        }
+    }
+
+    /// dest = src_3 - src_1 * src_2
+    fn msub(dest: Register, src_1: Register, src_2: Register, src_3: Register) -> Self {
+        Self::MSub {
+            dest,
+            src_1,
+            src_2,
+            src_3,
+        }
+    }
+
+    fn neg(dest: Register, src: Register) -> Self {
+        Self::Neg { dest, src }
+    }
+
+    fn fsqrt(dest: Register, src: Register) -> Self {
+        Self::Fsqrt { dest, src }
+    }
+
+    /// `signed` is the signedness of the integer side
+    fn convert(dest: Register, src: Register, signed: bool) -> Self {
+        Self::Convert { dest, src, signed }
+    }
+
+    fn sxt(dest: Register, src: Register) -> Self {
+        Self::Sxt { dest, src }
+    }
+
+    fn uxt(dest: Register, src: Register) -> Self {
+        assert!(matches!(src.size(), Size::Byte | Size::Word));
+
+        Self::Uxt { dest, src }
+    }
+
+    /// imm has to be encodable as a bitmask immediate
+    fn and_imm(dest: Register, src: Register, imm: u64) -> Self {
+        Self::AndImm { dest, src, imm }
+    }
+
+    fn and(dest: Register, src_1: Register, src_2: Register) -> Self {
+        Self::And { dest, src_1, src_2 }
+    }
+
+    fn orr(dest: Register, src_1: Register, src_2: Register) -> Self {
+        Self::Orr { dest, src_1, src_2 }
+    }
+
+    fn eor(dest: Register, src_1: Register, src_2: Register) -> Self {
+        Self::Eor { dest, src_1, src_2 }
+    }
+
+    fn mvn(dest: Register, src: Register) -> Self {
+        Self::Mvn { dest, src }
+    }
+
+    fn lsl(dest: Register, src: Register, amount: Register) -> Self {
+        Self::Lsl { dest, src, amount }
+    }
+
+    fn lsr(dest: Register, src: Register, amount: Register) -> Self {
+        Self::Lsr { dest, src, amount }
+    }
+
+    fn asr(dest: Register, src: Register, amount: Register) -> Self {
+        Self::Asr { dest, src, amount }
+    }
+
+    fn ldr(dest: Register, addr: Register, offset: u16, signed: bool) -> Self {
+        Self::Ldr {
+            dest,
+            addr,
+            offset,
+            size: dest.size(),
+            signed,
+        }
+    }
+
+    fn ldp(dest_1: Register, dest_2: Register, addr: Register, offset: u16) -> Self {
+        Self::Ldp {
+            dest_1,
+            dest_2,
+            addr,
+            offset,
+        }
+    }
+
+    /// Address of the 4 KiB page symbol is in
+    fn adrp(dest: Register, symbol: DataSymbol, format: ObjectFormat) -> Self {
+        Self::Adrp {
+            dest,
+            symbol,
+            format,
+        }
+    }
+
+    /// Adds the offset of symbol within its page
+    fn add_page_offset(
+        dest: Register,
+        src: Register,
+        symbol: DataSymbol,
+        format: ObjectFormat,
+    ) -> Self {
+        Self::AddPageOffset {
+            dest,
+            src,
+            symbol,
+            format,
+        }
+    }
+
+    /// Address of the 4 KiB page the GOT entry of func is in
+    fn adrp_got(dest: Register, func: String, format: ObjectFormat) -> Self {
+        Self::AdrpGot { dest, func, format }
+    }
+
+    /// Loads the GOT entry of func, at its offset within its page
+    fn ldr_got(dest: Register, src: Register, func: String, format: ObjectFormat) -> Self {
+        Self::LdrGot {
+            dest,
+            src,
+            func,
+            format,
+        }
+    }
+
+    fn str(src: Register, addr: Register, offset: u16) -> Self {
+        Self::Str { src, addr, offset }
+    }
+
+    fn ldur(dest: Register, addr: Register, offset: i16, signed: bool) -> Self {
+        Self::Ldur {
+            dest,
+            addr,
+            offset,
+            signed,
+        }
+    }
+
+    fn stur(src: Register, addr: Register, offset: i16) -> Self {
+        Self::Stur { src, addr, offset }
+    }
+
+    fn ldr_index(dest: Register, addr: Register, index: Index, signed: bool) -> Self {
+        Self::LdrIndex {
+            dest,
+            addr,
+            index,
+            signed,
+        }
+    }
+
+    fn str_index(src: Register, addr: Register, index: Index) -> Self {
+        Self::StrIndex { src, addr, index }
+    }
+
+    fn stp(src_1: Register, src_2: Register, addr: Register, offset: u16) -> Self {
+        Self::Stp {
+            src_1,
+            src_2,
+            addr,
+            offset,
+        }
+    }
+
+    fn b(label: Label) -> Self {
+        Self::Br { label }
+    }
+
+    fn cmp(src_1: Register, src_2: Register) -> Self {
+        Self::Cmp { src_1, src_2 }
+    }
+
+    fn cset(dest: Register, cond: ir::Condition) -> Self {
+        Self::CSet { dest, cond }
+    }
+
+    fn b_cond(cond: ir::Condition, label: Label) -> Self {
+        Self::BCond { cond, label }
+    }
+
+    fn cbz(src: Register, label: Label) -> Self {
+        Self::Cbz { src, label }
+    }
+
+    fn cbnz(src: Register, label: Label) -> Self {
+        Self::Cbnz { src, label }
+    }
+
+    fn tbz(src: Register, bit: u8, label: Label) -> Self {
+        Self::Tbz { src, bit, label }
+    }
+
+    fn bl(func: String) -> Self {
+        Self::Bl { func }
+    }
+
+    fn blr(func: Register) -> Self {
+        Self::Blr { func }
+    }
+
+    fn sub_imm(dest: Register, src_1: Register, src_2: u32) -> Self {
+        assert!(is_add_imm(src_2));
+
+        Self::SubImm { dest, src_1, src_2 }
+    }
+
+    fn add_imm(dest: Register, src_1: Register, src_2: u32) -> Self {
+        assert!(is_add_imm(src_2));
+
+        Self::AddImm { dest, src_1, src_2 }
+    }
+
+    fn label(label: Label) -> Self {
+        Self::Label { label }
+    }
+
+    fn ret() -> Self {
+        Self::Ret
+    }
+}
+
+impl fmt::Display for Instruction {
+    #[rustfmt::skip]
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Instruction::Empty                          => write!(f, ""),
+            Instruction::Custom { string }              => write!(f, "{string}"),
+            Instruction::Label { label }                => write!(f, "label_{}:", label.id()),
+            Instruction::MovReg { dest, src }           => write!(f, "    {}mov {dest}, {src}", float_prefix(dest.is_float() || src.is_float())),
+            Instruction::MovImm { dest, imm }           => write!(f, "    mov {dest}, #{imm}"),
+            Instruction::MovKImm { dest, imm, offset }  => write!(f, "    movk {dest}, #{imm}, lsl #{offset}"),
+            Instruction::Add { dest, src_1, src_2 }     => write!(f, "    {}add {dest}, {src_1}, {src_2}", float_prefix(dest.is_float())),
+            Instruction::Sub { dest, src_1, src_2 }     => write!(f, "    {}sub {dest}, {src_1}, {src_2}", float_prefix(dest.is_float())),
+            Instruction::Mul { dest, src_1, src_2 }     => write!(f, "    {}mul {dest}, {src_1}, {src_2}", float_prefix(dest.is_float())),
+            Instruction::Div { dest, src_1, src_2, .. } if dest.is_float() => write!(f, "    fdiv {dest}, {src_1}, {src_2}"),
+            Instruction::Div { dest, src_1, src_2, signed } => write!(f, "    {}div {dest}, {src_1}, {src_2}", if *signed { "s" } else { "u" }),
+            Instruction::MSub { dest, src_1, src_2, src_3 } => write!(f, "    msub {dest}, {src_1}, {src_2}, {src_3}"),
+            Instruction::Neg { dest, src }              => write!(f, "    {}neg {dest}, {src}", float_prefix(dest.is_float())),
+            Instruction::Fsqrt { dest, src }            => write!(f, "    fsqrt {dest}, {src}"),
+            Instruction::Convert { dest, src, signed } => {
+                let sign = if *signed { "s" } else { "u" };
+                match (src.is_float(), dest.is_float()) {
+                    (true, true)  => write!(f, "    fcvt {dest}, {src}"),
+                    (false, _)    => write!(f, "    {sign}cvtf {dest}, {src}"),
+                    (true, false) => write!(f, "    fcvtz{sign} {dest}, {src}"),
+                }
+            }
+            Instruction::Sxt { dest, src } => {
+                match src.size() {
+                    Size::Byte => write!(f, "    sxtb {dest}, {src}"),
+                    Size::Word => write!(f, "    sxth {dest}, {src}"),
+                    _          => write!(f, "    sxtw {dest}, {src}"),
+                }
+            }
+            Instruction::Uxt { dest, src } => {
+                match src.size() {
+                    Size::Byte => write!(f, "    uxtb {dest}, {src}"),
+                    _          => write!(f, "    uxth {dest}, {src}"),
+                }
+            }
+            Instruction::AndImm { dest, src, imm }      => write!(f, "    and {dest}, {src}, #{imm:#x}"),
+            Instruction::And { dest, src_1, src_2 }     => write!(f, "    and {dest}, {src_1}, {src_2}"),
+            Instruction::Orr { dest, src_1, src_2 }     => write!(f, "    orr {dest}, {src_1}, {src_2}"),
+            Instruction::Eor { dest, src_1, src_2 }     => write!(f, "    eor {dest}, {src_1}, {src_2}"),
+            Instruction::Mvn { dest, src }              => write!(f, "    mvn {dest}, {src}"),
+            Instruction::Lsl { dest, src, amount }      => write!(f, "    lsl {dest}, {src}, {amount}"),
+            Instruction::Lsr { dest, src, amount }      => write!(f, "    lsr {dest}, {src}, {amount}"),
+            Instruction::Asr { dest, src, amount }      => write!(f, "    asr {dest}, {src}, {amount}"),
+            Instruction::AddImm { dest, src_1, src_2 } if src_2 >> 12 != 0 => write!(f, "    add {dest}, {src_1}, #{}, lsl #12", src_2 >> 12),
+            Instruction::SubImm { dest, src_1, src_2 } if src_2 >> 12 != 0 => write!(f, "    sub {dest}, {src_1}, #{}, lsl #12", src_2 >> 12),
+            Instruction::AddImm { dest, src_1, src_2 }  => write!(f, "    add {dest}, {src_1}, #{src_2}"),
+            Instruction::SubImm { dest, src_1, src_2 }  => write!(f, "    sub {dest}, {src_1}, #{src_2}"),
+            Instruction::Cmp { src_1, src_2 }           => write!(f, "    {}cmp {src_1}, {src_2}", float_prefix(src_1.is_float())),
+            Instruction::CSet { dest, cond }            => write!(f, "    cset {dest}, {}", condition_code(*cond)),
+            Instruction::BCond { cond, label }          => write!(f, "    b.{} label_{}", condition_code(*cond), label.id()),
+            Instruction::Cbz { src, label }             => write!(f, "    cbz {src}, label_{}", label.id()),
+            Instruction::Cbnz { src, label }            => write!(f, "    cbnz {src}, label_{}", label.id()),
+            Instruction::Tbz { src, bit, label }        => write!(f, "    tbz {src}, #{bit}, label_{}", label.id()),
+            Instruction::Br { label }                   => write!(f, "    b label_{}", label.id()),
+            Instruction::Bl { func }                    => write!(f, "    bl {func}"),
+            Instruction::Blr { func }                   => write!(f, "    blr {func}"),
+            Instruction::Adrp { dest, symbol, format }  => {
+                match format {
+                    ObjectFormat::MachO => write!(f, "    adrp {dest}, {symbol}@PAGE"),
+                    ObjectFormat::Elf   => write!(f, "    adrp {dest}, {symbol}"),
+                }
+            }
+            Instruction::AddPageOffset { dest, src, symbol, format } => {
+                match format {
+                    ObjectFormat::MachO => write!(f, "    add {dest}, {src}, {symbol}@PAGEOFF"),
+                    ObjectFormat::Elf   => write!(f, "    add {dest}, {src}, :lo12:{symbol}"),
+                }
+            }
+            Instruction::AdrpGot { dest, func, format } => {
+                match format {
+                    ObjectFormat::MachO => write!(f, "    adrp {dest}, {func}@GOTPAGE"),
+                    ObjectFormat::Elf   => write!(f, "    adrp {dest}, :got:{func}"),
+                }
+            }
+            Instruction::LdrGot { dest, src, func, format } => {
+                match format {
+                    ObjectFormat::MachO => write!(f, "    ldr {dest}, [{src}, {func}@GOTPAGEOFF]"),
+                    ObjectFormat::Elf   => write!(f, "    ldr {dest}, [{src}, :got_lo12:{func}]"),
+                }
+            }
+            Instruction::Ret                            => write!(f, "    ret"),
+            Instruction::Section { name }               => write!(f, "{name}"),
+            Instruction::Ldr { dest, addr, offset, size, signed } => {
+                // Loads into w registers zero the upper half, so only sign extension needs an x register
+                let dest_w = Register::new(dest.number(), Size::DoubleWord);
+                match (size, signed) {
+                    (Size::F32 | Size::F64, _) => write!(f, "    ldr {dest}, "),
+                    (Size::DoubleWord, true) if dest.size() == Size::QuadWord => write!(f, "    ldrsw {dest}, "),
+                    (Size::Byte, true)  => write!(f, "    ldrsb {dest}, "),
+                    (Size::Word, true)  => write!(f, "    ldrsh {dest}, "),
+                    (Size::Byte, false) => write!(f, "    ldrb {dest_w}, "),
+                    (Size::Word, false) => write!(f, "    ldrh {dest_w}, "),
+                    (Size::DoubleWord, _) => write!(f, "    ldr {dest_w}, "),
+                    (Size::QuadWord, _)   => write!(f, "    ldr {dest}, "),
+                }?;
+
+                if *offset != 0 {
+                    write!(f, "[{addr}, #{offset}]")
+                } else {
+                    write!(f, "[{addr}]")
+                }
+            }
+            Instruction::Ldp { dest_1, dest_2, addr, offset } => {
+                write!(f, "    ldp {dest_1}, {dest_2}, ")?;
+
+                if *offset != 0 {
+                    write!(f, "[{addr}, #{offset}]")
+                } else {
+                    write!(f, "[{addr}]")
+                }
+            }
+            Instruction::Str { src, addr, offset } => {
+                match src.size() {
+                    Size::Byte       => write!(f, "    strb {src}, "),
+                    Size::Word       => write!(f, "    strh {src}, "),
+                    Size::DoubleWord => write!(f, "    str {src}, "),
+                    Size::QuadWord   => write!(f, "    str {src}, "),
+                    Size::F32        => write!(f, "    str {src}, "),
+                    Size::F64        => write!(f, "    str {src}, "),
+                }?;
+
+                if *offset != 0 {
+                    write!(f, "[{addr}, #{offset}]")
+                } else {
+                    write!(f, "[{addr}]")
+                }
+            }
+            Instruction::Ldur { dest, addr, offset, signed } => {
+                write!(f, "    ldur{} {dest}, [{addr}, #{offset}]", load_suffix(dest.size(), *signed))
+            }
+            Instruction::Stur { src, addr, offset } => {
+                write!(f, "    stur{} {src}, [{addr}, #{offset}]", store_suffix(src.size()))
+            }
+            Instruction::LdrIndex { dest, addr, index, signed } => {
+                write!(f, "    ldr{} {dest}, [{addr}, {index}]", load_suffix(dest.size(), *signed))
+            }
+            Instruction::StrIndex { src, addr, index } => {
+                write!(f, "    str{} {src}, [{addr}, {index}]", store_suffix(src.size()))
+            }
+            Instruction::Stp { src_1, src_2, addr, offset } => {
+                write!(f, "    stp {src_1}, {src_2}, ")?;
+
+                if *offset != 0 {
+                    write!(f, "[{addr}, #{offset}]")
+                } else {
+                    write!(f, "[{addr}]")
+                }
+            }
+
+            Instruction::Data { directive }             => write!(f, "{directive}"),
+        }
+    }
+}
+
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub(crate) struct Register {
+    number: RegisterNumber,
+    size: Size,
+}
+
+impl Register {
+    pub(crate) fn new(number: RegisterNumber, size: Size) -> Self {
+        Self { number, size }
+    }
+
+    pub(crate) fn number(self) -> RegisterNumber {
+        self.number
+    }
+
+    fn size(&self) -> Size {
+        self.size
+    }
+
+    fn is_float(self) -> bool {
+        self.number.is_float()
+    }
+
+    fn sp() -> Self {
+        Self::new(RegisterNumber::SP, Size::QuadWord)
+    }
+
+    /// Register a value of `size` is returned in
+    fn result(size: Size) -> Self {
+        if size.is_float() {
+            Self::new(RegisterNumber::V0, size)
+        } else {
+            Self::r0(size)
+        }
+    }
+
+    /// Register kept free of temporaries for moving a value of `size` around
+    fn scratch(size: Size) -> Self {
+        if size.is_float() {
+            Self::new(RegisterNumber::V31, size)
+        } else {
+            Self::r16(size)
+        }
+    }
+
+    fn x8() -> Self {
+        Self::new(RegisterNumber::R8, Size::QuadWord)
+    }
+
+    fn x29() -> Self {
+        Self::new(RegisterNumber::R29, Size::QuadWord)
+    }
+
+    fn x30() -> Self {
+        Self::new(RegisterNumber::R30, Size::QuadWord)
+    }
+
+    pub(crate) fn r0(size: Size) -> Self {
+        Self::new(RegisterNumber::R0, size)
+    }
+
+    fn r1(size: Size) -> Self {
+        Self::new(RegisterNumber::R1, size)
+    }
+
+    fn r2(size: Size) -> Self {
+        Self::new(RegisterNumber::R2, size)
+    }
+
+    fn r3(size: Size) -> Self {
+        Self::new(RegisterNumber::R3, size)
+    }
+
+    fn r4(size: Size) -> Self {
+        Self::new(RegisterNumber::R4, size)
+    }
+
+    fn r5(size: Size) -> Self {
+        Self::new(RegisterNumber::R5, size)
+    }
+
+    fn r6(size: Size) -> Self {
+        Self::new(RegisterNumber::R6, size)
+    }
+
+    fn r7(size: Size) -> Self {
+        Self::new(RegisterNumber::R7, size)
+    }
+
+    fn r16(size: Size) -> Self {
+        Self::new(RegisterNumber::R16, size)
+    }
+
+    fn r17(size: Size) -> Self {
+        Self::new(RegisterNumber::R17, size)
+    }
+}
+
+impl MachineRegister for Register {
+    type Number = RegisterNumber;
+
+    fn number(self) -> RegisterNumber {
+        self.number
+    }
+
+    fn size(self) -> Size {
+        self.size
+    }
+
+    fn resize(self, size: Size) -> Self {
+        Register::new(self.number, size)
+    }
+
+    fn scratch(size: Size) -> Self {
+        Register::scratch(size)
+    }
+}
+
+impl fmt::Display for Register {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        if self.number == RegisterNumber::SP {
+            return write!(f, "sp");
+        }
+
+        if self.is_float() {
+            let reg_num = self.number as u8 - RegisterNumber::V0 as u8;
+            return match self.size {
+                Size::F32 => write!(f, "s{reg_num}"),
+                Size::F64 => write!(f, "d{reg_num}"),
+                _ => unreachable!("Integer value in v{reg_num}"),
+            };
+        }
+
+        let reg_num = self.number as u8;
+        match self.size {
+            Size::Byte | Size::Word | Size::DoubleWord => write!(f, "w{reg_num}"),
+            Size::QuadWord => write!(f, "x{reg_num}"),
+            Size::F32 | Size::F64 => unreachable!("Float value in x{reg_num}"),
+        }
+    }
+}
+
+#[rustfmt::skip]
+#[allow(unused)]
+#[derive(Clone, Copy, PartialEq, Eq, Hash)]
+pub(crate) enum RegisterNumber {
+    R0,  R1,  R2,  R3,  R4,  R5,  R6,  R7,
+    R8,  R9,  R10, R11, R12, R13, R14, R15,
+    R16, R17, R18, R19, R20, R21, R22, R23,
+    R24, R25, R26, R27, R28, R29, R30, SP,
+    V0,  V1,  V2,  V3,  V4,  V5,  V6,  V7,
+    V8,  V9,  V10, V11, V12, V13, V14, V15,
+    V16, V17, V18, V19, V20, V21, V22, V23,
+    V24, V25, V26, V27, V28, V29, V30, V31,
+}
+
+impl RegisterNumber {
+    #[rustfmt::skip]
+    fn from_u8(number: u8) -> Self {
+        use RegisterNumber::*;
+        [
+            R0,  R1,  R2,  R3,  R4,  R5,  R6,  R7,
+            R8,  R9,  R10, R11, R12, R13, R14, R15,
+            R16, R17, R18, R19, R20, R21, R22, R23,
+            R24, R25, R26, R27, R28, R29, R30, SP,
+            V0,  V1,  V2,  V3,  V4,  V5,  V6,  V7,
+            V8,  V9,  V10, V11, V12, V13, V14, V15,
+            V16, V17, V18, V19, V20, V21, V22, V23,
+            V24, V25, V26, V27, V28, V29, V30, V31,
+        ][number as usize]
+    }
+
+    fn is_float(self) -> bool {
+        self as u8 >= RegisterNumber::V0 as u8
+    }
+}
+
+#[rustfmt::skip]
+fn usable_registers() -> Vec<RegisterNumber> {
+    use RegisterNumber::*;
+    vec![
+        R8,  R9,  R10, R11, R12, R13, 
+        R14, R15, R19, R20, R21, R22, 
+        R23, R24, R25, R26, R27, R28
+    ]
+}
+
+/// Every v register except the argument registers and v31, which is kept as a scratch register
+#[rustfmt::skip]
+fn usable_float_registers() -> Vec<RegisterNumber> {
+    use RegisterNumber::*;
+    vec![
+        V16, V17, V18, V19, V20, V21, V22, V23,
+        V24, V25, V26, V27, V28, V29, V30,
+        V8,  V9,  V10, V11, V12, V13, V14, V15,
+    ]
+}
+
+/// Registers a callee does not have to preserve
+#[rustfmt::skip]
+fn caller_saved_registers() -> Vec<RegisterNumber> {
+    use RegisterNumber::*;
+    vec![
+        R0,  R1,  R2,  R3,  R4,  R5,  R6,  R7,
+        R8,  R9,  R10, R11, R12, R13, R14, R15,
+        R16, R17, R18,
+        V0,  V1,  V2,  V3,  V4,  V5,  V6,  V7,
+        V16, V17, V18, V19, V20, V21, V22, V23,
+        V24, V25, V26, V27, V28, V29, V30, V31,
+    ]
+}
+
+/// Only the low 64 bits of v8-v15 are preserved, which is all a d register holds
+#[rustfmt::skip]
+fn callee_saved_registers() -> Vec<RegisterNumber> {
+    use RegisterNumber::*;
+    vec![
+        R19, R20, R21, R22, R23, R24, R25, R26, R27, R28,
+        V8,  V9,  V10, V11, V12, V13, V14, V15,
+    ]
+}
+
+/// Where an arg is passed
+enum ArgLocation {
+    Register(Register),
+    /// Offset in the stack args, aggregates are copied there whole
+    Stack(u32),
+    /// Aggregate split over registers, along with the offset in it each one holds
+    Registers(Vec<(Register, u16)>),
+    /// Aggregate copied by the caller, whose address is passed in a register or on the stack
+    Indirect(Result<Register, u32>),
+}
+
+/// Where the args of a function go, along with the registers and stack bytes they take
+struct ArgLocations {
+    locations: Vec<ArgLocation>,
+    /// x registers used
+    gr: u8,
+    /// v registers used
+    vr: u8,
+    /// Bytes of stack args
+    stack_size: u32,
+}
+
+/// Where each arg goes per AAPCS64, with Apple's changes on MachO: stack args only take
+/// their own size, and variadic args always go on the stack
+fn arg_locations(
+    types: &[ir::Type],
+    fixed_args: Option<usize>,
+    format: ObjectFormat,
+) -> ArgLocations {
+    let mut gr = 0;
+    let mut vr = 0;
+    let mut stack_size: u32 = 0;
+    let mut on_stack = |size: u32, align: u32| {
+        let offset = stack_size.next_multiple_of(align);
+        stack_size = offset + size;
+        offset
+    };
+
+    let mut locations = Vec::with_capacity(types.len());
+    for (index, ty) in types.iter().enumerate() {
+        let location = match ty {
+            ir::Type::Scalar(size, _) => {
+                let is_variadic = fixed_args.is_some_and(|fixed_args| index >= fixed_args);
+
+                let arg_num = if size.is_float() { &mut vr } else { &mut gr };
+                let arg_reg = match format {
+                    ObjectFormat::MachO if is_variadic => None,
+                    _ => arg_register(*arg_num, *size),
+                };
+
+                match arg_reg {
+                    Some(arg_reg) => {
+                        *arg_num += 1;
+                        ArgLocation::Register(arg_reg)
+                    }
+                    None => {
+                        let slot_size = match format {
+                            ObjectFormat::MachO if !is_variadic => size.in_bytes().into(),
+                            _ => 8,
+                        };
+                        ArgLocation::Stack(on_stack(slot_size, slot_size))
+                    }
+                }
+            }
+            ir::Type::Aggregate(aggregate) => {
+                let class = AggregateClass::of(aggregate);
+                // A 16 byte aligned aggregate passed in x registers starts at an even one,
+                // HFAs and aggregates passed by address are placed as usual
+                let general_gr = if aggregate.align() == 16 {
+                    gr.next_multiple_of(2)
+                } else {
+                    gr
+                };
+
+                match class {
+                    AggregateClass::Hfa { count, .. } if vr + count <= 8 => {
+                        let regs = class.registers(gr, vr);
+                        vr += count;
+                        ArgLocation::Registers(regs)
+                    }
+                    AggregateClass::General { count } if general_gr + count <= 8 => {
+                        let regs = class.registers(general_gr, vr);
+                        gr = general_gr + count;
+                        ArgLocation::Registers(regs)
+                    }
+                    AggregateClass::Indirect => {
+                        let addr = arg_register(gr, Size::QuadWord).ok_or_else(|| on_stack(8, 8));
+                        if addr.is_ok() {
+                            gr += 1;
+                        }
+                        ArgLocation::Indirect(addr)
+                    }
+                    // Aggregates are never split between registers and the stack, and once
+                    // one goes on the stack the rest of its class follows
+                    _ => {
+                        match class {
+                            AggregateClass::Hfa { .. } => vr = 8,
+                            _ => gr = 8,
+                        }
+                        let size = u32::from(aggregate.size()).next_multiple_of(8);
+                        ArgLocation::Stack(on_stack(size, aggregate.align().max(8).into()))
+                    }
+                }
+            }
+        };
+        locations.push(location);
+    }
+
+    ArgLocations {
+        locations,
+        gr,
+        vr,
+        stack_size,
+    }
+}
+
+/// Where the args of a call go, along with the copies of the aggregates passed in registers
+/// or by address, which are placed after the stack args in the outgoing args area
+struct CallLayout {
+    args: ArgLocations,
+    /// Offset from sp of the copy of each arg, if it is an aggregate that needs one
+    copies: Vec<Option<u32>>,
+    /// Bytes of the outgoing args area the call needs
+    size: u32,
+}
+
+impl CallLayout {
+    fn new(types: &[ir::Type], fixed_args: Option<usize>, format: ObjectFormat) -> Self {
+        let args = arg_locations(types, fixed_args, format);
+
+        let mut size = args.stack_size;
+        let copies = types
+            .iter()
+            .zip(&args.locations)
+            .map(|(ty, location)| match (ty, location) {
+                (
+                    ir::Type::Aggregate(aggregate),
+                    ArgLocation::Registers(_) | ArgLocation::Indirect(_),
+                ) => {
+                    let offset = size.next_multiple_of(aggregate.align().max(8).into());
+                    size = offset + u32::from(aggregate.size()).next_multiple_of(8);
+                    Some(offset)
+                }
+                _ => None,
+            })
+            .collect();
+
+        Self {
+            args,
+            copies,
+            size: size.next_multiple_of(16),
+        }
+    }
+}
+
+/// How AAPCS64 passes an aggregate
+#[derive(Clone, Copy)]
+enum AggregateClass {
+    /// Homogeneous floating point aggregate: up to 4 floats of the same size and nothing else,
+    /// each in its own v register
+    Hfa { size: Size, count: u8 },
+    /// Up to 16 bytes, in x registers 8 bytes at a time
+    General { count: u8 },
+    /// Anything bigger, copied to memory and passed by its address
+    Indirect,
+}
+
+impl AggregateClass {
+    fn of(aggregate: &ir::Aggregate) -> Self {
+        let fields = aggregate.fields();
+        let member = fields[0].1;
+
+        let is_hfa = member.is_float()
+            && fields.len() <= 4
+            && aggregate.size() == fields.len() as u16 * member.in_bytes()
+            && fields.iter().enumerate().all(|(index, (offset, size))| {
+                *size == member && *offset == index as u16 * member.in_bytes()
+            });
+
+        if is_hfa {
+            AggregateClass::Hfa {
+                size: member,
+                count: fields.len() as u8,
+            }
+        } else if aggregate.size() <= 16 {
+            AggregateClass::General {
+                count: aggregate.size().div_ceil(8) as u8,
+            }
+        } else {
+            AggregateClass::Indirect
+        }
+    }
+
+    /// Registers holding the aggregate starting from the given x and v registers, along with
+    /// the offset in it each one holds
+    fn registers(self, first_gr: u8, first_vr: u8) -> Vec<(Register, u16)> {
+        match self {
+            AggregateClass::Hfa { size, count } => (0..count)
+                .map(|index| {
+                    let reg = arg_register(first_vr + index, size).unwrap();
+                    (reg, index as u16 * size.in_bytes())
+                })
+                .collect(),
+            AggregateClass::General { count } => (0..count)
+                .map(|index| {
+                    let reg = arg_register(first_gr + index, Size::QuadWord).unwrap();
+                    (reg, index as u16 * 8)
+                })
+                .collect(),
+            AggregateClass::Indirect => Vec::new(),
+        }
+    }
+}
+
+/// Registers an aggregate is returned in, `None` if it is returned through the address in x8
+fn return_registers(aggregate: &ir::Aggregate) -> Option<Vec<(Register, u16)>> {
+    match AggregateClass::of(aggregate) {
+        AggregateClass::Indirect => None,
+        class => Some(class.registers(0, 0)),
+    }
+}
+
+/// Register of the `arg_num`th argument of the class of `size`
+fn arg_register(arg_num: u8, size: Size) -> Option<Register> {
+    if size.is_float() {
+        return (arg_num < 8).then(|| {
+            Register::new(
+                RegisterNumber::from_u8(RegisterNumber::V0 as u8 + arg_num),
+                size,
+            )
+        });
+    }
+
+    match arg_num {
+        0 => Some(Register::r0(size)),
+        1 => Some(Register::r1(size)),
+        2 => Some(Register::r2(size)),
+        3 => Some(Register::r3(size)),
+        4 => Some(Register::r4(size)),
+        5 => Some(Register::r5(size)),
+        6 => Some(Register::r6(size)),
+        7 => Some(Register::r7(size)),
+        _ => None,
+    }
+}
+
+fn condition_code(cond: ir::Condition) -> &'static str {
+    match cond {
+        ir::Condition::Eq => "eq",
+        ir::Condition::Ne => "ne",
+        ir::Condition::Slt => "lt",
+        ir::Condition::Sle => "le",
+        ir::Condition::Sgt => "gt",
+        ir::Condition::Sge => "ge",
+        ir::Condition::Ult => "lo",
+        ir::Condition::Ule => "ls",
+        ir::Condition::Ugt => "hi",
+        ir::Condition::Uge => "hs",
+        // Unordered comparisons set C and V, these are false for them
+        ir::Condition::Flt => "mi",
+        ir::Condition::Fle => "ls",
+        ir::Condition::Fgt => "gt",
+        ir::Condition::Fge => "ge",
+    }
+}
+
+fn float_prefix(is_float: bool) -> &'static str {
+    if is_float {
+        "f"
+    } else {
+        ""
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        ir::{Aggregate, Condition, Function, Module, Signature, Value},
+        util::{Liveness, RegisterAllocator},
+    };
+
+    fn generate(func: Function) -> Vec<String> {
+        let mut module = Module::new();
+        module.add_func(func);
+        module
+            .generate_asm_for(&Arm64::new(ObjectFormat::MachO))
+            .lines()
+            .to_vec()
+    }
+
+    fn has_line(lines: &[String], prefix: &str) -> bool {
+        lines.iter().any(|line| line.starts_with(prefix))
+    }
+
+    /// Whether lines starting with each of `prefixes` come one after the other
+    fn has_lines(lines: &[String], prefixes: &[&str]) -> bool {
+        lines.windows(prefixes.len()).any(|window| {
+            window
+                .iter()
+                .zip(prefixes)
+                .all(|(line, prefix)| line.starts_with(prefix))
+        })
+    }
+
+    /// Each arg location written out, registers by name and stack args by offset
+    fn locations_of(args: &ArgLocations) -> Vec<String> {
+        args.locations
+            .iter()
+            .map(|location| match location {
+                ArgLocation::Register(reg) => reg.to_string(),
+                ArgLocation::Stack(offset) => format!("[sp, {offset}]"),
+                ArgLocation::Registers(regs) => registers_of(regs).join(" "),
+                ArgLocation::Indirect(Ok(reg)) => format!("&{reg}"),
+                ArgLocation::Indirect(Err(offset)) => format!("&[sp, {offset}]"),
+            })
+            .collect()
+    }
+
+    /// Registers holding an aggregate, each with the offset in it it holds
+    fn registers_of(regs: &[(Register, u16)]) -> Vec<String> {
+        regs.iter()
+            .map(|(reg, offset)| format!("{reg}@{offset}"))
+            .collect()
+    }
+
+    fn scalars(sizes: &[Size]) -> Vec<ir::Type> {
+        sizes
+            .iter()
+            .map(|size| ir::Type::Scalar(*size, true))
+            .collect()
+    }
+
+    /// 8 x and 8 v register args, followed by stack args of every size
+    fn mixed_args() -> Vec<ir::Type> {
+        let mut sizes = Vec::new();
+        for _ in 0..8 {
+            sizes.extend([Size::QuadWord, Size::F64]);
+        }
+        sizes.extend([
+            Size::Byte,
+            Size::F32,
+            Size::Word,
+            Size::F64,
+            Size::DoubleWord,
+            Size::QuadWord,
+        ]);
+        scalars(&sizes)
+    }
+
+    /// Prologue and epilogue of a leaf function whose temporaries were given `numbers`
+    fn saving(numbers: &[RegisterNumber]) -> Vec<String> {
+        let mut func = Function::new("saving".to_string());
+        let registers = numbers
+            .iter()
+            .map(|number| {
+                let value = if number.is_float() {
+                    Value::F64(0.0)
+                } else {
+                    Value::I64(0)
+                };
+                (func.add_inst_set(value), *number as u8)
+            })
+            .collect();
+        func.add_inst_return(None);
+
+        let target = Arm64::new(ObjectFormat::MachO);
+        let layout = target.frame_layout(&func, &registers);
+        let frame = Frame::new(&func, &layout, ObjectFormat::MachO);
+        let mut asm = Asm::new(ObjectFormat::MachO, target.stack_guard_size);
+        asm.generate_func_prologue(&func, &frame, &HashMap::new());
+        asm.generate_func_epilogue(&func, &frame, Label::new(0));
+
+        asm.instructions
+            .iter()
+            .map(|inst| inst.to_string())
+            .collect()
+    }
+
+    /// Variadic function with the named args `sizes`, returning its first variadic arg,
+    /// along with the register holding its `va_list`
+    fn va_arg_of(sizes: &[Size], size: Size, format: ObjectFormat) -> (Vec<String>, String) {
+        let mut func = Function::new("va".to_string());
+        func.make_variadic();
+        for size in sizes {
+            func.add_arg(*size, true);
+        }
+        let va_list = func.add_va_list();
+        let addr = func.add_inst_slot_addr(va_list);
+        func.add_inst_va_start(addr);
+        let value = func.add_inst_va_arg(addr, size, true);
+        func.add_inst_va_end(addr);
+        func.add_inst_return(Some(value));
+
+        let mut module = Module::new();
+        module.add_func(func);
+        let lines = module
+            .generate_asm_for(&Arm64::new(format))
+            .lines()
+            .to_vec();
+
+        // va_start stores __stack, or the whole Apple va_list, first
+        let va_list = lines
+            .iter()
+            .find_map(|line| line.strip_prefix("    str x16, [")?.strip_suffix(']'))
+            .unwrap()
+            .to_string();
+
+        (lines, va_list)
+    }
+
+    /// Returns `a op b` for two args of `size`
+    fn binary(
+        size: Size,
+        signed: bool,
+        op: fn(&mut Function, ir::Temporary, ir::Temporary) -> ir::Temporary,
+    ) -> Vec<String> {
+        let mut func = Function::new("binary".to_string());
+        let a = func.add_arg(size, signed);
+        let b = func.add_arg(size, signed);
+        let a = func.add_inst_load(a);
+        let b = func.add_inst_load(b);
+        let result = op(&mut func, a, b);
+        func.add_inst_return(Some(result));
+
+        generate(func)
+    }
+
+    /// Returns `a cond b` for two args of `size`
+    fn compare(size: Size, signed: bool, cond: Condition) -> Vec<String> {
+        let mut func = Function::new("compare".to_string());
+        let a = func.add_arg(size, signed);
+        let b = func.add_arg(size, signed);
+        let a = func.add_inst_load(a);
+        let b = func.add_inst_load(b);
+        let result = func.add_inst_cmp(cond, a, b);
+        func.add_inst_return(Some(result));
+
+        generate(func)
+    }
+
+    #[test]
+    fn cmp_sign_extends_i8() {
+        let lines = compare(Size::Byte, true, Condition::Slt);
+
+        assert!(has_line(&lines, "    sxtb w16, "));
+        assert!(has_line(&lines, "    sxtb w17, "));
+        assert!(has_line(&lines, "    cmp w16, w17"));
+    }
+
+    #[test]
+    fn cmp_zero_extends_u8() {
+        let lines = compare(Size::Byte, false, Condition::Ugt);
+
+        assert!(has_line(&lines, "    uxtb w16, "));
+        assert!(has_line(&lines, "    uxtb w17, "));
+        assert!(has_line(&lines, "    cmp w16, w17"));
+    }
+
+    #[test]
+    fn cmp_sign_extends_i16() {
+        let lines = compare(Size::Word, true, Condition::Sge);
+
+        assert!(has_line(&lines, "    sxth w16, "));
+        assert!(has_line(&lines, "    sxth w17, "));
+        assert!(has_line(&lines, "    cmp w16, w17"));
+    }
+
+    #[test]
+    fn cmp_extends_by_condition() {
+        // Unsigned conditions read signed bytes as unsigned
+        let lines = compare(Size::Byte, true, Condition::Ult);
+        assert!(has_line(&lines, "    uxtb w16, "));
+
+        // Equality follows the operands
+        let lines = compare(Size::Word, false, Condition::Eq);
+        assert!(has_line(&lines, "    uxth w16, "));
+    }
+
+    #[test]
+    fn cmp_leaves_wide_operands() {
+        let lines = compare(Size::DoubleWord, true, Condition::Slt);
+
+        assert!(!has_line(&lines, "    sxt"));
+        assert!(!has_line(&lines, "    cmp w16"));
+    }
+
+    #[test]
+    fn branch_on_byte_zero_extends_before_cbz() {
+        let mut func = Function::new("branch".to_string());
+        let a = func.add_arg(Size::Byte, true);
+        let a = func.add_inst_load(a);
+        let then_label = func.add_label();
+        let else_label = func.add_label();
+        func.add_inst_branch_if(a, then_label, else_label);
+        func.add_block(then_label);
+        func.add_inst_return(Some(a));
+        func.add_block(else_label);
+        func.add_inst_return(Some(a));
+
+        let lines = generate(func);
+
+        assert!(has_line(&lines, "    uxtb w16, "));
+        assert!(has_line(&lines, "    cbz w16, "));
+    }
+
+    #[test]
+    fn div_sign_extends_i8() {
+        let lines = binary(Size::Byte, true, Function::add_inst_sdiv);
+
+        assert!(has_line(&lines, "    sxtb w16, "));
+        assert!(has_line(&lines, "    sxtb w17, "));
+        assert!(lines
+            .iter()
+            .any(|line| line.starts_with("    sdiv w") && line.ends_with(", w16, w17")));
+    }
 
-    DataLabel {
-        id: usize,
-    },
-    LocalDataLabel {
-        id: usize,
-    },
-    AscizData {
-        value: String,
-    },
-}
+    #[test]
+    fn div_zero_extends_u16() {
+        let lines = binary(Size::Word, false, Function::add_inst_udiv);
 
-impl Instruction {
-    fn custom(string: String) -> Self {
-        Self::Custom { string }
+        assert!(has_line(&lines, "    uxth w16, "));
+        assert!(has_line(&lines, "    uxth w17, "));
+        assert!(lines
+            .iter()
+            .any(|line| line.starts_with("    udiv w") && line.ends_with(", w16, w17")));
     }
 
-    fn mov_imm(dest: Register, value: u64) -> Vec<Instruction> {
-        let mut asm = Vec::with_capacity(4);
+    #[test]
+    fn rem_divides_extended_operands() {
+        let lines = binary(Size::Byte, true, Function::add_inst_srem);
 
-        let flag_16 = 0x000000000000FFFF;
-        let flag_32 = 0x00000000FFFF0000;
-        let flag_48 = 0x0000FFFF00000000;
-        let flag_64 = 0xFFFF000000000000;
+        assert!(has_line(&lines, "    sxtb w16, "));
+        assert!(has_line(&lines, "    sxtb w17, "));
+        assert!(lines
+            .iter()
+            .any(|line| line.starts_with("    msub w") && line.ends_with(", w17, w16")));
+    }
 
-        let imm = (value & flag_16) as u16;
-        asm.push(Instruction::MovImm { dest, imm });
+    #[test]
+    fn div_leaves_wide_operands() {
+        let lines = binary(Size::QuadWord, true, Function::add_inst_sdiv);
 
-        if value >> 16 == 0 || dest.size() == Size::Byte || dest.size() == Size::Word {
-            return asm;
-        }
+        assert!(!has_line(&lines, "    sxt"));
+        assert!(has_line(&lines, "    sdiv x"));
+    }
 
-        let imm = ((value & flag_32) >> 16) as u16;
-        asm.push(Instruction::MovKImm {
-            dest,
-            imm,
-            offset: 16,
-        });
+    #[test]
+    fn lshr_zero_extends_narrow_src() {
+        let lines = binary(Size::Byte, false, Function::add_inst_lshr);
 
-        if value >> 32 == 0 || dest.size() == Size::DoubleWord {
-            return asm;
-        }
+        assert!(has_line(&lines, "    uxtb w16, "));
+        assert!(has_line(&lines, "    lsr w"));
+        assert!(lines.iter().any(|line| line.contains(", w16, w")));
+    }
 
-        let imm = ((value & flag_48) >> 32) as u16;
-        asm.push(Instruction::MovKImm {
-            dest,
-            imm,
-            offset: 32,
-        });
+    #[test]
+    fn ashr_sign_extends_narrow_src() {
+        // Shifting signed values logically still zero extends
+        let lines = binary(Size::Word, true, Function::add_inst_lshr);
+        assert!(has_line(&lines, "    uxth w16, "));
 
-        if value >> 48 == 0 {
-            return asm;
-        }
+        let lines = binary(Size::Word, true, Function::add_inst_ashr);
+        assert!(has_line(&lines, "    sxth w16, "));
+        assert!(has_line(&lines, "    asr w"));
+    }
 
-        let imm = ((value & flag_64) >> 48) as u16;
-        asm.push(Instruction::MovKImm {
-            dest,
-            imm,
-            offset: 48,
-        });
+    #[test]
+    fn shr_leaves_wide_src() {
+        let lines = binary(Size::QuadWord, true, Function::add_inst_ashr);
 
-        asm
+        assert!(!has_line(&lines, "    sxt"));
+        assert!(has_line(&lines, "    asr x"));
     }
 
-    fn mov(dest: Register, src: Register) -> Option<Self> {
-        // No need to return the instruction if it is a NOP
-        if dest == src {
-            None
-        } else {
-            Some(Self::MovReg { dest, src })
-        }
+    /// Returns an arg of `from` converted to `to` with `op`
+    fn convert(
+        from: Size,
+        signed: bool,
+        to: Size,
+        op: fn(&mut Function, ir::Temporary, Size) -> ir::Temporary,
+    ) -> Vec<String> {
+        let mut func = Function::new("convert".to_string());
+        let src = func.add_arg(from, signed);
+        let src = func.add_inst_load(src);
+        let result = op(&mut func, src, to);
+        func.add_inst_return(Some(result));
+
+        generate(func)
     }
 
-    fn add(dest: Register, src_1: Register, src_2: Register) -> Self {
-        Self::Add { dest, src_1, src_2 }
+    /// The access through the pointer in `lines`, the only one not going to the stack
+    fn pointer_access(lines: &[String]) -> &str {
+        let accesses: Vec<_> = lines
+            .iter()
+            .filter(|line| line.contains('[') && !line.contains("[sp"))
+            .collect();
+        assert_eq!(accesses.len(), 1);
+        accesses[0]
     }
 
-    fn ldr(dest: Register, addr: Register, offset: u16, signed: bool) -> Self {
-        Self::Ldr {
-            dest,
-            addr,
-            offset,
-            signed,
-        }
+    /// Loads an i64 at `offset` from a pointer arg
+    fn load_at(offset: i32) -> Vec<String> {
+        let mut func = Function::new("load_at".to_string());
+        let ptr = func.add_arg(Size::QuadWord, false);
+        let ptr = func.add_inst_load(ptr);
+        let value = func.add_inst_load_ptr(ptr, Size::QuadWord, true, offset);
+        func.add_inst_return(Some(value));
+
+        generate(func)
     }
 
-    fn ldp(dest_1: Register, dest_2: Register, addr: Register, offset: u16) -> Self {
-        Self::Ldp {
-            dest_1,
-            dest_2,
-            addr,
-            offset,
-        }
+    /// Loads an i64 from a pointer arg indexed by an arg of `index_size`
+    fn load_indexed(index_size: Size, signed: bool, scaled: bool) -> Vec<String> {
+        let mut func = Function::new("load_indexed".to_string());
+        let ptr = func.add_arg(Size::QuadWord, false);
+        let index = func.add_arg(index_size, signed);
+        let ptr = func.add_inst_load(ptr);
+        let index = func.add_inst_load(index);
+        let value = func.add_inst_load_ptr_indexed(ptr, index, scaled, Size::QuadWord, true);
+        func.add_inst_return(Some(value));
+
+        generate(func)
     }
 
-    fn adr(dest: Register, addr: ir::DataAddr) -> Self {
-        Self::Adr { dest, addr }
+    #[test]
+    fn ptr_offset_scales_up_to_4095_times_the_size() {
+        let lines = load_at(4095 * 8);
+        let access = pointer_access(&lines);
+
+        assert!(access.starts_with("    ldr x"));
+        assert!(access.ends_with(", #32760]"));
     }
 
-    fn str(src: Register, addr: Register, offset: u16) -> Self {
-        Self::Str { src, addr, offset }
+    #[test]
+    fn ptr_offset_moves_4096_times_the_size_into_x16() {
+        let lines = load_at(4096 * 8);
+        let access = pointer_access(&lines);
+
+        assert!(has_line(&lines, "    mov x16, #32768"));
+        assert!(access.starts_with("    ldr x"));
+        assert!(access.ends_with(", x16]"));
     }
 
-    fn stp(src_1: Register, src_2: Register, addr: Register, offset: u16) -> Self {
-        Self::Stp {
-            src_1,
-            src_2,
-            addr,
-            offset,
-        }
+    #[test]
+    fn ptr_offset_falls_back_to_unscaled_from_minus_256_to_255() {
+        let lines = load_at(-256);
+        let access = pointer_access(&lines);
+        assert!(access.starts_with("    ldur x"));
+        assert!(access.ends_with(", #-256]"));
+
+        let lines = load_at(255);
+        let access = pointer_access(&lines);
+        assert!(access.starts_with("    ldur x"));
+        assert!(access.ends_with(", #255]"));
     }
 
-    fn b(label: Label) -> Self {
-        Self::Br { label }
+    #[test]
+    fn ptr_offset_moves_minus_257_into_x16() {
+        let lines = load_at(-257);
+        let access = pointer_access(&lines);
+
+        assert!(has_line(&lines, "    mov x16, "));
+        assert!(access.starts_with("    ldr x"));
+        assert!(access.ends_with(", x16]"));
     }
 
-    fn bl(func: String) -> Self {
-        Self::Bl { func }
+    #[test]
+    fn ptr_offset_misaligned_is_unscaled_or_in_x16() {
+        let lines = load_at(12);
+        let access = pointer_access(&lines);
+        assert!(access.starts_with("    ldur x"));
+        assert!(access.ends_with(", #12]"));
+
+        // Too far for ldur, and not a multiple of 8 for ldr
+        let lines = load_at(4100);
+        let access = pointer_access(&lines);
+        assert!(has_line(&lines, "    mov x16, #4100"));
+        assert!(access.ends_with(", x16]"));
     }
 
-    fn sub_imm(dest: Register, src_1: Register, src_2: u16) -> Self {
-        // Imm is 12 bits wide
-        assert!(src_2 >> 12 == 0);
+    #[test]
+    fn ptr_offset_scales_index_by_the_size() {
+        let lines = load_indexed(Size::QuadWord, false, true);
+        assert!(pointer_access(&lines).ends_with(", lsl #3]"));
 
-        Self::SubImm { dest, src_1, src_2 }
+        let lines = load_indexed(Size::DoubleWord, true, true);
+        assert!(pointer_access(&lines).ends_with(", sxtw #3]"));
     }
 
-    fn add_imm(dest: Register, src_1: Register, src_2: u16) -> Self {
-        // Imm is 12 bits wide
-        assert!(src_2 >> 12 == 0);
+    #[test]
+    fn ptr_offset_leaves_unscaled_index_unshifted() {
+        let lines = load_indexed(Size::QuadWord, false, false);
+        let access = pointer_access(&lines);
+        assert!(!access.contains("lsl"));
+        assert!(!access.contains('#'));
 
-        Self::AddImm { dest, src_1, src_2 }
+        let lines = load_indexed(Size::DoubleWord, false, false);
+        assert!(pointer_access(&lines).ends_with(", uxtw]"));
     }
 
-    fn data_label(id: usize) -> Self {
-        Self::DataLabel { id }
+    #[test]
+    fn ldr_sign_extends_i32_into_x_register() {
+        let load = |signed| Instruction::Ldr {
+            dest: Register::new(RegisterNumber::R8, Size::QuadWord),
+            addr: Register::sp(),
+            offset: 8,
+            size: Size::DoubleWord,
+            signed,
+        };
+
+        assert_eq!(load(true).to_string(), "    ldrsw x8, [sp, #8]");
+        // Writing w8 already zeroes the upper half
+        assert_eq!(load(false).to_string(), "    ldr w8, [sp, #8]");
     }
 
-    fn locla_data_label(id: usize) -> Self {
-        Self::LocalDataLabel { id }
+    #[test]
+    fn zext_uses_uxtb_and_uxth() {
+        let lines = convert(Size::Byte, false, Size::QuadWord, Function::add_inst_zext);
+        assert!(has_line(&lines, "    uxtb w"));
+
+        let lines = convert(Size::Word, false, Size::DoubleWord, Function::add_inst_zext);
+        assert!(has_line(&lines, "    uxth w"));
     }
 
-    fn asciz_data(value: String) -> Self {
-        Self::AscizData { value }
+    #[test]
+    fn sext_of_i32_uses_sxtw() {
+        let sext = Function::add_inst_sext;
+        let lines = convert(Size::DoubleWord, true, Size::QuadWord, sext);
+
+        assert!(has_line(&lines, "    sxtw x"));
     }
 
-    fn label(label: Label) -> Self {
-        Self::Label { label }
+    #[test]
+    fn trunc_masks_with_and() {
+        let masks = |lines: &[String], mask: &str| {
+            lines
+                .iter()
+                .any(|line| line.starts_with("    and w") && line.ends_with(mask))
+        };
+
+        let lines = convert(Size::QuadWord, false, Size::Byte, Function::add_inst_trunc);
+        assert!(masks(&lines, ", #0xff"));
+
+        let lines = convert(Size::QuadWord, false, Size::Word, Function::add_inst_trunc);
+        assert!(masks(&lines, ", #0xffff"));
     }
 
-    fn ret() -> Self {
-        Self::Ret
+    #[test]
+    fn saves_callee_saved_registers_in_pairs() {
+        use RegisterNumber::*;
+        let lines = saving(&[R19, R20, R21, R22, R23, R24, R25, R26, R27, R28]);
+
+        assert!(has_line(&lines, "    sub sp, sp, #80"));
+        assert!(has_line(&lines, "    stp x19, x20, [sp]"));
+        assert!(has_line(&lines, "    stp x21, x22, [sp, #16]"));
+        assert!(has_line(&lines, "    stp x27, x28, [sp, #64]"));
+        assert!(has_line(&lines, "    ldp x19, x20, [sp]"));
+        assert!(has_line(&lines, "    ldp x27, x28, [sp, #64]"));
+        assert!(has_line(&lines, "    add sp, sp, #80"));
     }
-}
 
-impl fmt::Display for Instruction {
-    #[rustfmt::skip]
-    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
-        match self {
-            Instruction::Empty                          => write!(f, ""),
-            Instruction::Custom { string }              => write!(f, "{string}"),
-            Instruction::Label { label }                => write!(f, "label_{}:", label.id()),
-            Instruction::MovReg { dest, src }           => write!(f, "    mov {dest}, {src}"),
-            Instruction::MovImm { dest, imm }           => write!(f, "    mov {dest}, #{imm}"),
-            Instruction::MovKImm { dest, imm, offset }  => write!(f, "    movk {dest}, #{imm}, lsl #{offset}"),
-            Instruction::Add { dest, src_1, src_2 }     => write!(f, "    add {dest}, {src_1}, {src_2}"),
-            Instruction::AddImm { dest, src_1, src_2 }  => write!(f, "    add {dest}, {src_1}, #{src_2}"),
-            Instruction::SubImm { dest, src_1, src_2 }  => write!(f, "    sub {dest}, {src_1}, #{src_2}"),
-            Instruction::Br { label }                   => write!(f, "    b label_{}", label.id()),
-            Instruction::Bl { func }                    => write!(f, "    bl {func}"),
-            Instruction::Adr { dest, addr }             => write!(f, "    adr {dest}, local_data_{}", addr.id()),
-            Instruction::Ret                            => write!(f, "    ret"),
-            Instruction::DataSection                    => write!(f, ".data"),
-            Instruction::Ldr { dest, addr, offset, signed } => {
-                match dest.size() {
-                    Size::Byte       => write!(f, "    ldr{}b {dest}, ", if *signed { "s" } else { "" }),
-                    Size::Word       => write!(f, "    ldr{}h {dest}, ", if *signed { "s" } else { "" }),
-                    Size::DoubleWord => write!(f, "    ldr {dest}, "), // TODO: If we need to load 32 bit signed to 64 bit signed use ldrsw
-                    Size::QuadWord   => write!(f, "    ldr {dest}, "),
-                }?;
+    #[test]
+    fn saves_odd_callee_saved_register_alone() {
+        use RegisterNumber::*;
+        let lines = saving(&[R19, R20, R21]);
+
+        // The last register still takes a whole 16 bytes to keep sp aligned
+        assert!(has_line(&lines, "    sub sp, sp, #32"));
+        assert!(has_line(&lines, "    stp x19, x20, [sp]"));
+        assert!(has_line(&lines, "    str x21, [sp, #16]"));
+        assert!(has_line(&lines, "    ldp x19, x20, [sp]"));
+        assert!(has_line(&lines, "    ldr x21, [sp, #16]"));
+        assert!(!has_line(&lines, "    stp x21"));
+    }
 
-                if *offset != 0 {
-                    write!(f, "[{addr}, #{offset}]")
-                } else {
-                    write!(f, "[{addr}]")
-                }
-            }
-            Instruction::Ldp { dest_1, dest_2, addr, offset } => {
-                write!(f, "    ldp {dest_1}, {dest_2}, ")?;
+    #[test]
+    fn saves_callee_saved_float_registers_in_pairs() {
+        use RegisterNumber::*;
+        let lines = saving(&[V8, V9, V10, V11, V12, V13, V14, V15]);
+
+        assert!(has_line(&lines, "    sub sp, sp, #64"));
+        assert!(has_line(&lines, "    stp d8, d9, [sp]"));
+        assert!(has_line(&lines, "    stp d14, d15, [sp, #48]"));
+        assert!(has_line(&lines, "    ldp d8, d9, [sp]"));
+        assert!(has_line(&lines, "    ldp d14, d15, [sp, #48]"));
+        assert!(has_line(&lines, "    add sp, sp, #64"));
+    }
 
-                if *offset != 0 {
-                    write!(f, "[{addr}, #{offset}]")
-                } else {
-                    write!(f, "[{addr}]")
-                }
-            }
-            Instruction::Str { src, addr, offset } => {
-                match src.size() {
-                    Size::Byte       => write!(f, "    strb {src}, "),
-                    Size::Word       => write!(f, "    strh {src}, "),
-                    Size::DoubleWord => write!(f, "    str {src}, "),
-                    Size::QuadWord   => write!(f, "    str {src}, "),
-                }?;
+    #[test]
+    fn saves_integer_and_float_registers_in_separate_pairs() {
+        use RegisterNumber::*;
+        let lines = saving(&[R19, R20, R21, V8, V9, V10]);
+
+        // x21 and d8 can't share an stp, so both odd registers are saved alone
+        assert!(has_line(&lines, "    sub sp, sp, #64"));
+        assert!(has_line(&lines, "    stp x19, x20, [sp]"));
+        assert!(has_line(&lines, "    str x21, [sp, #16]"));
+        assert!(has_line(&lines, "    stp d8, d9, [sp, #32]"));
+        assert!(has_line(&lines, "    str d10, [sp, #48]"));
+        assert!(has_line(&lines, "    ldr x21, [sp, #16]"));
+        assert!(has_line(&lines, "    ldr d10, [sp, #48]"));
+    }
 
-                if *offset != 0 {
-                    write!(f, "[{addr}, #{offset}]")
-                } else {
-                    write!(f, "[{addr}]")
-                }
-            }
-            Instruction::Stp { src_1, src_2, addr, offset } => {
-                write!(f, "    stp {src_1}, {src_2}, ")?;
+    #[test]
+    fn saves_nothing_without_callee_saved_registers() {
+        let lines = saving(&[]);
 
-                if *offset != 0 {
-                    write!(f, "[{addr}, #{offset}]")
-                } else {
-                    write!(f, "[{addr}]")
-                }
-            }
+        assert!(!has_line(&lines, "    sub sp"));
+        assert!(!has_line(&lines, "    stp"));
+        assert!(!has_line(&lines, "    str"));
+    }
 
-            Instruction::DataLabel { id }               => write!(f, "data_{id}:"),
-            Instruction::LocalDataLabel { id }               => write!(f, "local_data_{id}:"),
-            Instruction::AscizData { value }           => write!(f, "    .asciz {value:?}")
+    #[test]
+    fn values_live_across_calls_avoid_caller_saved_registers() {
+        // One more value than there are callee saved registers, so some have to be spilled
+        let mut module = Module::new();
+        let callee = module.declare_func(
+            "callee".to_string(),
+            Signature::new(Vec::new(), Some((Size::QuadWord, true))),
+        );
+
+        let mut func = Function::new("live_across_call".to_string());
+        let values: Vec<_> = (0..11)
+            .map(|value| func.add_inst_set(Value::I64(value)))
+            .collect();
+
+        let mut sum = func.add_inst_call(&callee, Vec::new()).unwrap();
+        for value in values {
+            sum = func.add_inst_add(sum, value);
+        }
+        func.add_inst_return(Some(sum));
+
+        let target = Arm64::new(ObjectFormat::MachO);
+        let reg_map = RegisterAllocator::new().allocate(&mut func, &target);
+        let ir = func.instructions();
+
+        let call = ir
+            .iter()
+            .position(|inst| matches!(inst, ir::Instruction::Call { .. }))
+            .unwrap();
+        // The result of the call is only written once the callee is done with them
+        let live: Vec<_> = Liveness::analyze(ir)
+            .live_after(call)
+            .iter()
+            .filter(|tmp| ir[call].def() != Some(**tmp))
+            .copied()
+            .collect();
+        assert!(!live.is_empty());
+        for tmp in &live {
+            assert!(callee_saved_registers().contains(&RegisterNumber::from_u8(reg_map[tmp])));
         }
+
+        let stores = ir
+            .iter()
+            .filter(|inst| matches!(inst, ir::Instruction::Store { .. }))
+            .count();
+        assert!(stores >= 11 - live.len());
     }
-}
 
-#[derive(Clone, Copy, PartialEq, Eq)]
-pub(crate) struct Register {
-    number: RegisterNumber,
-    size: Size,
-}
+    #[test]
+    fn macho_packs_stack_args_by_size() {
+        let layout = CallLayout::new(&mixed_args(), None, ObjectFormat::MachO);
+        let locations = locations_of(&layout.args);
+
+        assert_eq!(locations[..4], ["x0", "d0", "x1", "d1"]);
+        assert_eq!(locations[14..16], ["x7", "d7"]);
+        assert_eq!(
+            locations[16..],
+            ["[sp, 0]", "[sp, 4]", "[sp, 8]", "[sp, 16]", "[sp, 24]", "[sp, 32]"]
+        );
+        assert_eq!((layout.args.gr, layout.args.vr), (8, 8));
+        assert_eq!(layout.args.stack_size, 40);
+        assert_eq!(layout.size, 48);
+    }
 
-impl Register {
-    pub(crate) fn new(number: RegisterNumber, size: Size) -> Self {
-        Self { number, size }
+    #[test]
+    fn elf_gives_stack_args_8_bytes() {
+        let layout = CallLayout::new(&mixed_args(), None, ObjectFormat::Elf);
+        let locations = locations_of(&layout.args);
+
+        assert_eq!(locations[..4], ["x0", "d0", "x1", "d1"]);
+        assert_eq!(locations[14..16], ["x7", "d7"]);
+        assert_eq!(
+            locations[16..],
+            ["[sp, 0]", "[sp, 8]", "[sp, 16]", "[sp, 24]", "[sp, 32]", "[sp, 40]"]
+        );
+        assert_eq!(layout.args.stack_size, 48);
+        assert_eq!(layout.size, 48);
     }
 
-    pub(crate) fn number(self) -> RegisterNumber {
-        self.number
+    #[test]
+    fn stack_args_fill_once_one_class_runs_out() {
+        // Floats keep going in v registers after the x registers are used up
+        let mut sizes = vec![Size::DoubleWord; 9];
+        sizes.extend([Size::F32, Size::Word]);
+        let layout = CallLayout::new(&scalars(&sizes), None, ObjectFormat::MachO);
+        let locations = locations_of(&layout.args);
+
+        assert_eq!(locations[7..], ["w7", "[sp, 0]", "s0", "[sp, 4]"]);
+        assert_eq!(layout.args.stack_size, 6);
+        assert_eq!(layout.size, 16);
+
+        let layout = CallLayout::new(&scalars(&sizes), None, ObjectFormat::Elf);
+        assert_eq!(
+            locations_of(&layout.args)[7..],
+            ["w7", "[sp, 0]", "s0", "[sp, 8]"]
+        );
+        assert_eq!(layout.args.stack_size, 16);
     }
 
-    fn size(&self) -> Size {
-        self.size
+    #[test]
+    fn variadic_args() {
+        let types = scalars(&[Size::QuadWord, Size::DoubleWord, Size::F64, Size::Byte]);
+
+        // Apple puts every variadic arg on the stack, 8 bytes each
+        let layout = CallLayout::new(&types, Some(1), ObjectFormat::MachO);
+        assert_eq!(
+            locations_of(&layout.args),
+            ["x0", "[sp, 0]", "[sp, 8]", "[sp, 16]"]
+        );
+        assert_eq!(layout.size, 32);
+
+        let layout = CallLayout::new(&types, Some(1), ObjectFormat::Elf);
+        assert_eq!(locations_of(&layout.args), ["x0", "w1", "d0", "w2"]);
+        assert_eq!(layout.size, 0);
     }
 
-    fn sp() -> Self {
-        Self::new(RegisterNumber::SP, Size::QuadWord)
+    #[test]
+    fn elf_va_start_counts_the_named_args_in_the_offs() {
+        let named = [Size::DoubleWord, Size::DoubleWord, Size::F64];
+        let (lines, va_list) = va_arg_of(&named, Size::QuadWord, ObjectFormat::Elf);
+
+        // __gr_top and __vr_top point past the saved x0-x7 and v0-v7
+        assert!(has_lines(
+            &lines,
+            &["    stp x0, x1, [sp, #48]", "    stp x2, x3, [sp, #64]"]
+        ));
+        assert!(has_line(&lines, "    str d7, [sp, #224]"));
+        assert!(has_lines(
+            &lines,
+            &[
+                "    add x16, sp, #112",
+                &format!("    str x16, [{va_list}, #8]"),
+                "    add x16, sp, #240",
+                &format!("    str x16, [{va_list}, #16]"),
+            ]
+        ));
+
+        // 6 x registers are left to read, -48 for __gr_offs
+        assert!(has_lines(
+            &lines,
+            &[
+                "    mov w16, #65488",
+                "    movk w16, #65535, lsl #16",
+                &format!("    str w16, [{va_list}, #24]"),
+            ]
+        ));
+        // 7 v registers of 16 bytes are left, -112 for __vr_offs
+        assert!(has_lines(
+            &lines,
+            &[
+                "    mov w16, #65424",
+                "    movk w16, #65535, lsl #16",
+                &format!("    str w16, [{va_list}, #28]"),
+            ]
+        ));
     }
 
-    fn x29() -> Self {
-        Self::new(RegisterNumber::R29, Size::QuadWord)
+    #[test]
+    fn elf_va_arg_switches_to_the_stack_once_the_offs_reach_0() {
+        let (lines, va_list) = va_arg_of(&[Size::DoubleWord], Size::QuadWord, ObjectFormat::Elf);
+
+        // A negative __gr_offs still points into the save area below __gr_top
+        let tbz = lines
+            .iter()
+            .position(|line| line.starts_with("    tbz w17, #31, "))
+            .unwrap();
+        assert_eq!(lines[tbz - 1], format!("    ldrsw x17, [{va_list}, #24]"));
+        assert_eq!(lines[tbz + 1], "    add w16, w17, #8");
+        assert_eq!(lines[tbz + 3], format!("    ldr x16, [{va_list}, #8]"));
+
+        // Past that the arg is read from __stack, which moves on by 8
+        let on_stack = format!("{}:", &lines[tbz]["    tbz w17, #31, ".len()..]);
+        assert!(has_lines(
+            &lines,
+            &[
+                &on_stack,
+                &format!("    ldr x16, [{va_list}]"),
+                "    add x17, x16, #8",
+                &format!("    str x17, [{va_list}]"),
+            ]
+        ));
+
+        // Floats go through __vr_offs and __vr_top, 16 bytes at a time
+        let (lines, va_list) = va_arg_of(&[Size::DoubleWord], Size::F64, ObjectFormat::Elf);
+        assert!(has_lines(
+            &lines,
+            &[
+                &format!("    ldrsw x17, [{va_list}, #28]"),
+                "    tbz w17, #31, ",
+                "    add w16, w17, #16",
+                &format!("    str w16, [{va_list}, #28]"),
+                &format!("    ldr x16, [{va_list}, #16]"),
+            ]
+        ));
     }
 
-    fn x30() -> Self {
-        Self::new(RegisterNumber::R30, Size::QuadWord)
+    #[test]
+    fn apple_va_list_is_a_pointer_moved_on_by_8() {
+        let named = [Size::DoubleWord, Size::DoubleWord, Size::F64];
+        for size in [Size::QuadWord, Size::F64] {
+            let (lines, va_list) = va_arg_of(&named, size, ObjectFormat::MachO);
+
+            // Variadic args start right above the frame
+            assert!(has_line(&lines, "    sub sp, sp, #48"));
+            assert!(has_lines(
+                &lines,
+                &["    add x16, sp, #48", &format!("    str x16, [{va_list}]")]
+            ));
+            assert!(!has_line(&lines, &format!("    str x16, [{va_list}, #8]")));
+
+            assert!(has_lines(
+                &lines,
+                &[
+                    &format!("    ldr x16, [{va_list}]"),
+                    "    add x17, x16, #8",
+                    &format!("    str x17, [{va_list}]"),
+                ]
+            ));
+            assert!(!has_line(&lines, "    ldrsw"));
+            assert!(!has_line(&lines, "    tbz"));
+        }
     }
 
-    pub(crate) fn r0(size: Size) -> Self {
-        Self::new(RegisterNumber::R0, size)
+    #[test]
+    fn va_end_emits_nothing() {
+        let mut func = Function::new("va".to_string());
+        func.make_variadic();
+        let va_list = func.add_va_list();
+        let addr = func.add_inst_slot_addr(va_list);
+        func.add_inst_va_start(addr);
+        let with_va_end = {
+            let mut func = func.clone();
+            func.add_inst_va_end(addr);
+            func.add_inst_return(None);
+            generate(func)
+        };
+        func.add_inst_return(None);
+
+        assert_eq!(with_va_end, generate(func));
     }
 
-    fn r1(size: Size) -> Self {
-        Self::new(RegisterNumber::R1, size)
+    #[test]
+    fn hfas_of_one_to_four_members() {
+        for (size, prefix) in [(Size::F32, "s"), (Size::F64, "d")] {
+            for count in 1..=4 {
+                let aggregate = Aggregate::new(vec![size; count]);
+                let class = AggregateClass::of(&aggregate);
+                assert!(matches!(
+                    class,
+                    AggregateClass::Hfa { size: member, count: members }
+                        if member == size && usize::from(members) == count
+                ));
+
+                let expected: Vec<_> = (0..count)
+                    .map(|index| format!("{prefix}{index}@{}", index as u16 * size.in_bytes()))
+                    .collect();
+                let regs = return_registers(&aggregate).unwrap();
+                assert_eq!(registers_of(&regs), expected);
+            }
+        }
     }
 
-    fn r2(size: Size) -> Self {
-        Self::new(RegisterNumber::R2, size)
+    #[test]
+    fn hfas_need_one_float_size() {
+        // Mixed float sizes or a fifth member make it a general aggregate, or an indirect one
+        let mixed = Aggregate::new(vec![Size::F32, Size::F64]);
+        assert!(matches!(
+            AggregateClass::of(&mixed),
+            AggregateClass::General { count: 2 }
+        ));
+
+        let five = Aggregate::new(vec![Size::F32; 5]);
+        assert!(matches!(
+            AggregateClass::of(&five),
+            AggregateClass::Indirect
+        ));
     }
 
-    fn r3(size: Size) -> Self {
-        Self::new(RegisterNumber::R3, size)
+    #[test]
+    fn general_aggregates_up_to_16_bytes() {
+        let small = Aggregate::new(vec![Size::DoubleWord, Size::F32]);
+        assert!(matches!(
+            AggregateClass::of(&small),
+            AggregateClass::General { count: 1 }
+        ));
+        assert_eq!(registers_of(&return_registers(&small).unwrap()), ["x0@0"]);
+
+        let pair = Aggregate::new(vec![Size::QuadWord, Size::Byte]);
+        assert!(matches!(
+            AggregateClass::of(&pair),
+            AggregateClass::General { count: 2 }
+        ));
+        assert_eq!(
+            registers_of(&return_registers(&pair).unwrap()),
+            ["x0@0", "x1@8"]
+        );
     }
 
-    fn r4(size: Size) -> Self {
-        Self::new(RegisterNumber::R4, size)
+    #[test]
+    fn aggregates_over_16_bytes_go_by_reference() {
+        let big = Aggregate::new(vec![Size::QuadWord; 3]);
+        assert!(matches!(AggregateClass::of(&big), AggregateClass::Indirect));
+        assert!(return_registers(&big).is_none());
+
+        let types = vec![
+            ir::Type::Aggregate(big.clone()),
+            ir::Type::Scalar(Size::QuadWord, true),
+        ];
+        let layout = CallLayout::new(&types, None, ObjectFormat::Elf);
+        assert_eq!(locations_of(&layout.args), ["&x0", "x1"]);
+        // The copy the address points to goes after the stack args
+        assert!(matches!(layout.copies[..], [Some(0), None]));
+        assert_eq!(layout.size, 32);
     }
 
-    fn r5(size: Size) -> Self {
-        Self::new(RegisterNumber::R5, size)
+    #[test]
+    fn aligned_general_aggregates_start_at_an_even_x_register() {
+        let pair = Aggregate::with_layout(16, 16, vec![(0, Size::QuadWord), (8, Size::QuadWord)]);
+        let mut types = scalars(&[Size::QuadWord]);
+        types.extend([
+            ir::Type::Aggregate(pair),
+            ir::Type::Scalar(Size::QuadWord, true),
+        ]);
+
+        // x1 is skipped, and stays unused
+        let layout = CallLayout::new(&types, None, ObjectFormat::Elf);
+        assert_eq!(locations_of(&layout.args), ["x0", "x2@0 x3@8", "x4"]);
+        assert_eq!(layout.args.gr, 5);
     }
 
-    fn r6(size: Size) -> Self {
-        Self::new(RegisterNumber::R6, size)
+    #[test]
+    fn aligned_hfas_leave_the_x_registers_alone() {
+        let hfa = Aggregate::with_layout(16, 16, vec![(0, Size::F64), (8, Size::F64)]);
+        let mut types = scalars(&[Size::QuadWord]);
+        types.extend([
+            ir::Type::Aggregate(hfa),
+            ir::Type::Scalar(Size::QuadWord, true),
+        ]);
+
+        let layout = CallLayout::new(&types, None, ObjectFormat::Elf);
+        assert_eq!(locations_of(&layout.args), ["x0", "d0@0 d1@8", "x1"]);
+        assert_eq!((layout.args.gr, layout.args.vr), (2, 2));
     }
 
-    fn r7(size: Size) -> Self {
-        Self::new(RegisterNumber::R7, size)
+    #[test]
+    fn aligned_indirect_aggregates_take_the_next_x_register() {
+        let big = Aggregate::with_layout(
+            32,
+            16,
+            vec![
+                (0, Size::QuadWord),
+                (8, Size::QuadWord),
+                (16, Size::QuadWord),
+                (24, Size::QuadWord),
+            ],
+        );
+        let hfa = Aggregate::with_layout(16, 16, vec![(0, Size::F64), (8, Size::F64)]);
+        let mut types = scalars(&[Size::QuadWord]);
+        types.extend([
+            ir::Type::Aggregate(big),
+            ir::Type::Aggregate(hfa),
+            ir::Type::Scalar(Size::QuadWord, true),
+        ]);
+
+        // Only the address is passed, so the alignment of the aggregate does not matter
+        let layout = CallLayout::new(&types, None, ObjectFormat::Elf);
+        assert_eq!(locations_of(&layout.args), ["x0", "&x1", "d0@0 d1@8", "x2"]);
+        assert_eq!(layout.args.gr, 3);
     }
 
-    fn r9(size: Size) -> Self {
-        Self::new(RegisterNumber::R9, size)
+    #[test]
+    fn indirect_result_goes_through_x8() {
+        let big = Aggregate::new(vec![Size::QuadWord; 3]);
+
+        // The callee keeps x8 around until it copies the result there
+        let mut module = Module::new();
+        let mut func = Function::new("make".to_string());
+        let object = func.add_stack_object(24, 8);
+        let addr = func.add_inst_slot_addr(object);
+        func.add_inst_return_aggregate(addr, big.clone());
+        module.add_func(func);
+
+        let asm = module.generate_asm_for(&Arm64::new(ObjectFormat::Elf));
+        assert!(has_line(asm.lines(), "    str x8, [sp, "));
+        assert!(has_line(asm.lines(), "    ldr x17, [sp, "));
+
+        // The caller points x8 at the stack object the result goes in
+        let mut module = Module::new();
+        let make = module.declare_func(
+            "make".to_string(),
+            Signature::with_types(Vec::new(), Some(ir::Type::Aggregate(big))),
+        );
+
+        let mut func = Function::new("call".to_string());
+        let addr = func.add_inst_call(&make, Vec::new()).unwrap();
+        let value = func.add_inst_load_ptr(addr, Size::QuadWord, true, 0);
+        func.add_inst_return(Some(value));
+        module.add_func(func);
+
+        let asm = module.generate_asm_for(&Arm64::new(ObjectFormat::Elf));
+        let lines = asm.lines();
+        let x8 = lines
+            .iter()
+            .position(|line| line.starts_with("    add x8, sp, "))
+            .unwrap();
+        assert!(lines[x8..].iter().any(|line| line == "    bl make"));
     }
-}
 
-impl fmt::Display for Register {
-    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
-        if self.number == RegisterNumber::SP {
-            return write!(f, "sp");
-        }
+    #[test]
+    fn aggregates_are_never_split_between_registers_and_the_stack() {
+        let pair = Aggregate::new(vec![Size::QuadWord; 2]);
+        let hfa = Aggregate::new(vec![Size::F64; 3]);
+
+        let mut types = scalars(&[Size::QuadWord; 7]);
+        types.extend(scalars(&[Size::F64; 6]));
+        types.extend([
+            ir::Type::Aggregate(pair),
+            ir::Type::Scalar(Size::QuadWord, true),
+            ir::Type::Aggregate(hfa),
+            ir::Type::Scalar(Size::F64, true),
+        ]);
+
+        let layout = CallLayout::new(&types, None, ObjectFormat::Elf);
+        let locations = locations_of(&layout.args);
+
+        // Only x7 is left for the pair, so it and every later x register arg go on the stack,
+        // the same goes for the HFA and the v registers
+        assert_eq!(locations[6], "x6");
+        assert_eq!(locations[12], "d5");
+        assert_eq!(
+            locations[13..],
+            ["[sp, 0]", "[sp, 16]", "[sp, 24]", "[sp, 48]"]
+        );
+        assert_eq!((layout.args.gr, layout.args.vr), (8, 8));
+        assert_eq!(layout.args.stack_size, 56);
+    }
 
-        let reg_num = self.number as u8;
-        match self.size {
-            Size::Byte | Size::Word | Size::DoubleWord => write!(f, "w{reg_num}"),
-            Size::QuadWord => write!(f, "x{reg_num}"),
+    /// Instructions `emit` adds, with a stack guard of `stack_guard_size` bytes
+    fn emitted(stack_guard_size: u32, emit: impl FnOnce(&mut Asm)) -> Vec<String> {
+        let mut asm = Asm::new(ObjectFormat::MachO, stack_guard_size);
+        emit(&mut asm);
+        asm.instructions
+            .iter()
+            .map(|inst| inst.to_string())
+            .collect()
+    }
+
+    /// Frame with `slots_size` bytes of slots below `saved_pairs` pairs of callee saved registers
+    fn frame(slots_size: u32, saved_pairs: usize) -> Frame {
+        Frame {
+            outgoing_args_size: 0,
+            slots_size,
+            va_save_size: 0,
+            return_area_size: 0,
+            returns_indirect: false,
+            args: arg_locations(&[], None, ObjectFormat::Elf),
+            saved_regs: vec![Register::new(RegisterNumber::R19, Size::QuadWord); saved_pairs * 2],
+            saves_fp_lr: true,
         }
     }
-}
 
-#[rustfmt::skip]
-#[allow(unused)]
-#[derive(Clone, Copy, PartialEq, Eq)]
-pub(crate) enum RegisterNumber {
-    R0,  R1,  R2,  R3,  R4,  R5,  R6,  R7,
-    R8,  R9,  R10, R11, R12, R13, R14, R15,
-    R16, R17, R18, R19, R20, R21, R22, R23,
-    R24, R25, R26, R27, R28, R29, R30, SP,
-}
+    #[test]
+    fn offsets_split_over_the_shifted_immediate() {
+        let x0 = Register::r0(Size::QuadWord);
+        let add = |offset| emitted(4096, |asm| asm.add_offset(x0, Register::sp(), offset));
+
+        assert_eq!(add(4095), ["    add x0, sp, #4095"]);
+        assert_eq!(add(4096), ["    add x0, sp, #1, lsl #12"]);
+        assert_eq!(
+            add(4097),
+            ["    add x0, sp, #1, lsl #12", "    add x0, x0, #1"]
+        );
+        assert_eq!(
+            add(0xffffff),
+            ["    add x0, sp, #4095, lsl #12", "    add x0, x0, #4095"]
+        );
+    }
 
-#[rustfmt::skip]
-pub(crate) fn usable_registers() -> Vec<RegisterNumber> {
-    use RegisterNumber::*;
-    vec![
-        R8,  R9,  R10, R11, R12, R13, 
-        R14, R15, R19, R20, R21, R22, 
-        R23, R24, R25, R26, R27, R28
-    ]
-}
+    #[test]
+    fn offsets_from_16_mib_are_materialized() {
+        let x0 = Register::r0(Size::QuadWord);
+
+        let lines = emitted(4096, |asm| asm.add_offset(x0, Register::sp(), 1 << 24));
+        assert_eq!(
+            lines,
+            [
+                "    mov x0, #0",
+                "    movk x0, #256, lsl #16",
+                "    add x0, sp, x0"
+            ]
+        );
+
+        // sp can not hold the offset, so it goes in x16
+        let lines = emitted(4096, |asm| {
+            asm.sub_offset(Register::sp(), Register::sp(), 1 << 24)
+        });
+        assert_eq!(
+            lines,
+            [
+                "    mov x16, #0",
+                "    movk x16, #256, lsl #16",
+                "    sub sp, sp, x16"
+            ]
+        );
+    }
 
-pub(crate) fn arg_register(arg_num: u8, size: Size) -> Option<Register> {
-    match arg_num {
-        0 => Some(Register::r0(size)),
-        1 => Some(Register::r1(size)),
-        2 => Some(Register::r2(size)),
-        3 => Some(Register::r3(size)),
-        4 => Some(Register::r4(size)),
-        5 => Some(Register::r5(size)),
-        6 => Some(Register::r6(size)),
-        7 => Some(Register::r7(size)),
-        _ => None,
+    #[test]
+    fn frame_offsets_past_the_scaled_immediate() {
+        let x0 = Register::r0(Size::QuadWord);
+        let x16 = Register::r16(Size::QuadWord);
+        let mut asm = Asm::new(ObjectFormat::MachO, 4096);
+
+        assert!(matches!(
+            asm.frame_offset(4095 * 8, Size::QuadWord, [x0, x0]),
+            Offset::Scaled(32760)
+        ));
+        assert!(matches!(
+            asm.frame_offset(4095, Size::Byte, [x0, x0]),
+            Offset::Scaled(4095)
+        ));
+        assert!(asm.instructions.is_empty());
+
+        let Offset::Index(index) = asm.frame_offset(4096, Size::Byte, [x0, x0]) else {
+            panic!("4096 does not fit the immediate of a byte access");
+        };
+        assert_eq!(index.to_string(), "x16");
+
+        // Offsets that are not a multiple of the size can not be scaled either
+        let Offset::Index(index) = asm.frame_offset(4, Size::QuadWord, [x16, x0]) else {
+            panic!("4 is not a multiple of 8");
+        };
+        assert_eq!(index.to_string(), "x17");
+
+        let lines: Vec<_> = asm
+            .instructions
+            .iter()
+            .map(|inst| inst.to_string())
+            .collect();
+        assert_eq!(lines, ["    mov x16, #4096", "    mov x17, #4"]);
     }
-}
 
-fn get_correct_offset(is_leaf: bool, offset: u16) -> u16 {
-    // NOTE: Write propper tests to check this
-    if !is_leaf {
-        offset // + 16
-    } else {
-        offset
+    #[test]
+    fn stack_within_one_guard_is_allocated_at_once() {
+        assert_eq!(
+            emitted(4096, |asm| asm.allocate_stack(4095)),
+            ["    sub sp, sp, #4095"]
+        );
+        assert_eq!(
+            emitted(4096, |asm| asm.allocate_stack(4096)),
+            ["    sub sp, sp, #1, lsl #12"]
+        );
+        assert!(emitted(4096, |asm| asm.allocate_stack(0)).is_empty());
+    }
+
+    #[test]
+    fn stack_past_one_guard_is_probed() {
+        assert_eq!(
+            emitted(4096, |asm| asm.allocate_stack(4097)),
+            [
+                "    mov x16, #1",
+                "label_0:",
+                "    sub sp, sp, #1, lsl #12",
+                "    str x16, [sp]",
+                "    sub x16, x16, #1",
+                "    cbnz x16, label_0",
+                "    sub sp, sp, #1",
+                "    str x16, [sp]",
+            ]
+        );
+
+        // Whole guards leave nothing to touch after the loop
+        let lines = emitted(4096, |asm| asm.allocate_stack(8192));
+        assert_eq!(lines[0], "    mov x16, #2");
+        assert_eq!(lines.last().unwrap(), "    cbnz x16, label_0");
+
+        let lines = emitted(4096, |asm| asm.allocate_stack(1 << 24));
+        assert_eq!(lines[0], "    mov x16, #4096");
+        assert_eq!(lines[2], "    sub sp, sp, #1, lsl #12");
+        assert_eq!(lines.len(), 6);
+    }
+
+    #[test]
+    fn bigger_guards_probe_less() {
+        assert_eq!(
+            emitted(65536, |asm| asm.allocate_stack(65536)),
+            ["    sub sp, sp, #16, lsl #12"]
+        );
+
+        let lines = emitted(65536, |asm| asm.allocate_stack(1 << 24));
+        assert_eq!(lines[0], "    mov x16, #256");
+        assert_eq!(lines[2], "    sub sp, sp, #16, lsl #12");
+
+        let lines = emitted(4096, |asm| asm.allocate_stack(65536));
+        assert_eq!(lines[0], "    mov x16, #16");
+    }
+
+    #[test]
+    fn frames_past_504_bytes_are_split() {
+        // stp reaches x29, x30 at 504, one more byte and the slots are allocated apart
+        assert_eq!(frame(504, 0).fp_offset(), 504);
+        assert_eq!(frame(504, 0).lower_size(), 0);
+        assert_eq!(frame(505, 0).lower_size(), 505);
+
+        assert_eq!(frame(488, 1).fp_offset(), 504);
+        assert_eq!(frame(488, 1).lower_size(), 0);
+        assert_eq!(frame(489, 1).lower_size(), 489);
     }
 }
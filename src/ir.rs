@@ -1,11 +1,16 @@
-use std::collections::HashMap;
+use std::collections::{HashMap, HashSet};
 
-use crate::{arm64::Asm, util::Iota};
+use crate::{
+    arm64::{Arm64, ObjectFormat},
+    target::{Asm, Target},
+    util::{Iota, RegisterAllocator},
+};
 
 pub struct Module {
     data_iota: Iota,
     funcs: Vec<Function>,
-    data: HashMap<DataAddr, Data>,
+    signatures: HashMap<String, Signature>,
+    data: HashMap<DataAddr, (Section, Data)>,
 }
 
 impl Module {
@@ -13,31 +18,80 @@ impl Module {
     pub fn new() -> Self {
         Self {
             funcs: Vec::new(),
+            signatures: HashMap::new(),
             data: HashMap::new(),
             data_iota: Iota::new(),
         }
     }
 
+    /// Adds `func`, which has to match its signature if it was declared with `declare_func`
     pub fn add_func(&mut self, func: Function) {
+        if let Some(signature) = self.signatures.get(func.name()) {
+            func.check_signature(signature);
+        }
+
         self.funcs.push(func);
     }
 
+    /// Declares a function the functions of this module can call. It can be added later
+    /// with `add_func` or be defined somewhere else, e.g. in libc
+    pub fn declare_func(&mut self, name: String, signature: Signature) -> FuncRef {
+        if let Some(declared) = self.signatures.get(&name) {
+            assert!(
+                *declared == signature,
+                "Function {name} is already declared with a different signature"
+            );
+        }
+
+        if let Some(func) = self.funcs.iter().find(|func| func.name() == name) {
+            func.check_signature(&signature);
+        }
+
+        self.signatures.insert(name.clone(), signature.clone());
+        FuncRef { name, signature }
+    }
+
+    /// Adds mutable data every function of the module can address with `add_inst_global_addr`
     pub fn add_data(&mut self, data: Data) -> DataAddr {
-        let addr = DataAddr::new(self.data_iota.next());
-        self.data.insert(addr, data);
+        self.add_data_in(Section::Mutable, data)
+    }
+
+    pub fn add_data_in(&mut self, section: Section, data: Data) -> DataAddr {
+        section.check(&data);
+
+        for addr in data.pointed_data() {
+            assert!(
+                addr.is_global(),
+                "Module data can not point at data {} local to a function",
+                addr.id()
+            );
+        }
+
+        let addr = DataAddr::global(self.data_iota.next());
+        self.data.insert(addr, (section, data));
         addr
     }
 
+    /// Generates arm64 assembly for Apple platforms
     pub fn generate_asm(&mut self) -> Asm {
-        Asm::from_module(self)
+        self.generate_asm_for(&Arm64::new(ObjectFormat::MachO))
     }
 
-    pub(crate) fn funcs(&self) -> &[Function] {
-        &self.funcs
-    }
+    pub fn generate_asm_for(&self, target: &dyn Target) -> Asm {
+        let mut emitter = target.emitter();
 
-    pub(crate) fn data(&self) -> &HashMap<DataAddr, Data> {
-        &self.data
+        for func in &self.funcs {
+            // Spilling rewrites the function, so work on a copy of it
+            let mut func = func.clone();
+            let registers = RegisterAllocator::new().allocate(&mut func, target);
+            let frame = target.frame_layout(&func, &registers);
+
+            emitter.generate_function(&func, &registers, &frame);
+        }
+
+        emitter.generate_data(&self.data);
+
+        emitter.finish()
     }
 }
 
@@ -45,14 +99,18 @@ impl Module {
 pub struct Function {
     is_public: bool,
     is_leaf: bool,
+    is_variadic: bool,
     name: String,
     tmp_iota: Iota,
     var_iota: Iota,
     data_iota: Iota,
+    lbl_iota: Iota,
+    placed_labels: HashSet<Label>,
     args: Vec<StackSlot>,
+    aggregate_args: HashMap<StackSlot, Aggregate>,
     stack_slots: Vec<StackSlot>,
     instructions: Vec<Instruction>,
-    data: HashMap<DataAddr, Data>,
+    data: HashMap<DataAddr, (Section, Data)>,
 }
 
 impl Function {
@@ -60,20 +118,40 @@ impl Function {
         Self {
             is_public: false,
             is_leaf: true,
+            is_variadic: false,
             name,
             tmp_iota: Iota::new(),
             var_iota: Iota::new(),
             data_iota: Iota::new(),
+            lbl_iota: Iota::new(),
+            placed_labels: HashSet::new(),
             args: Vec::new(),
+            aggregate_args: HashMap::new(),
             stack_slots: Vec::new(),
             instructions: Vec::new(),
             data: HashMap::new(),
         }
     }
 
+    /// Adds read-only data only this function can address, with `add_inst_local_addr`
     pub fn add_data(&mut self, data: Data) -> DataAddr {
-        let addr = DataAddr::new(self.data_iota.next());
-        self.data.insert(addr, data);
+        self.add_data_in(Section::ReadOnly, data)
+    }
+
+    pub fn add_data_in(&mut self, section: Section, data: Data) -> DataAddr {
+        section.check(&data);
+
+        for addr in data.pointed_data() {
+            assert!(
+                addr.is_global() || self.data.contains_key(&addr),
+                "Data {} does not belong to function {}",
+                addr.id(),
+                self.name
+            );
+        }
+
+        let addr = DataAddr::local(self.data_iota.next());
+        self.data.insert(addr, (section, data));
         addr
     }
 
@@ -81,10 +159,20 @@ impl Function {
         self.is_public = true;
     }
 
+    /// Lets the function take any number of args after the ones added with `add_arg`,
+    /// which are read with `add_inst_va_arg`
+    pub fn make_variadic(&mut self) {
+        self.is_variadic = true;
+    }
+
     pub(crate) fn is_public(&self) -> bool {
         self.is_public
     }
 
+    pub(crate) fn is_variadic(&self) -> bool {
+        self.is_variadic
+    }
+
     pub(crate) fn is_leaf(&self) -> bool {
         self.is_leaf
     }
@@ -101,10 +189,62 @@ impl Function {
         &self.args
     }
 
-    pub(crate) fn data(&self) -> &HashMap<DataAddr, Data> {
+    pub(crate) fn data(&self) -> &HashMap<DataAddr, (Section, Data)> {
         &self.data
     }
 
+    /// Types of the args, in order
+    pub(crate) fn arg_types(&self) -> Vec<Type> {
+        self.args
+            .iter()
+            .map(|arg| match self.arg_aggregate(*arg) {
+                Some(aggregate) => Type::Aggregate(aggregate.clone()),
+                None => Type::Scalar(arg.size(), arg.is_signed()),
+            })
+            .collect()
+    }
+
+    /// The aggregate `arg` holds, if it was added with `add_arg_aggregate`
+    pub(crate) fn arg_aggregate(&self, arg: StackSlot) -> Option<&Aggregate> {
+        self.aggregate_args.get(&arg)
+    }
+
+    /// Whether any arg, call or return of this function passes an aggregate by value
+    pub(crate) fn has_aggregates(&self) -> bool {
+        !self.aggregate_args.is_empty()
+            || self.instructions.iter().any(|inst| match inst {
+                Instruction::Return { aggregate, .. } => aggregate.is_some(),
+                Instruction::Call { signature, .. }
+                | Instruction::CallIndirect { signature, .. } => signature.has_aggregates(),
+                _ => false,
+            })
+    }
+
+    /// Panics if the args or the returned values do not match `signature`
+    fn check_signature(&self, signature: &Signature) {
+        assert!(
+            self.arg_types() == signature.params && self.is_variadic == signature.is_variadic,
+            "Args of function {} do not match its signature",
+            self.name
+        );
+
+        for inst in &self.instructions {
+            if let Instruction::Return { src, aggregate } = inst {
+                let matches = match (aggregate, &signature.ret) {
+                    (Some(aggregate), Some(Type::Aggregate(ret))) => aggregate == ret,
+                    (None, Some(Type::Scalar(size, _))) => src.map(|src| src.size()) == Some(*size),
+                    (None, None) => src.is_none(),
+                    _ => false,
+                };
+                assert!(
+                    matches,
+                    "Function {} returns a value that does not match its signature",
+                    self.name
+                );
+            }
+        }
+    }
+
     pub fn add_arg(&mut self, size: Size, signed: bool) -> StackSlot {
         let slot = StackSlot::new(self.var_iota.next(), size, signed);
         // NOTE: We are duplicating data... but whatever
@@ -113,144 +253,936 @@ impl Function {
         slot
     }
 
-    pub fn add_inst_set(&mut self, value: Value) -> Temporary {
-        let result = Temporary::new(self.tmp_iota.next(), value.size(), value.is_signed());
+    /// Adds an arg passed by value, it is copied into a stack object whose address is
+    /// taken with `add_inst_slot_addr`
+    pub fn add_arg_aggregate(&mut self, aggregate: Aggregate) -> StackSlot {
+        // Rounded up so registers can be stored into it whole
+        let slot = self.add_stack_object(
+            u32::from(aggregate.size()).next_multiple_of(8),
+            aggregate.align().max(8),
+        );
+        self.args.push(slot);
+        self.aggregate_args.insert(slot, aggregate);
+        slot
+    }
+
+    /// Reserves `size` bytes on the stack for an array or struct, starting at a multiple of `align`
+    pub fn add_stack_object(&mut self, size: u32, align: u16) -> StackSlot {
+        assert!(
+            align.is_power_of_two() && align <= 16,
+            "Stack objects have to be aligned to a power of 2 up to 16, not {align}"
+        );
+
+        let slot = StackSlot::new_object(self.var_iota.next(), size, align);
+        self.stack_slots.push(slot);
+        slot
+    }
+
+    pub fn add_inst_set(&mut self, value: Value) -> Temporary {
+        let result = Temporary::new(self.tmp_iota.next(), value.size(), value.is_signed());
+
+        let inst = Instruction::Set {
+            dest: result,
+            src: value,
+        };
+        self.instructions.push(inst);
+
+        result
+    }
+
+    pub fn add_inst_return(&mut self, tmp: Option<Temporary>) {
+        let inst = Instruction::Return {
+            src: tmp,
+            aggregate: None,
+        };
+        self.instructions.push(inst);
+    }
+
+    /// Returns a copy of the aggregate at `addr` by value
+    pub fn add_inst_return_aggregate(&mut self, addr: Temporary, aggregate: Aggregate) {
+        assert_eq!(addr.size(), Size::QuadWord, "Pointers are 64 bits");
+
+        let inst = Instruction::Return {
+            src: Some(addr),
+            aggregate: Some(aggregate),
+        };
+        self.instructions.push(inst);
+    }
+
+    pub fn add_inst_load(&mut self, slot: StackSlot) -> Temporary {
+        let result = Temporary::new(self.tmp_iota.next(), slot.size(), slot.is_signed());
+
+        let inst = Instruction::Load {
+            dest: result,
+            src: slot,
+        };
+        self.instructions.push(inst);
+
+        result
+    }
+
+    pub fn add_inst_store(&mut self, tmp: Temporary) -> StackSlot {
+        let slot = StackSlot::new(self.var_iota.next(), tmp.size(), tmp.is_signed());
+        self.stack_slots.push(slot);
+
+        let inst = Instruction::Store {
+            dest: slot,
+            src: tmp,
+        };
+        self.instructions.push(inst);
+
+        slot
+    }
+
+    /// Address of `slot`, for passing it by reference or accessing a stack object
+    pub fn add_inst_slot_addr(&mut self, slot: StackSlot) -> Temporary {
+        let result = Temporary::new(self.tmp_iota.next(), Size::QuadWord, false);
+
+        let inst = Instruction::SlotAddr { dest: result, slot };
+        self.instructions.push(inst);
+
+        result
+    }
+
+    /// Loads `size` bytes from `addr + offset`
+    pub fn add_inst_load_ptr(
+        &mut self,
+        addr: Temporary,
+        size: Size,
+        signed: bool,
+        offset: i32,
+    ) -> Temporary {
+        self.load_ptr(addr, size, signed, PtrOffset::Imm(offset))
+    }
+
+    /// Loads `size` bytes from `addr + index`, with index counted in multiples of `size` if `scaled`
+    pub fn add_inst_load_ptr_indexed(
+        &mut self,
+        addr: Temporary,
+        index: Temporary,
+        scaled: bool,
+        size: Size,
+        signed: bool,
+    ) -> Temporary {
+        Self::check_index(index);
+        self.load_ptr(addr, size, signed, PtrOffset::Index { index, scaled })
+    }
+
+    /// Stores `value` to `addr + offset`
+    pub fn add_inst_store_ptr(&mut self, addr: Temporary, value: Temporary, offset: i32) {
+        self.store_ptr(addr, value, PtrOffset::Imm(offset));
+    }
+
+    /// Stores `value` to `addr + index`, with index counted in multiples of its size if `scaled`
+    pub fn add_inst_store_ptr_indexed(
+        &mut self,
+        addr: Temporary,
+        index: Temporary,
+        scaled: bool,
+        value: Temporary,
+    ) {
+        Self::check_index(index);
+        self.store_ptr(addr, value, PtrOffset::Index { index, scaled });
+    }
+
+    fn load_ptr(
+        &mut self,
+        addr: Temporary,
+        size: Size,
+        signed: bool,
+        offset: PtrOffset,
+    ) -> Temporary {
+        Self::check_ptr(addr);
+
+        let result = Temporary::new(self.tmp_iota.next(), size, signed);
+
+        let inst = Instruction::LoadPtr {
+            dest: result,
+            addr,
+            offset,
+        };
+        self.instructions.push(inst);
+
+        result
+    }
+
+    fn store_ptr(&mut self, addr: Temporary, value: Temporary, offset: PtrOffset) {
+        Self::check_ptr(addr);
+
+        let inst = Instruction::StorePtr {
+            addr,
+            src: value,
+            offset,
+        };
+        self.instructions.push(inst);
+    }
+
+    fn check_ptr(addr: Temporary) {
+        assert_eq!(addr.size(), Size::QuadWord, "Pointers are 64 bits");
+    }
+
+    fn check_index(index: Temporary) {
+        assert!(
+            matches!(index.size(), Size::DoubleWord | Size::QuadWord),
+            "Index has to be 32 or 64 bits"
+        );
+    }
+
+    pub fn add_inst_add(&mut self, src_1: Temporary, src_2: Temporary) -> Temporary {
+        let result = self.binary_result(src_1, src_2, src_1.is_signed() | src_2.is_signed());
+
+        let inst = Instruction::Add {
+            dest: result,
+            src_1,
+            src_2,
+        };
+        self.instructions.push(inst);
+
+        result
+    }
+
+    pub fn add_inst_sub(&mut self, src_1: Temporary, src_2: Temporary) -> Temporary {
+        let result = self.binary_result(src_1, src_2, src_1.is_signed() | src_2.is_signed());
+
+        let inst = Instruction::Sub {
+            dest: result,
+            src_1,
+            src_2,
+        };
+        self.instructions.push(inst);
+
+        result
+    }
+
+    pub fn add_inst_mul(&mut self, src_1: Temporary, src_2: Temporary) -> Temporary {
+        let result = self.binary_result(src_1, src_2, src_1.is_signed() | src_2.is_signed());
+
+        let inst = Instruction::Mul {
+            dest: result,
+            src_1,
+            src_2,
+        };
+        self.instructions.push(inst);
+
+        result
+    }
+
+    pub fn add_inst_sdiv(&mut self, src_1: Temporary, src_2: Temporary) -> Temporary {
+        let result = self.binary_result(src_1, src_2, true);
+
+        let inst = Instruction::Div {
+            dest: result,
+            src_1,
+            src_2,
+        };
+        self.instructions.push(inst);
+
+        result
+    }
+
+    pub fn add_inst_udiv(&mut self, src_1: Temporary, src_2: Temporary) -> Temporary {
+        let result = self.binary_result(src_1, src_2, false);
+
+        let inst = Instruction::Div {
+            dest: result,
+            src_1,
+            src_2,
+        };
+        self.instructions.push(inst);
+
+        result
+    }
+
+    pub fn add_inst_srem(&mut self, src_1: Temporary, src_2: Temporary) -> Temporary {
+        let result = self.binary_result(src_1, src_2, true);
+
+        let inst = Instruction::Rem {
+            dest: result,
+            src_1,
+            src_2,
+        };
+        self.instructions.push(inst);
+
+        result
+    }
+
+    pub fn add_inst_urem(&mut self, src_1: Temporary, src_2: Temporary) -> Temporary {
+        let result = self.binary_result(src_1, src_2, false);
+
+        let inst = Instruction::Rem {
+            dest: result,
+            src_1,
+            src_2,
+        };
+        self.instructions.push(inst);
+
+        result
+    }
+
+    pub fn add_inst_neg(&mut self, src: Temporary) -> Temporary {
+        Self::check_int(src);
+
+        let result = Temporary::new(self.tmp_iota.next(), src.size(), src.is_signed());
+
+        let inst = Instruction::Neg { dest: result, src };
+        self.instructions.push(inst);
+
+        result
+    }
+
+    pub fn add_inst_and(&mut self, src_1: Temporary, src_2: Temporary) -> Temporary {
+        let result = self.binary_result(src_1, src_2, src_1.is_signed() | src_2.is_signed());
+
+        let inst = Instruction::And {
+            dest: result,
+            src_1,
+            src_2,
+        };
+        self.instructions.push(inst);
+
+        result
+    }
+
+    pub fn add_inst_or(&mut self, src_1: Temporary, src_2: Temporary) -> Temporary {
+        let result = self.binary_result(src_1, src_2, src_1.is_signed() | src_2.is_signed());
+
+        let inst = Instruction::Or {
+            dest: result,
+            src_1,
+            src_2,
+        };
+        self.instructions.push(inst);
+
+        result
+    }
+
+    pub fn add_inst_xor(&mut self, src_1: Temporary, src_2: Temporary) -> Temporary {
+        let result = self.binary_result(src_1, src_2, src_1.is_signed() | src_2.is_signed());
+
+        let inst = Instruction::Xor {
+            dest: result,
+            src_1,
+            src_2,
+        };
+        self.instructions.push(inst);
+
+        result
+    }
+
+    pub fn add_inst_not(&mut self, src: Temporary) -> Temporary {
+        Self::check_int(src);
+
+        let result = Temporary::new(self.tmp_iota.next(), src.size(), src.is_signed());
+
+        let inst = Instruction::Not { dest: result, src };
+        self.instructions.push(inst);
+
+        result
+    }
+
+    pub fn add_inst_shl(&mut self, src: Temporary, amount: Temporary) -> Temporary {
+        Self::check_int(src);
+
+        let result = Temporary::new(self.tmp_iota.next(), src.size(), src.is_signed());
+
+        let inst = Instruction::Shl {
+            dest: result,
+            src,
+            amount,
+        };
+        self.instructions.push(inst);
+
+        result
+    }
+
+    /// Shifts right, arithmetic for signed `src` and logical for unsigned `src`
+    pub fn add_inst_shr(&mut self, src: Temporary, amount: Temporary) -> Temporary {
+        if src.is_signed() {
+            self.add_inst_ashr(src, amount)
+        } else {
+            self.add_inst_lshr(src, amount)
+        }
+    }
+
+    pub fn add_inst_lshr(&mut self, src: Temporary, amount: Temporary) -> Temporary {
+        Self::check_int(src);
+
+        let result = Temporary::new(self.tmp_iota.next(), src.size(), src.is_signed());
+
+        let inst = Instruction::LShr {
+            dest: result,
+            src,
+            amount,
+        };
+        self.instructions.push(inst);
+
+        result
+    }
+
+    pub fn add_inst_ashr(&mut self, src: Temporary, amount: Temporary) -> Temporary {
+        Self::check_int(src);
+
+        let result = Temporary::new(self.tmp_iota.next(), src.size(), src.is_signed());
+
+        let inst = Instruction::AShr {
+            dest: result,
+            src,
+            amount,
+        };
+        self.instructions.push(inst);
+
+        result
+    }
+
+    /// Widens `src` to `size`, filling the new bits with its sign bit
+    pub fn add_inst_sext(&mut self, src: Temporary, size: Size) -> Temporary {
+        self.extend(src, size, true)
+    }
+
+    /// Widens `src` to `size`, filling the new bits with zeros
+    pub fn add_inst_zext(&mut self, src: Temporary, size: Size) -> Temporary {
+        self.extend(src, size, false)
+    }
+
+    /// Narrows `src` to `size`, keeping its low bits
+    pub fn add_inst_trunc(&mut self, src: Temporary, size: Size) -> Temporary {
+        Self::check_int(src);
+
+        assert!(
+            size.in_bytes() < src.size().in_bytes(),
+            "Can only truncate to a smaller size"
+        );
+
+        let result = Temporary::new(self.tmp_iota.next(), size, src.is_signed());
+
+        let inst = Instruction::Trunc { dest: result, src };
+        self.instructions.push(inst);
+
+        result
+    }
+
+    fn extend(&mut self, src: Temporary, size: Size, signed: bool) -> Temporary {
+        Self::check_int(src);
+
+        assert!(
+            size.in_bytes() > src.size().in_bytes(),
+            "Can only extend to a bigger size"
+        );
+
+        let result = Temporary::new(self.tmp_iota.next(), size, signed);
+
+        let inst = Instruction::Extend {
+            dest: result,
+            src,
+            signed,
+        };
+        self.instructions.push(inst);
+
+        result
+    }
+
+    /// Creates the result temporary of a binary operation
+    fn binary_result(&mut self, src_1: Temporary, src_2: Temporary, signed: bool) -> Temporary {
+        // NOTE: For now assert their sizes are equal
+        assert_eq!(src_1.size(), src_2.size());
+        Self::check_int(src_1);
+
+        Temporary::new(self.tmp_iota.next(), src_1.size(), signed)
+    }
+
+    pub fn add_inst_fadd(&mut self, src_1: Temporary, src_2: Temporary) -> Temporary {
+        let result = self.float_result(src_1, src_2);
+
+        let inst = Instruction::Add {
+            dest: result,
+            src_1,
+            src_2,
+        };
+        self.instructions.push(inst);
+
+        result
+    }
+
+    pub fn add_inst_fsub(&mut self, src_1: Temporary, src_2: Temporary) -> Temporary {
+        let result = self.float_result(src_1, src_2);
+
+        let inst = Instruction::Sub {
+            dest: result,
+            src_1,
+            src_2,
+        };
+        self.instructions.push(inst);
+
+        result
+    }
+
+    pub fn add_inst_fmul(&mut self, src_1: Temporary, src_2: Temporary) -> Temporary {
+        let result = self.float_result(src_1, src_2);
+
+        let inst = Instruction::Mul {
+            dest: result,
+            src_1,
+            src_2,
+        };
+        self.instructions.push(inst);
+
+        result
+    }
+
+    pub fn add_inst_fdiv(&mut self, src_1: Temporary, src_2: Temporary) -> Temporary {
+        let result = self.float_result(src_1, src_2);
+
+        let inst = Instruction::Div {
+            dest: result,
+            src_1,
+            src_2,
+        };
+        self.instructions.push(inst);
+
+        result
+    }
+
+    pub fn add_inst_fneg(&mut self, src: Temporary) -> Temporary {
+        Self::check_float(src);
+
+        let result = Temporary::new(self.tmp_iota.next(), src.size(), true);
+
+        let inst = Instruction::Neg { dest: result, src };
+        self.instructions.push(inst);
+
+        result
+    }
+
+    pub fn add_inst_fsqrt(&mut self, src: Temporary) -> Temporary {
+        Self::check_float(src);
+
+        let result = Temporary::new(self.tmp_iota.next(), src.size(), true);
+
+        let inst = Instruction::Sqrt { dest: result, src };
+        self.instructions.push(inst);
+
+        result
+    }
+
+    /// Converts the integer `src` to the float `size`, treating it as signed if `src` is signed
+    pub fn add_inst_itof(&mut self, src: Temporary, size: Size) -> Temporary {
+        Self::check_int(src);
+        assert!(size.is_float(), "Can only convert to F32 or F64");
+
+        self.convert(src, size, true)
+    }
+
+    /// Converts the float `src` to the integer `size`, rounding towards zero
+    pub fn add_inst_ftoi(&mut self, src: Temporary, size: Size, signed: bool) -> Temporary {
+        Self::check_float(src);
+        assert!(!size.is_float(), "Can only convert to an integer size");
+
+        self.convert(src, size, signed)
+    }
+
+    /// Converts between F32 and F64
+    pub fn add_inst_fcvt(&mut self, src: Temporary, size: Size) -> Temporary {
+        Self::check_float(src);
+        assert!(
+            size.is_float() && size != src.size(),
+            "Can only convert to the other float size"
+        );
+
+        self.convert(src, size, true)
+    }
+
+    fn convert(&mut self, src: Temporary, size: Size, signed: bool) -> Temporary {
+        let result = Temporary::new(self.tmp_iota.next(), size, signed);
+
+        let inst = Instruction::Convert { dest: result, src };
+        self.instructions.push(inst);
+
+        result
+    }
+
+    /// Creates the result temporary of a float binary operation
+    fn float_result(&mut self, src_1: Temporary, src_2: Temporary) -> Temporary {
+        assert_eq!(src_1.size(), src_2.size());
+        Self::check_float(src_1);
+
+        Temporary::new(self.tmp_iota.next(), src_1.size(), true)
+    }
+
+    fn check_int(src: Temporary) {
+        assert!(
+            !src.size().is_float(),
+            "Use the float instructions for F32 and F64"
+        );
+    }
+
+    fn check_float(src: Temporary) {
+        assert!(src.size().is_float(), "Expected an F32 or F64 temporary");
+    }
+
+    /// Creates a new label, which can be branched to before it is placed with `add_block`
+    pub fn add_label(&mut self) -> Label {
+        Label::new(self.lbl_iota.next())
+    }
+
+    /// Starts a new basic block at `label`
+    pub fn add_block(&mut self, label: Label) {
+        assert!(
+            self.placed_labels.insert(label),
+            "Label {} is already placed",
+            label.id()
+        );
+
+        let inst = Instruction::Label { label };
+        self.instructions.push(inst);
+    }
+
+    pub fn add_inst_jump(&mut self, label: Label) {
+        let inst = Instruction::Jump { label };
+        self.instructions.push(inst);
+    }
+
+    /// Branches to `then_label` if `cond` is non zero, otherwise to `else_label`
+    pub fn add_inst_branch_if(&mut self, cond: Temporary, then_label: Label, else_label: Label) {
+        let inst = Instruction::Branch {
+            cond,
+            then_label,
+            else_label,
+        };
+        self.instructions.push(inst);
+    }
+
+    /// Compares `src_1` with `src_2`, the result is 1 if `cond` holds and 0 otherwise
+    pub fn add_inst_cmp(
+        &mut self,
+        cond: Condition,
+        src_1: Temporary,
+        src_2: Temporary,
+    ) -> Temporary {
+        // NOTE: For now assert their sizes are equal
+        assert_eq!(src_1.size(), src_2.size());
+        if src_1.size().is_float() {
+            assert!(
+                cond.is_float() || matches!(cond, Condition::Eq | Condition::Ne),
+                "Floats are compared with Eq, Ne or the float conditions"
+            );
+        } else {
+            assert!(!cond.is_float(), "Float conditions only compare floats");
+        }
+
+        let result = Temporary::new(self.tmp_iota.next(), Size::Byte, false);
+
+        let inst = Instruction::Cmp {
+            dest: result,
+            src_1,
+            src_2,
+            cond,
+        };
+        self.instructions.push(inst);
+
+        result
+    }
+
+    pub fn add_inst_eq(&mut self, src_1: Temporary, src_2: Temporary) -> Temporary {
+        self.add_inst_cmp(Condition::Eq, src_1, src_2)
+    }
+
+    pub fn add_inst_ne(&mut self, src_1: Temporary, src_2: Temporary) -> Temporary {
+        self.add_inst_cmp(Condition::Ne, src_1, src_2)
+    }
+
+    pub fn add_inst_slt(&mut self, src_1: Temporary, src_2: Temporary) -> Temporary {
+        self.add_inst_cmp(Condition::Slt, src_1, src_2)
+    }
+
+    pub fn add_inst_sle(&mut self, src_1: Temporary, src_2: Temporary) -> Temporary {
+        self.add_inst_cmp(Condition::Sle, src_1, src_2)
+    }
+
+    pub fn add_inst_sgt(&mut self, src_1: Temporary, src_2: Temporary) -> Temporary {
+        self.add_inst_cmp(Condition::Sgt, src_1, src_2)
+    }
+
+    pub fn add_inst_sge(&mut self, src_1: Temporary, src_2: Temporary) -> Temporary {
+        self.add_inst_cmp(Condition::Sge, src_1, src_2)
+    }
+
+    pub fn add_inst_ult(&mut self, src_1: Temporary, src_2: Temporary) -> Temporary {
+        self.add_inst_cmp(Condition::Ult, src_1, src_2)
+    }
+
+    pub fn add_inst_ule(&mut self, src_1: Temporary, src_2: Temporary) -> Temporary {
+        self.add_inst_cmp(Condition::Ule, src_1, src_2)
+    }
+
+    pub fn add_inst_ugt(&mut self, src_1: Temporary, src_2: Temporary) -> Temporary {
+        self.add_inst_cmp(Condition::Ugt, src_1, src_2)
+    }
+
+    pub fn add_inst_uge(&mut self, src_1: Temporary, src_2: Temporary) -> Temporary {
+        self.add_inst_cmp(Condition::Uge, src_1, src_2)
+    }
+
+    pub fn add_inst_flt(&mut self, src_1: Temporary, src_2: Temporary) -> Temporary {
+        self.add_inst_cmp(Condition::Flt, src_1, src_2)
+    }
+
+    pub fn add_inst_fle(&mut self, src_1: Temporary, src_2: Temporary) -> Temporary {
+        self.add_inst_cmp(Condition::Fle, src_1, src_2)
+    }
+
+    pub fn add_inst_fgt(&mut self, src_1: Temporary, src_2: Temporary) -> Temporary {
+        self.add_inst_cmp(Condition::Fgt, src_1, src_2)
+    }
 
-        let inst = Instruction::Set {
+    pub fn add_inst_fge(&mut self, src_1: Temporary, src_2: Temporary) -> Temporary {
+        self.add_inst_cmp(Condition::Fge, src_1, src_2)
+    }
+
+    /// Calls `func`, returns the temporary holding its return value if it has one.
+    /// Aggregates are passed by their address, and a returned one is placed in a stack
+    /// object whose address is returned
+    pub fn add_inst_call(&mut self, func: &FuncRef, args: Vec<Temporary>) -> Option<Temporary> {
+        func.signature.check_args(&func.name, &args);
+
+        self.is_leaf = false;
+
+        let (result, ret_slot) = self.call_result(&func.signature);
+        let inst = Instruction::Call {
             dest: result,
-            src: value,
+            func: func.name.clone(),
+            args,
+            signature: func.signature.clone(),
+            ret_slot,
         };
         self.instructions.push(inst);
 
         result
     }
 
-    pub fn add_inst_return(&mut self, tmp: Option<Temporary>) {
-        let inst = Instruction::Return { src: tmp };
-        self.instructions.push(inst);
-    }
+    /// Calls the function whose address is in `func`, which has to have `signature`
+    pub fn add_inst_call_indirect(
+        &mut self,
+        func: Temporary,
+        signature: &Signature,
+        args: Vec<Temporary>,
+    ) -> Option<Temporary> {
+        assert_eq!(func.size(), Size::QuadWord, "Function pointers are 64 bits");
+        signature.check_args("behind the pointer", &args);
 
-    pub fn add_inst_load(&mut self, slot: StackSlot) -> Temporary {
-        let result = Temporary::new(self.tmp_iota.next(), slot.size(), slot.is_signed());
+        self.is_leaf = false;
 
-        let inst = Instruction::Load {
+        let (result, ret_slot) = self.call_result(signature);
+        let inst = Instruction::CallIndirect {
             dest: result,
-            src: slot,
+            func,
+            args,
+            signature: signature.clone(),
+            ret_slot,
         };
         self.instructions.push(inst);
 
         result
     }
 
-    pub fn add_inst_store(&mut self, tmp: Temporary) -> StackSlot {
-        let slot = StackSlot::new(self.var_iota.next(), tmp.size(), tmp.is_signed());
-        self.stack_slots.push(slot);
+    /// Temporary holding the return value of a call, along with the stack object holding
+    /// a returned aggregate
+    fn call_result(&mut self, signature: &Signature) -> (Option<Temporary>, Option<StackSlot>) {
+        match &signature.ret {
+            Some(Type::Scalar(size, signed)) => {
+                let result = Temporary::new(self.tmp_iota.next(), *size, *signed);
+                (Some(result), None)
+            }
+            Some(Type::Aggregate(aggregate)) => {
+                // Rounded up so registers can be stored into it whole
+                let slot = self.add_stack_object(
+                    u32::from(aggregate.size()).next_multiple_of(8),
+                    aggregate.align().max(8),
+                );
+                let result = Temporary::new(self.tmp_iota.next(), Size::QuadWord, false);
+                (Some(result), Some(slot))
+            }
+            None => (None, None),
+        }
+    }
 
-        let inst = Instruction::Store {
-            dest: slot,
-            src: tmp,
-        };
-        self.instructions.push(inst);
+    /// Reserves a stack object big enough to hold a `va_list` on every target
+    pub fn add_va_list(&mut self) -> StackSlot {
+        self.add_stack_object(32, 8)
+    }
 
-        slot
+    /// Starts reading the variadic args into the `va_list` at `va_list`, see `add_va_list`
+    pub fn add_inst_va_start(&mut self, va_list: Temporary) {
+        assert!(
+            self.is_variadic,
+            "Function {} has to be made variadic to use va_start",
+            self.name
+        );
+        assert_eq!(va_list.size(), Size::QuadWord, "Pointers are 64 bits");
+
+        let inst = Instruction::VaStart { va_list };
+        self.instructions.push(inst);
     }
 
-    pub fn add_inst_add(&mut self, src_1: Temporary, src_2: Temporary) -> Temporary {
-        // NOTE: For now assert their sizes are equal
-        assert_eq!(src_1.size(), src_2.size());
+    /// Reads the next variadic arg from the `va_list` at `va_list`. Floats are passed
+    /// to variadic functions as F64
+    pub fn add_inst_va_arg(&mut self, va_list: Temporary, size: Size, signed: bool) -> Temporary {
+        assert_eq!(va_list.size(), Size::QuadWord, "Pointers are 64 bits");
+        assert_ne!(size, Size::F32, "Variadic floats are passed as F64");
 
-        let result = Temporary::new(
-            self.tmp_iota.next(),
-            src_1.size(),
-            src_1.is_signed() | src_2.is_signed(),
-        );
+        let result = Temporary::new(self.tmp_iota.next(), size, signed);
 
-        let inst = Instruction::Add {
+        let inst = Instruction::VaArg {
             dest: result,
-            src_1,
-            src_2,
+            va_list,
         };
         self.instructions.push(inst);
 
         result
     }
 
-    pub fn add_inst_call(
-        &mut self,
-        func: String,
-        args: Vec<Temporary>,
-    ) {
-        self.is_leaf = false;
-        
-        let inst = Instruction::Call { func, args };
+    /// Done reading variadic args from `va_list`. va_end is a no-op on both AAPCS64 and
+    /// SysV, as a `va_list` holds no resources, so the targets emit nothing for it
+    pub fn add_inst_va_end(&mut self, va_list: Temporary) {
+        assert_eq!(va_list.size(), Size::QuadWord, "Pointers are 64 bits");
+
+        let inst = Instruction::VaEnd { va_list };
         self.instructions.push(inst);
     }
 
-    pub fn add_inst_call_result(&mut self, size: Size, signed: bool) -> Temporary {
-        let result = Temporary::new(
-            self.tmp_iota.next(),
-            size,
-            signed,
-        );
+    /// Address of the function `func`, which does not have to be in this module
+    pub fn add_inst_func_addr(&mut self, func: &FuncRef) -> Temporary {
+        let result = Temporary::new(self.tmp_iota.next(), Size::QuadWord, false);
 
-        let inst = Instruction::CallResult { dest: result };
+        let inst = Instruction::FuncAddr {
+            dest: result,
+            func: func.name.clone(),
+        };
         self.instructions.push(inst);
 
         result
     }
 
+    /// Address of data added to this function
     pub fn add_inst_local_addr(&mut self, addr: DataAddr) -> Temporary {
-        let result = Temporary::new(
-            self.tmp_iota.next(),
-            Size::QuadWord,
-            false,
+        assert!(
+            self.data.contains_key(&addr),
+            "Data {} does not belong to function {}",
+            addr.id(),
+            self.name
+        );
+
+        self.load_addr(addr)
+    }
+
+    /// Address of data added to the module
+    pub fn add_inst_global_addr(&mut self, addr: DataAddr) -> Temporary {
+        assert!(
+            addr.is_global(),
+            "Data {} is local to a function, use add_inst_local_addr",
+            addr.id()
         );
 
+        self.load_addr(addr)
+    }
+
+    fn load_addr(&mut self, addr: DataAddr) -> Temporary {
+        let result = Temporary::new(self.tmp_iota.next(), Size::QuadWord, false);
+
         let inst = Instruction::LoadAddr { dest: result, addr };
         self.instructions.push(inst);
 
         result
     }
 
-    pub(crate) fn generate_stack_slot_offsets(&self) -> HashMap<StackSlot, u16> {
-        // TODO: C gives an extra 4 byte gap before variables... why?
-        let mut stack_slot_offsets = HashMap::new();
-        let mut current_offset = self.stack_size();
-
-        for slot in &self.stack_slots {
-            let slot_size = slot.size().in_bytes();
-            // round up current offset to nearest slot_size
-            while current_offset % slot_size != 0 {
-                current_offset -= 1;
+    /// Moves `tmps` into stack slots, they are reloaded into a new temporary before every use
+    /// and stored right after their def. Returns the temporaries created for the reloads and stores
+    pub(crate) fn spill(&mut self, tmps: &HashSet<Temporary>) -> Vec<Temporary> {
+        let mut tmps: Vec<Temporary> = tmps.iter().copied().collect();
+        tmps.sort_by_key(|tmp| tmp.id());
+
+        let mut slots = HashMap::new();
+        for tmp in tmps {
+            let slot = StackSlot::new(self.var_iota.next(), tmp.size(), tmp.is_signed());
+            self.stack_slots.push(slot);
+            slots.insert(tmp, slot);
+        }
+
+        let mut new_tmps = Vec::new();
+        let mut instructions = Vec::with_capacity(self.instructions.len());
+
+        for mut inst in std::mem::take(&mut self.instructions) {
+            let mut store = None;
+
+            {
+                let (dest, srcs) = inst.operands_mut();
+
+                let mut reloads = HashMap::new();
+                for src in srcs {
+                    if let Some(slot) = slots.get(src) {
+                        let reload = *reloads.entry(*src).or_insert_with(|| {
+                            let reload =
+                                Temporary::new(self.tmp_iota.next(), src.size(), src.is_signed());
+                            instructions.push(Instruction::Load {
+                                dest: reload,
+                                src: *slot,
+                            });
+                            new_tmps.push(reload);
+                            reload
+                        });
+
+                        *src = reload;
+                    }
+                }
+
+                if let Some(dest) = dest {
+                    if let Some(slot) = slots.get(dest) {
+                        let tmp =
+                            Temporary::new(self.tmp_iota.next(), dest.size(), dest.is_signed());
+                        store = Some(Instruction::Store {
+                            dest: *slot,
+                            src: tmp,
+                        });
+                        new_tmps.push(tmp);
+
+                        *dest = tmp;
+                    }
+                }
             }
 
-            current_offset -= slot_size;
-            stack_slot_offsets.insert(*slot, current_offset);
+            instructions.push(inst);
+            instructions.extend(store);
         }
 
-        stack_slot_offsets
-    }
+        self.instructions = instructions;
 
-    pub(crate) fn stack_size(&self) -> u16 {
-        let mut stack_size = 0;
+        new_tmps
+    }
 
-        for slot in &self.stack_slots {
-            let slot_size = slot.size().in_bytes();
+    pub(crate) fn generate_stack_slot_offsets(&self) -> HashMap<StackSlot, u32> {
+        // TODO: C gives an extra 4 byte gap before variables... why?
+        let stack_size = self.stack_size();
 
-            // Add alignment if needed
-            while stack_size % slot_size != 0 {
-                stack_size += 1;
-            }
+        self.slot_depths()
+            .map(|(slot, depth)| (slot, stack_size - depth))
+            .collect()
+    }
 
-            stack_size += slot_size;
-        }
+    pub(crate) fn stack_size(&self) -> u32 {
+        let depth = self.slot_depths().last().map_or(0, |(_, depth)| depth);
 
         // Align stack to 16 bytes
-        while stack_size % 16 != 0 {
-            stack_size += 1;
-        }
+        depth.next_multiple_of(16)
+    }
 
-        stack_size
+    /// Slots are laid out downwards from the top of the slot area, this gives how far below
+    /// the top each slot starts. Rounding up keeps every slot aligned as the top is 16 byte aligned
+    fn slot_depths(&self) -> impl Iterator<Item = (StackSlot, u32)> + '_ {
+        let mut depth: u32 = 0;
+
+        self.stack_slots.iter().map(move |slot| {
+            depth = (depth + slot.bytes()).next_multiple_of(slot.align().into());
+            (*slot, depth)
+        })
     }
 }
 
@@ -266,11 +1198,15 @@ impl Temporary {
         Self { id, size, signed }
     }
 
+    pub(crate) fn id(self) -> usize {
+        self.id
+    }
+
     pub(crate) fn size(self) -> Size {
         self.size
     }
 
-    fn is_signed(self) -> bool {
+    pub(crate) fn is_signed(self) -> bool {
         self.signed
     }
 }
@@ -281,6 +1217,8 @@ pub enum Size {
     Word,
     DoubleWord,
     QuadWord,
+    F32,
+    F64,
 }
 
 impl Size {
@@ -288,10 +1226,14 @@ impl Size {
         match self {
             Size::Byte => 1,
             Size::Word => 2,
-            Size::DoubleWord => 4,
-            Size::QuadWord => 8,
+            Size::DoubleWord | Size::F32 => 4,
+            Size::QuadWord | Size::F64 => 8,
         }
     }
+
+    pub(crate) fn is_float(self) -> bool {
+        matches!(self, Size::F32 | Size::F64)
+    }
 }
 
 #[derive(Clone, Copy)]
@@ -304,10 +1246,12 @@ pub enum Value {
     I16(i16),
     I32(i32),
     I64(i64),
+    F32(f32),
+    F64(f64),
 }
 
 impl Value {
-    fn size(self) -> Size {
+    pub(crate) fn size(self) -> Size {
         match self {
             Value::U8(_) => Size::Byte,
             Value::U16(_) => Size::Word,
@@ -317,6 +1261,8 @@ impl Value {
             Value::I16(_) => Size::Word,
             Value::I32(_) => Size::DoubleWord,
             Value::I64(_) => Size::QuadWord,
+            Value::F32(_) => Size::F32,
+            Value::F64(_) => Size::F64,
         }
     }
 
@@ -330,6 +1276,8 @@ impl Value {
             Value::I16(x) => x as u64,
             Value::I32(x) => x as u64,
             Value::I64(x) => x as u64,
+            Value::F32(x) => x.to_bits() as u64,
+            Value::F64(x) => x.to_bits(),
         }
     }
 
@@ -337,6 +1285,7 @@ impl Value {
         match self {
             Value::U8(_) | Value::U16(_) | Value::U32(_) | Value::U64(_) => false,
             Value::I8(_) | Value::I16(_) | Value::I32(_) | Value::I64(_) => true,
+            Value::F32(_) | Value::F64(_) => true,
         }
     }
 }
@@ -345,47 +1294,610 @@ impl Value {
 #[derive(Clone)]
 pub(crate) enum Instruction {
     Set             { dest: Temporary, src: Value },
-    Return          { src: Option<Temporary> },
+    /// `src` is the address of the returned aggregate if there is one
+    Return          { src: Option<Temporary>, aggregate: Option<Aggregate> },
     Load            { dest: Temporary, src: StackSlot },
     LoadAddr        { dest: Temporary, addr: DataAddr },
+    FuncAddr        { dest: Temporary, func: String },
     Store           { dest: StackSlot, src: Temporary },
+    SlotAddr        { dest: Temporary, slot: StackSlot },
+    LoadPtr         { dest: Temporary, addr: Temporary, offset: PtrOffset },
+    StorePtr        { addr: Temporary, src: Temporary, offset: PtrOffset },
     Add             { dest: Temporary, src_1: Temporary, src_2: Temporary },
-    Call            { func: String, args: Vec<Temporary> },
-    CallResult      { dest: Temporary },
+    Sub             { dest: Temporary, src_1: Temporary, src_2: Temporary },
+    Mul             { dest: Temporary, src_1: Temporary, src_2: Temporary },
+    Div             { dest: Temporary, src_1: Temporary, src_2: Temporary },
+    Rem             { dest: Temporary, src_1: Temporary, src_2: Temporary },
+    Neg             { dest: Temporary, src: Temporary },
+    Sqrt            { dest: Temporary, src: Temporary },
+    /// Between integers and floats, or between float sizes
+    Convert         { dest: Temporary, src: Temporary },
+    Extend          { dest: Temporary, src: Temporary, signed: bool },
+    Trunc           { dest: Temporary, src: Temporary },
+    And             { dest: Temporary, src_1: Temporary, src_2: Temporary },
+    Or              { dest: Temporary, src_1: Temporary, src_2: Temporary },
+    Xor             { dest: Temporary, src_1: Temporary, src_2: Temporary },
+    Not             { dest: Temporary, src: Temporary },
+    Shl             { dest: Temporary, src: Temporary, amount: Temporary },
+    LShr            { dest: Temporary, src: Temporary, amount: Temporary },
+    AShr            { dest: Temporary, src: Temporary, amount: Temporary },
+    Cmp             { dest: Temporary, src_1: Temporary, src_2: Temporary, cond: Condition },
+    Label           { label: Label },
+    Jump            { label: Label },
+    Branch          { cond: Temporary, then_label: Label, else_label: Label },
+    // `ret_slot` holds a returned aggregate, `dest` gets its address
+    Call            { dest: Option<Temporary>, func: String, args: Vec<Temporary>, signature: Signature, ret_slot: Option<StackSlot> },
+    CallIndirect    { dest: Option<Temporary>, func: Temporary, args: Vec<Temporary>, signature: Signature, ret_slot: Option<StackSlot> },
+    VaStart         { va_list: Temporary },
+    VaArg           { dest: Temporary, va_list: Temporary },
+    VaEnd           { va_list: Temporary },
+}
+
+/// What a target has to know about an instruction to tell which registers it clobbers
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub enum InstructionKind {
+    Call,
+    /// Integer division and remainder
+    Division,
+    Shift,
+    Other,
+}
+
+impl Instruction {
+    pub(crate) fn kind(&self) -> InstructionKind {
+        match self {
+            Instruction::Call { .. } | Instruction::CallIndirect { .. } => InstructionKind::Call,
+            Instruction::Div { dest, .. } if !dest.size().is_float() => InstructionKind::Division,
+            Instruction::Rem { .. } => InstructionKind::Division,
+            Instruction::Shl { .. } | Instruction::LShr { .. } | Instruction::AShr { .. } => {
+                InstructionKind::Shift
+            }
+            _ => InstructionKind::Other,
+        }
+    }
+
+    /// Temporaries read by this instruction
+    pub(crate) fn uses(&self) -> Vec<Temporary> {
+        match self {
+            Instruction::Set { .. }
+            | Instruction::Load { .. }
+            | Instruction::LoadAddr { .. }
+            | Instruction::FuncAddr { .. }
+            | Instruction::SlotAddr { .. }
+            | Instruction::Label { .. }
+            | Instruction::Jump { .. } => Vec::new(),
+            Instruction::Return { src, .. } => src.iter().copied().collect(),
+            Instruction::Store { src, .. } => vec![*src],
+            Instruction::LoadPtr { addr, offset, .. } => {
+                std::iter::once(*addr).chain(offset.index()).collect()
+            }
+            Instruction::StorePtr { addr, src, offset } => {
+                [*addr, *src].into_iter().chain(offset.index()).collect()
+            }
+            Instruction::Add { src_1, src_2, .. }
+            | Instruction::Sub { src_1, src_2, .. }
+            | Instruction::Mul { src_1, src_2, .. }
+            | Instruction::Div { src_1, src_2, .. }
+            | Instruction::Rem { src_1, src_2, .. }
+            | Instruction::And { src_1, src_2, .. }
+            | Instruction::Or { src_1, src_2, .. }
+            | Instruction::Xor { src_1, src_2, .. }
+            | Instruction::Cmp { src_1, src_2, .. } => vec![*src_1, *src_2],
+            Instruction::Shl { src, amount, .. }
+            | Instruction::LShr { src, amount, .. }
+            | Instruction::AShr { src, amount, .. } => vec![*src, *amount],
+            Instruction::Neg { src, .. }
+            | Instruction::Not { src, .. }
+            | Instruction::Sqrt { src, .. }
+            | Instruction::Convert { src, .. }
+            | Instruction::Extend { src, .. }
+            | Instruction::Trunc { src, .. } => vec![*src],
+            Instruction::Branch { cond, .. } => vec![*cond],
+            Instruction::VaStart { va_list }
+            | Instruction::VaArg { va_list, .. }
+            | Instruction::VaEnd { va_list } => vec![*va_list],
+            Instruction::Call { args, .. } => args.clone(),
+            Instruction::CallIndirect { func, args, .. } => {
+                std::iter::once(*func).chain(args.iter().copied()).collect()
+            }
+        }
+    }
+
+    /// Temporary written by this instruction
+    pub(crate) fn def(&self) -> Option<Temporary> {
+        match self {
+            Instruction::Set { dest, .. }
+            | Instruction::Load { dest, .. }
+            | Instruction::LoadAddr { dest, .. }
+            | Instruction::FuncAddr { dest, .. }
+            | Instruction::SlotAddr { dest, .. }
+            | Instruction::LoadPtr { dest, .. }
+            | Instruction::Add { dest, .. }
+            | Instruction::Sub { dest, .. }
+            | Instruction::Mul { dest, .. }
+            | Instruction::Div { dest, .. }
+            | Instruction::Rem { dest, .. }
+            | Instruction::Neg { dest, .. }
+            | Instruction::Sqrt { dest, .. }
+            | Instruction::Convert { dest, .. }
+            | Instruction::Extend { dest, .. }
+            | Instruction::Trunc { dest, .. }
+            | Instruction::And { dest, .. }
+            | Instruction::Or { dest, .. }
+            | Instruction::Xor { dest, .. }
+            | Instruction::Not { dest, .. }
+            | Instruction::Shl { dest, .. }
+            | Instruction::LShr { dest, .. }
+            | Instruction::AShr { dest, .. }
+            | Instruction::Cmp { dest, .. }
+            | Instruction::VaArg { dest, .. } => Some(*dest),
+            Instruction::Call { dest, .. } | Instruction::CallIndirect { dest, .. } => *dest,
+            Instruction::Return { .. }
+            | Instruction::Store { .. }
+            | Instruction::StorePtr { .. }
+            | Instruction::Label { .. }
+            | Instruction::Jump { .. }
+            | Instruction::Branch { .. }
+            | Instruction::VaStart { .. }
+            | Instruction::VaEnd { .. } => None,
+        }
+    }
+
+    /// Mutable access to the temporary written and the temporaries read by this instruction
+    fn operands_mut(&mut self) -> (Option<&mut Temporary>, Vec<&mut Temporary>) {
+        match self {
+            Instruction::Set { dest, .. }
+            | Instruction::Load { dest, .. }
+            | Instruction::LoadAddr { dest, .. }
+            | Instruction::FuncAddr { dest, .. }
+            | Instruction::SlotAddr { dest, .. } => (Some(dest), Vec::new()),
+            Instruction::Return { src, .. } => (None, src.iter_mut().collect()),
+            Instruction::Store { src, .. } => (None, vec![src]),
+            Instruction::LoadPtr { dest, addr, offset } => {
+                (Some(dest), std::iter::once(addr).chain(offset.index_mut()).collect())
+            }
+            Instruction::StorePtr { addr, src, offset } => {
+                (None, [addr, src].into_iter().chain(offset.index_mut()).collect())
+            }
+            Instruction::Add { dest, src_1, src_2 }
+            | Instruction::Sub { dest, src_1, src_2 }
+            | Instruction::Mul { dest, src_1, src_2 }
+            | Instruction::Div { dest, src_1, src_2 }
+            | Instruction::Rem { dest, src_1, src_2 }
+            | Instruction::And { dest, src_1, src_2 }
+            | Instruction::Or { dest, src_1, src_2 }
+            | Instruction::Xor { dest, src_1, src_2 }
+            | Instruction::Cmp {
+                dest, src_1, src_2, ..
+            } => (Some(dest), vec![src_1, src_2]),
+            Instruction::Shl { dest, src, amount }
+            | Instruction::LShr { dest, src, amount }
+            | Instruction::AShr { dest, src, amount } => (Some(dest), vec![src, amount]),
+            Instruction::Neg { dest, src }
+            | Instruction::Not { dest, src }
+            | Instruction::Sqrt { dest, src }
+            | Instruction::Convert { dest, src }
+            | Instruction::Extend { dest, src, .. }
+            | Instruction::Trunc { dest, src } => (Some(dest), vec![src]),
+            Instruction::Branch { cond, .. } => (None, vec![cond]),
+            Instruction::VaStart { va_list } | Instruction::VaEnd { va_list } => {
+                (None, vec![va_list])
+            }
+            Instruction::VaArg { dest, va_list } => (Some(dest), vec![va_list]),
+            Instruction::Call { dest, args, .. } => (dest.as_mut(), args.iter_mut().collect()),
+            Instruction::CallIndirect {
+                dest, func, args, ..
+            } => {
+                (dest.as_mut(), std::iter::once(func).chain(args.iter_mut()).collect())
+            }
+            Instruction::Label { .. } | Instruction::Jump { .. } => (None, Vec::new()),
+        }
+    }
+
+    /// Labels this instruction can transfer control to, `None` if it falls through
+    pub(crate) fn successors(&self) -> Option<Vec<Label>> {
+        match self {
+            Instruction::Return { .. } => Some(Vec::new()),
+            Instruction::Jump { label } => Some(vec![*label]),
+            Instruction::Branch {
+                then_label,
+                else_label,
+                ..
+            } => Some(vec![*then_label, *else_label]),
+            _ => None,
+        }
+    }
+}
+
+/// Offset from the address of a pointer load or store
+#[derive(Clone, Copy)]
+pub(crate) enum PtrOffset {
+    Imm(i32),
+    /// Offset in a temporary, in multiples of the size of the access if `scaled`
+    Index { index: Temporary, scaled: bool },
+}
+
+impl PtrOffset {
+    fn index(self) -> Option<Temporary> {
+        match self {
+            PtrOffset::Imm(_) => None,
+            PtrOffset::Index { index, .. } => Some(index),
+        }
+    }
+
+    fn index_mut(&mut self) -> Option<&mut Temporary> {
+        match self {
+            PtrOffset::Imm(_) => None,
+            PtrOffset::Index { index, .. } => Some(index),
+        }
+    }
 }
 
 #[derive(Clone, Copy, PartialEq, Eq, Hash)]
 pub struct StackSlot {
     id: usize,
-    size: Size,
-    signed: bool,
+    layout: SlotLayout,
+}
+
+#[derive(Clone, Copy, PartialEq, Eq, Hash)]
+enum SlotLayout {
+    /// Holds a single value, loaded and stored as a whole
+    Scalar { size: Size, signed: bool },
+    /// `size` bytes starting at a multiple of `align`, only accessed through its address
+    Object { size: u32, align: u16 },
 }
 
 impl StackSlot {
     fn new(id: usize, size: Size, signed: bool) -> Self {
-        Self { id, size, signed }
+        Self {
+            id,
+            layout: SlotLayout::Scalar { size, signed },
+        }
+    }
+
+    fn new_object(id: usize, size: u32, align: u16) -> Self {
+        Self {
+            id,
+            layout: SlotLayout::Object { size, align },
+        }
     }
 
     pub(crate) fn size(self) -> Size {
-        self.size
+        match self.layout {
+            SlotLayout::Scalar { size, .. } => size,
+            SlotLayout::Object { .. } => {
+                panic!("Stack objects can only be accessed through their address")
+            }
+        }
     }
 
     pub(crate) fn is_signed(self) -> bool {
-        self.signed
+        match self.layout {
+            SlotLayout::Scalar { signed, .. } => signed,
+            SlotLayout::Object { .. } => {
+                panic!("Stack objects can only be accessed through their address")
+            }
+        }
+    }
+
+    /// Bytes the slot takes in the frame
+    fn bytes(self) -> u32 {
+        match self.layout {
+            SlotLayout::Scalar { size, .. } => size.in_bytes().into(),
+            SlotLayout::Object { size, .. } => size,
+        }
+    }
+
+    fn align(self) -> u16 {
+        match self.layout {
+            SlotLayout::Scalar { size, .. } => size.in_bytes(),
+            SlotLayout::Object { align, .. } => align,
+        }
     }
 }
 
 #[derive(Clone)]
 pub enum Data {
     StringNullTerminated(String),
+    Bytes(Vec<u8>),
+    /// Integers laid out one after another, each taking the size of its value
+    Integers(Vec<Value>),
+    /// `size` bytes of zeros
+    Zeroed(u64),
+    /// `data` starting at a multiple of `align` bytes, which has to be a power of 2
+    Aligned { align: u64, data: Box<Data> },
+    /// Pointer sized slot holding the address of `symbol` plus `addend`
+    Pointer { symbol: Symbol, addend: i64 },
+    /// Data laid out one after another, e.g. the fields of a struct
+    Sequence(Vec<Data>),
+}
+
+impl Data {
+    fn is_zeroed(&self) -> bool {
+        match self {
+            Data::Zeroed(_) => true,
+            Data::Aligned { data, .. } => data.is_zeroed(),
+            Data::Sequence(items) => items.iter().all(|item| item.is_zeroed()),
+            _ => false,
+        }
+    }
+
+    /// Whether the data holds addresses, which have to be relocated when the program is loaded
+    pub(crate) fn has_pointers(&self) -> bool {
+        match self {
+            Data::Pointer { .. } => true,
+            Data::Aligned { data, .. } => data.has_pointers(),
+            Data::Sequence(items) => items.iter().any(|item| item.has_pointers()),
+            _ => false,
+        }
+    }
+
+    /// Addresses of the data this data points at
+    fn pointed_data(&self) -> Vec<DataAddr> {
+        match self {
+            Data::Pointer {
+                symbol: Symbol::Data(addr),
+                ..
+            } => vec![*addr],
+            Data::Aligned { data, .. } => data.pointed_data(),
+            Data::Sequence(items) => items.iter().flat_map(|item| item.pointed_data()).collect(),
+            _ => Vec::new(),
+        }
+    }
+}
+
+/// Something whose address can be stored in data
+#[derive(Clone)]
+pub enum Symbol {
+    Data(DataAddr),
+    Function(String),
+}
+
+/// Where data is placed in the program
+#[derive(Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Section {
+    ReadOnly,
+    Mutable,
+    /// Zero-filled data which takes no space in the binary
+    Uninitialized,
+}
+
+impl Section {
+    fn check(self, data: &Data) {
+        assert!(
+            self != Section::Uninitialized || data.is_zeroed(),
+            "Only zeroed data can be uninitialized"
+        );
+    }
+}
+
+/// Type of an arg or a return value
+#[derive(Clone, PartialEq)]
+pub enum Type {
+    /// Size and signedness, as given to `add_arg`
+    Scalar(Size, bool),
+    /// Passed by value, see `add_arg_aggregate`
+    Aggregate(Aggregate),
+}
+
+/// Struct or array passed by value, only its size, alignment and the sizes of its fields
+/// matter for passing it
+#[derive(Clone, PartialEq)]
+pub struct Aggregate {
+    size: u16,
+    align: u16,
+    /// Offset and size of every field
+    fields: Vec<(u16, Size)>,
+}
+
+impl Aggregate {
+    /// Lays out `fields` one after another like a C struct, each at a multiple of its size
+    pub fn new(fields: Vec<Size>) -> Self {
+        assert!(!fields.is_empty(), "Aggregates need at least one field");
+
+        let mut end: u16 = 0;
+        let fields: Vec<_> = fields
+            .into_iter()
+            .map(|size| {
+                let offset = end.next_multiple_of(size.in_bytes());
+                end = offset + size.in_bytes();
+                (offset, size)
+            })
+            .collect();
+        let align = fields.iter().map(|(_, size)| size.in_bytes()).max().unwrap();
+
+        Self {
+            size: end.next_multiple_of(align),
+            align,
+            fields,
+        }
+    }
+
+    /// Aggregate with `fields` at the given offsets, e.g. for nested structs
+    pub fn with_layout(size: u16, align: u16, fields: Vec<(u16, Size)>) -> Self {
+        assert!(
+            align.is_power_of_two() && align <= 16,
+            "Aggregates have to be aligned to a power of 2 up to 16, not {align}"
+        );
+        assert!(
+            size != 0 && size.is_multiple_of(align),
+            "Aggregate size {size} is not a multiple of its alignment {align}"
+        );
+        assert!(!fields.is_empty(), "Aggregates need at least one field");
+        for (offset, field_size) in &fields {
+            assert!(
+                offset + field_size.in_bytes() <= size,
+                "Field at {offset} does not fit in an aggregate of {size} bytes"
+            );
+        }
+
+        Self {
+            size,
+            align,
+            fields,
+        }
+    }
+
+    pub(crate) fn size(&self) -> u16 {
+        self.size
+    }
+
+    pub(crate) fn align(&self) -> u16 {
+        self.align
+    }
+
+    pub(crate) fn fields(&self) -> &[(u16, Size)] {
+        &self.fields
+    }
+}
+
+/// Types of the args and the return value of a function
+#[derive(Clone, PartialEq)]
+pub struct Signature {
+    params: Vec<Type>,
+    ret: Option<Type>,
+    is_variadic: bool,
+}
+
+impl Signature {
+    /// `params` and `ret` are `(size, signed)` pairs, as given to `add_arg`
+    pub fn new(params: Vec<(Size, bool)>, ret: Option<(Size, bool)>) -> Self {
+        Self::with_types(
+            params
+                .into_iter()
+                .map(|(size, signed)| Type::Scalar(size, signed))
+                .collect(),
+            ret.map(|(size, signed)| Type::Scalar(size, signed)),
+        )
+    }
+
+    /// Same as `new`, for functions like printf which take any number of args after `params`
+    pub fn new_variadic(params: Vec<(Size, bool)>, ret: Option<(Size, bool)>) -> Self {
+        Self {
+            is_variadic: true,
+            ..Self::new(params, ret)
+        }
+    }
+
+    /// Same as `new`, for functions which take or return aggregates
+    pub fn with_types(params: Vec<Type>, ret: Option<Type>) -> Self {
+        Self {
+            params,
+            ret,
+            is_variadic: false,
+        }
+    }
+
+    pub(crate) fn ret(&self) -> Option<&Type> {
+        self.ret.as_ref()
+    }
+
+    /// Types of `args` passed to a function with this signature, the variadic ones are scalars
+    pub(crate) fn arg_types(&self, args: &[Temporary]) -> Vec<Type> {
+        let variadic_args = args[self.params.len()..]
+            .iter()
+            .map(|arg| Type::Scalar(arg.size(), arg.is_signed()));
+        self.params.iter().cloned().chain(variadic_args).collect()
+    }
+
+    /// Number of args before the variadic ones, `None` if the function is not variadic
+    pub(crate) fn fixed_args(&self) -> Option<usize> {
+        self.is_variadic.then_some(self.params.len())
+    }
+
+    fn has_aggregates(&self) -> bool {
+        self.params
+            .iter()
+            .chain(&self.ret)
+            .any(|ty| matches!(ty, Type::Aggregate(_)))
+    }
+
+    /// Panics if `args` do not have the sizes of the params of function `name`
+    fn check_args(&self, name: &str, args: &[Temporary]) {
+        if self.is_variadic {
+            assert!(
+                args.len() >= self.params.len(),
+                "Function {name} takes at least {} args",
+                self.params.len()
+            );
+        } else {
+            assert_eq!(
+                args.len(),
+                self.params.len(),
+                "Function {name} takes {} args",
+                self.params.len()
+            );
+        }
+
+        for (arg_num, (arg, param)) in args.iter().zip(&self.params).enumerate() {
+            // Aggregates are passed by their address
+            let size = match param {
+                Type::Scalar(size, _) => *size,
+                Type::Aggregate(_) => Size::QuadWord,
+            };
+            assert_eq!(
+                arg.size(),
+                size,
+                "Arg {arg_num} of function {name} has the wrong size"
+            );
+        }
+    }
+}
+
+/// A function declared on a module, see `Module::declare_func`
+#[derive(Clone)]
+pub struct FuncRef {
+    name: String,
+    signature: Signature,
+}
+
+impl FuncRef {
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    pub fn signature(&self) -> &Signature {
+        &self.signature
+    }
 }
 
+/// Address of data added to a module or a function, ids are only unique within either
 #[derive(Clone, Copy, PartialEq, Eq, Hash)]
 pub struct DataAddr {
     id: usize,
+    is_global: bool,
 }
 
 impl DataAddr {
+    pub(crate) fn global(id: usize) -> Self {
+        Self {
+            id,
+            is_global: true,
+        }
+    }
+
+    pub(crate) fn local(id: usize) -> Self {
+        Self {
+            id,
+            is_global: false,
+        }
+    }
+
+    pub(crate) fn id(&self) -> usize {
+        self.id
+    }
+
+    pub(crate) fn is_global(&self) -> bool {
+        self.is_global
+    }
+}
+
+#[derive(Clone, Copy, PartialEq, Eq, Hash)]
+pub struct Label {
+    id: usize,
+}
+
+impl Label {
     pub(crate) fn new(id: usize) -> Self {
         Self { id }
     }
@@ -395,17 +1907,30 @@ impl DataAddr {
     }
 }
 
-// #[derive(Clone, Copy)]
-// pub(crate) struct Label {
-//     id: usize,
-// }
-
-// impl Label {
-//     pub(crate) fn new(id: usize) -> Self {
-//         Self { id }
-//     }
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Condition {
+    Eq,
+    Ne,
+    Slt,
+    Sle,
+    Sgt,
+    Sge,
+    Ult,
+    Ule,
+    Ugt,
+    Uge,
+    /// Float comparisons, false if either side is NaN
+    Flt,
+    Fle,
+    Fgt,
+    Fge,
+}
 
-//     pub(crate) fn id(&self) -> usize {
-//         self.id
-//     }
-// }
+impl Condition {
+    pub(crate) fn is_float(self) -> bool {
+        matches!(
+            self,
+            Condition::Flt | Condition::Fle | Condition::Fgt | Condition::Fge
+        )
+    }
+}
@@ -1,3 +1,5 @@
 pub mod ir;
 pub(crate) mod util;
-pub mod arm64;
\ No newline at end of file
+pub mod target;
+pub mod arm64;
+pub mod x86_64;
\ No newline at end of file
@@ -1,6 +1,10 @@
 use std::io;
 
-use lube::ir::{Data, Function, Module, Size, Value};
+use lube::{
+    arm64::{Arm64, ObjectFormat},
+    ir::{Aggregate, Data, Function, Module, Signature, Size, Value},
+    x86_64::X86_64,
+};
 
 fn main() -> io::Result<()> {
     /*
@@ -204,14 +208,21 @@ fn main() -> io::Result<()> {
             $1 = %1
             %2 = $0
             %3 = $1
-            call add %2 %3
-            %4 = call_result
+            %4 = call add %2 %3
             return %4
 
     */
 
     let mut module = Module::new();
 
+    let add = module.declare_func(
+        "_add".to_string(),
+        Signature::new(
+            vec![(Size::DoubleWord, true), (Size::DoubleWord, true)],
+            Some((Size::DoubleWord, true)),
+        ),
+    );
+
     let mut func_1 = Function::new("_add".to_string());
     func_1.make_public();
 
@@ -234,8 +245,7 @@ fn main() -> io::Result<()> {
     let var_1 = func_2.add_inst_store(tmp_1);
     let tmp_2 = func_2.add_inst_load(var_0);
     let tmp_3 = func_2.add_inst_load(var_1);
-    func_2.add_inst_call("_add".to_string(), vec![tmp_2, tmp_3]);
-    let tmp_4 = func_2.add_inst_call_result(Size::DoubleWord, true);
+    let tmp_4 = func_2.add_inst_call(&add, vec![tmp_2, tmp_3]).unwrap();
     func_2.add_inst_return(Some(tmp_4));
 
     module.add_func(func_2);
@@ -277,6 +287,11 @@ fn main() -> io::Result<()> {
 
     let mut module = Module::new();
 
+    let why_would_you_do_this = module.declare_func(
+        "_why_would_you_do_this".to_string(),
+        Signature::new(vec![(Size::DoubleWord, true); 10], None),
+    );
+
     let mut func = Function::new("_why_would_you_do_this".to_string());
 
     func.add_arg(Size::DoubleWord, true);
@@ -307,7 +322,7 @@ fn main() -> io::Result<()> {
     let tmp_8 = func.add_inst_set(Value::I32(8));
     let tmp_9 = func.add_inst_set(Value::I32(9));
     func.add_inst_call(
-        "_why_would_you_do_this".to_string(),
+        &why_would_you_do_this,
         vec![
             tmp_0, tmp_1, tmp_2, tmp_3, tmp_4, tmp_5, tmp_6, tmp_7, tmp_8, tmp_9,
         ],
@@ -366,6 +381,12 @@ fn main() -> io::Result<()> {
 
     let mut module = Module::new();
 
+    let dummy = module.declare_func("_dummy".to_string(), Signature::new(Vec::new(), None));
+    let why_would_you_do_this = module.declare_func(
+        "_why_would_you_do_this".to_string(),
+        Signature::new(vec![(Size::DoubleWord, true); 10], None),
+    );
+
     let mut func = Function::new("_dummy".to_string());
 
     func.add_inst_return(None);
@@ -385,7 +406,7 @@ fn main() -> io::Result<()> {
     func.add_arg(Size::DoubleWord, true);
     func.add_arg(Size::DoubleWord, true);
 
-    func.add_inst_call("_dummy".to_string(), Vec::new());
+    func.add_inst_call(&dummy, Vec::new());
 
     func.add_inst_return(None);
 
@@ -404,7 +425,7 @@ fn main() -> io::Result<()> {
     let tmp_8 = func.add_inst_set(Value::I32(8));
     let tmp_9 = func.add_inst_set(Value::I32(9));
     func.add_inst_call(
-        "_why_would_you_do_this".to_string(),
+        &why_would_you_do_this,
         vec![
             tmp_0, tmp_1, tmp_2, tmp_3, tmp_4, tmp_5, tmp_6, tmp_7, tmp_8, tmp_9,
         ],
@@ -441,13 +462,17 @@ fn main() -> io::Result<()> {
 
     let mut module = Module::new();
 
+    let printf = module.declare_func(
+        "_printf".to_string(),
+        Signature::new_variadic(vec![(Size::QuadWord, false)], Some((Size::DoubleWord, true))),
+    );
     
     let mut func = Function::new("_main".to_string());
     func.make_public();
     
     let addr_0 = func.add_data(Data::StringNullTerminated("Hello, World!\n".to_string()));
     let tmp_0 = func.add_inst_local_addr(addr_0);
-    func.add_inst_call("_printf".to_string(), vec![tmp_0]);
+    func.add_inst_call(&printf, vec![tmp_0]);
     let tmp_1 = func.add_inst_set(Value::I32(0));
     func.add_inst_return(Some(tmp_1));
 
@@ -455,5 +480,248 @@ fn main() -> io::Result<()> {
 
     module.generate_asm().save_to(".build/hello_world.s")?;
 
+    /*
+
+        Same as above, for arm64 Linux
+
+    */
+
+    let mut module = Module::new();
+
+    let printf = module.declare_func(
+        "printf".to_string(),
+        Signature::new_variadic(vec![(Size::QuadWord, false)], Some((Size::DoubleWord, true))),
+    );
+
+    let mut func = Function::new("main".to_string());
+    func.make_public();
+
+    let addr_0 = func.add_data(Data::StringNullTerminated("Hello, World!\n".to_string()));
+    let tmp_0 = func.add_inst_local_addr(addr_0);
+    func.add_inst_call(&printf, vec![tmp_0]);
+    let tmp_1 = func.add_inst_set(Value::I32(0));
+    func.add_inst_return(Some(tmp_1));
+
+    module.add_func(func);
+
+    module
+        .generate_asm_for(&Arm64::new(ObjectFormat::Elf))
+        .save_to(".build/hello_world_linux.s")?;
+
+    /*
+
+        Same as above, for x86_64 Linux
+
+    */
+
+    let mut module = Module::new();
+
+    let printf = module.declare_func(
+        "printf".to_string(),
+        Signature::new_variadic(vec![(Size::QuadWord, false)], Some((Size::DoubleWord, true))),
+    );
+
+    let mut func = Function::new("main".to_string());
+    func.make_public();
+
+    let addr_0 = func.add_data(Data::StringNullTerminated("Hello, World!\n".to_string()));
+    let tmp_0 = func.add_inst_local_addr(addr_0);
+    func.add_inst_call(&printf, vec![tmp_0]);
+    let tmp_1 = func.add_inst_set(Value::I32(0));
+    func.add_inst_return(Some(tmp_1));
+
+    module.add_func(func);
+
+    module
+        .generate_asm_for(&X86_64)
+        .save_to(".build/hello_world_x86_64.s")?;
+
+    /*
+
+        int main() {
+            printf("%d + %d = %ld\n", 2, 3, 5L);
+            return 0;
+        }
+
+        ==========
+
+        main:
+            %0 = @0
+            %1 = 2
+            %2 = 3
+            %3 = 5
+            call printf %0 ... %1 %2 %3
+            %4 = 0
+            return %4
+
+        @0:
+            StringNullTerminated "%d + %d = %ld\n"
+
+    */
+
+    let mut module = Module::new();
+
+    let printf = module.declare_func(
+        "_printf".to_string(),
+        Signature::new_variadic(vec![(Size::QuadWord, false)], Some((Size::DoubleWord, true))),
+    );
+
+    let mut func = Function::new("_main".to_string());
+    func.make_public();
+
+    let addr_0 = func.add_data(Data::StringNullTerminated("%d + %d = %ld\n".to_string()));
+    let tmp_0 = func.add_inst_local_addr(addr_0);
+    let tmp_1 = func.add_inst_set(Value::I32(2));
+    let tmp_2 = func.add_inst_set(Value::I32(3));
+    let tmp_3 = func.add_inst_set(Value::I64(5));
+    func.add_inst_call(&printf, vec![tmp_0, tmp_1, tmp_2, tmp_3]);
+    let tmp_4 = func.add_inst_set(Value::I32(0));
+    func.add_inst_return(Some(tmp_4));
+
+    module.add_func(func);
+
+    module.generate_asm().save_to(".build/printf_variadic.s")?;
+
+    /*
+        long sum_3(int count, ...) {
+            va_list args;
+            va_start(args, count);
+            long a = va_arg(args, long);
+            long b = va_arg(args, long);
+            double c = va_arg(args, double);
+            va_end(args);
+            return a + b + (long)c;
+        }
+
+        ==========
+
+        sum_3 variadic:
+            $0 = count
+            $1 = va_list
+            %0 = &$1
+            va_start %0
+            %1 = va_arg %0
+            %2 = va_arg %0
+            %3 = va_arg %0
+            va_end %0
+            %4 = %1 + %2
+            %5 = ftoi %3
+            %6 = %4 + %5
+            return %6
+    */
+
+    let mut module = Module::new();
+
+    let mut func = Function::new("sum_3".to_string());
+    func.make_public();
+    func.make_variadic();
+
+    let _count = func.add_arg(Size::DoubleWord, true);
+    let va_list = func.add_va_list();
+    let tmp_0 = func.add_inst_slot_addr(va_list);
+    func.add_inst_va_start(tmp_0);
+    let tmp_1 = func.add_inst_va_arg(tmp_0, Size::QuadWord, true);
+    let tmp_2 = func.add_inst_va_arg(tmp_0, Size::QuadWord, true);
+    let tmp_3 = func.add_inst_va_arg(tmp_0, Size::F64, true);
+    func.add_inst_va_end(tmp_0);
+    let tmp_4 = func.add_inst_add(tmp_1, tmp_2);
+    let tmp_5 = func.add_inst_ftoi(tmp_3, Size::QuadWord, true);
+    let tmp_6 = func.add_inst_add(tmp_4, tmp_5);
+    func.add_inst_return(Some(tmp_6));
+
+    module.add_func(func);
+
+    module
+        .generate_asm_for(&Arm64::new(ObjectFormat::Elf))
+        .save_to(".build/sum_variadic_linux.s")?;
+
+    /*
+        struct Point { long x; long y; };
+
+        struct Point offset(struct Point p, long d) {
+            p.x += d;
+            p.y += d;
+            return p;
+        }
+
+        ==========
+
+        offset:
+            $0 = p
+            $1 = d
+            %0 = &$0
+            %1 = $1
+            %2 = load %0
+            %3 = %2 + %1
+            store %3 -> %0
+            %4 = load %0 + 8
+            %5 = %4 + %1
+            store %5 -> %0 + 8
+            return aggregate %0
+    */
+
+    let mut module = Module::new();
+
+    let point = Aggregate::new(vec![Size::QuadWord, Size::QuadWord]);
+
+    let mut func = Function::new("offset".to_string());
+    func.make_public();
+
+    let p = func.add_arg_aggregate(point.clone());
+    let d = func.add_arg(Size::QuadWord, true);
+    let tmp_0 = func.add_inst_slot_addr(p);
+    let tmp_1 = func.add_inst_load(d);
+    let tmp_2 = func.add_inst_load_ptr(tmp_0, Size::QuadWord, true, 0);
+    let tmp_3 = func.add_inst_add(tmp_2, tmp_1);
+    func.add_inst_store_ptr(tmp_0, tmp_3, 0);
+    let tmp_4 = func.add_inst_load_ptr(tmp_0, Size::QuadWord, true, 8);
+    let tmp_5 = func.add_inst_add(tmp_4, tmp_1);
+    func.add_inst_store_ptr(tmp_0, tmp_5, 8);
+    func.add_inst_return_aggregate(tmp_0, point);
+
+    module.add_func(func);
+
+    module
+        .generate_asm_for(&Arm64::new(ObjectFormat::Elf))
+        .save_to(".build/struct_point_linux.s")?;
+
+    /*
+        long fill(long value) {
+            long buffer[16384];
+            buffer[0] = value;
+            return buffer[0];
+        }
+
+        ==========
+
+        fill:
+            $0 = value
+            $1 = buffer
+            %0 = &$1
+            %1 = $0
+            store %1 -> %0
+            %2 = load %0
+            return %2
+    */
+
+    let mut module = Module::new();
+
+    let mut func = Function::new("fill".to_string());
+    func.make_public();
+
+    let value = func.add_arg(Size::QuadWord, true);
+    let buffer = func.add_stack_object(16384 * 8, 8);
+    let tmp_0 = func.add_inst_slot_addr(buffer);
+    let tmp_1 = func.add_inst_load(value);
+    func.add_inst_store_ptr(tmp_0, tmp_1, 0);
+    let tmp_2 = func.add_inst_load_ptr(tmp_0, Size::QuadWord, true, 0);
+    func.add_inst_return(Some(tmp_2));
+
+    module.add_func(func);
+
+    module
+        .generate_asm_for(&Arm64::new(ObjectFormat::Elf))
+        .save_to(".build/big_frame_linux.s")?;
+
     Ok(())
 }
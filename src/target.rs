@@ -0,0 +1,345 @@
+use core::fmt;
+use std::{
+    collections::HashMap,
+    fs::File,
+    io::{self, Write},
+};
+
+use crate::ir::{self, Data, DataAddr, InstructionKind, Section, Size, Symbol};
+
+/// A backend the module driver can generate assembly for, registers are identified by number
+pub trait Target {
+    /// Registers temporaries of `class` can be assigned, in order of preference
+    fn allocatable_registers(&self, class: RegisterClass) -> Vec<u8>;
+
+    /// Register the `arg_num`th argument of `class` of a call is passed in,
+    /// integer and float arguments are counted separately
+    fn arg_register(&self, arg_num: usize, class: RegisterClass) -> Option<u8>;
+
+    /// Register values of `class` are returned in
+    fn return_register(&self, class: RegisterClass) -> u8;
+
+    /// Registers overwritten by instructions of `kind`,
+    /// temporaries live across them can not be assigned them
+    fn clobbered_registers(&self, kind: InstructionKind) -> Vec<u8>;
+
+    /// Registers a function has to restore before returning if it uses them
+    fn callee_saved_registers(&self) -> Vec<u8>;
+
+    /// Lays out the stack slots of `func` and picks the callee saved registers it has to save
+    fn frame_layout(
+        &self,
+        func: &ir::Function,
+        registers: &HashMap<ir::Temporary, u8>,
+    ) -> FrameLayout {
+        let callee_saved = self.callee_saved_registers();
+        let mut saved_registers: Vec<u8> = registers
+            .values()
+            .copied()
+            .filter(|reg| callee_saved.contains(reg))
+            .collect();
+        saved_registers.sort();
+        saved_registers.dedup();
+
+        FrameLayout {
+            saved_registers,
+            slot_offsets: func.generate_stack_slot_offsets(),
+            slots_size: func.stack_size(),
+        }
+    }
+
+    /// Starts the assembly of a new module
+    fn emitter(&self) -> Box<dyn Emitter>;
+}
+
+/// What a target lays out in a function's frame, the backend decides where it all goes
+pub struct FrameLayout {
+    /// Callee saved registers the function uses, in ascending order
+    pub(crate) saved_registers: Vec<u8>,
+    /// Offset of every stack slot from the start of the slots area
+    pub(crate) slot_offsets: HashMap<ir::StackSlot, u32>,
+    pub(crate) slots_size: u32,
+}
+
+/// Kind of register a temporary lives in, every class has its own registers
+#[derive(Clone, Copy, PartialEq, Eq, Hash)]
+pub enum RegisterClass {
+    Integer,
+    Float,
+}
+
+impl RegisterClass {
+    pub(crate) fn of(size: Size) -> Self {
+        if size.is_float() {
+            RegisterClass::Float
+        } else {
+            RegisterClass::Integer
+        }
+    }
+}
+
+/// Lowers a module to assembly, one function at a time
+pub trait Emitter {
+    /// Selects and emits the instructions of `func` in the frame laid out by the target,
+    /// with its temporaries in the registers chosen by the register allocator
+    fn generate_function(
+        &mut self,
+        func: &ir::Function,
+        registers: &HashMap<ir::Temporary, u8>,
+        frame: &FrameLayout,
+    );
+
+    fn generate_data(&mut self, data: &HashMap<DataAddr, (Section, Data)>);
+
+    fn finish(self: Box<Self>) -> Asm;
+}
+
+/// Assembly of a module
+pub struct Asm {
+    lines: Vec<String>,
+}
+
+impl Asm {
+    pub(crate) fn new(lines: Vec<String>) -> Self {
+        Self { lines }
+    }
+
+    #[cfg(test)]
+    pub(crate) fn lines(&self) -> &[String] {
+        &self.lines
+    }
+
+    pub fn save_to(self, file: &str) -> io::Result<()> {
+        let mut file = File::create(file)?;
+
+        for line in self.lines {
+            writeln!(file, "{line}")?;
+        }
+
+        Ok(())
+    }
+}
+
+/// Data grouped by section and then in the order it was added, so the output
+/// does not depend on the iteration order of the map
+pub(crate) fn data_in_order(
+    data: &HashMap<DataAddr, (Section, Data)>,
+) -> Vec<(DataAddr, Section, &Data)> {
+    let mut data: Vec<_> = data
+        .iter()
+        .map(|(addr, (section, data))| (*addr, *section, data))
+        .collect();
+    data.sort_by_key(|(addr, section, _)| (*section, addr.id()));
+    data
+}
+
+/// Label data is emitted under. Function local data ids are only unique within their
+/// function, so emitters number local data across the module themselves
+#[derive(Clone, Copy)]
+pub(crate) enum DataSymbol {
+    Global(usize),
+    Local(usize),
+}
+
+impl fmt::Display for DataSymbol {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            DataSymbol::Global(id) => write!(f, "data_{id}"),
+            DataSymbol::Local(id) => write!(f, "local_data_{id}"),
+        }
+    }
+}
+
+/// Register of a backend, as far as the lowering shared between backends needs it
+pub(crate) trait MachineRegister: Copy {
+    type Number: PartialEq;
+
+    fn number(self) -> Self::Number;
+
+    fn size(self) -> Size;
+
+    /// The same register accessed with a different size
+    fn resize(self, size: Size) -> Self;
+
+    /// Register no temporary is assigned, of the class values of `size` live in
+    fn scratch(size: Size) -> Self;
+}
+
+/// Orders `(dest, src)` pairs so moving them one after the other has the same effect as
+/// moving them all at the same time, cycles are broken through the scratch register
+pub(crate) fn parallel_moves<R: MachineRegister>(mut moves: Vec<(R, R)>) -> Vec<(R, R)> {
+    let mut ordered = Vec::new();
+    moves.retain(|(dest, src)| dest.number() != src.number());
+
+    while !moves.is_empty() {
+        // A move is safe once no other pending move still reads its dest
+        let safe = moves
+            .iter()
+            .position(|(dest, _)| moves.iter().all(|(_, src)| src.number() != dest.number()));
+
+        match safe {
+            Some(index) => ordered.push(moves.remove(index)),
+            None => {
+                // Only cycles are left, break one by moving its source out of the way
+                let (_, src) = moves[0];
+                let scratch = R::scratch(src.size());
+                ordered.push((scratch, src));
+
+                for (_, pending_src) in &mut moves {
+                    if pending_src.number() == src.number() {
+                        *pending_src = scratch.resize(pending_src.size());
+                    }
+                }
+            }
+        }
+    }
+
+    ordered
+}
+
+/// Assembler directive emitting a piece of data, the same for every backend
+/// apart from the names of the integer directives
+pub(crate) enum DataDirective {
+    Label(DataSymbol),
+    Asciz(String),
+    Integers {
+        directive: &'static str,
+        size: Size,
+        values: Vec<u64>,
+    },
+    Zero(u64),
+    Align(u64),
+    Pointer {
+        symbol: String,
+        addend: i64,
+    },
+}
+
+impl fmt::Display for DataDirective {
+    #[rustfmt::skip]
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            DataDirective::Label(symbol)   => write!(f, "{symbol}:"),
+            DataDirective::Asciz(value)    => write!(f, "    .asciz {value:?}"),
+            DataDirective::Integers { directive, size, values } => {
+                // Floats are written out as their bits
+                let values = values.iter().map(|value| match size {
+                    Size::Byte                    => (*value as u8).to_string(),
+                    Size::Word                    => (*value as u16).to_string(),
+                    Size::DoubleWord | Size::F32  => (*value as u32).to_string(),
+                    Size::QuadWord | Size::F64    => value.to_string(),
+                });
+
+                write!(f, "    {directive} {}", values.collect::<Vec<_>>().join(", "))
+            }
+            DataDirective::Zero(size)      => write!(f, "    .zero {size}"),
+            DataDirective::Align(align)    => write!(f, "    .p2align {}", align.trailing_zeros()),
+            DataDirective::Pointer { symbol, addend } => match addend {
+                0                     => write!(f, "    .quad {symbol}"),
+                addend if *addend < 0 => write!(f, "    .quad {symbol}-{}", addend.unsigned_abs()),
+                addend                => write!(f, "    .quad {symbol}+{addend}"),
+            },
+        }
+    }
+}
+
+/// Emits data items, backends only pick the sections, directive names and symbols
+pub(crate) trait DataEmitter {
+    /// Section directive for data placed in `section`
+    fn section_for(&self, section: Section, data: &Data) -> &'static str;
+
+    /// Name of the directive emitting integers of `size`
+    fn integer_directive(&self, size: Size) -> &'static str;
+
+    fn data_symbol_for(&mut self, addr: DataAddr) -> DataSymbol;
+
+    fn push_section(&mut self, name: &'static str);
+
+    fn push_data(&mut self, directive: DataDirective);
+
+    /// Emits every data item under the section it belongs in
+    fn add_data_items(&mut self, data: &HashMap<DataAddr, (Section, Data)>) {
+        let mut current_section = None;
+
+        for (addr, section, data) in data_in_order(data) {
+            let section = self.section_for(section, data);
+            if current_section != Some(section) {
+                self.push_section(section);
+                current_section = Some(section);
+            }
+
+            let symbol = self.data_symbol_for(addr);
+            self.add_data(symbol, data);
+        }
+    }
+
+    fn add_data(&mut self, symbol: DataSymbol, data: &Data) {
+        // Alignment goes before the label so the label is aligned as well
+        if let Data::Aligned { align, data } = data {
+            assert!(
+                align.is_power_of_two(),
+                "Data alignment {align} is not a power of 2"
+            );
+
+            self.push_data(DataDirective::Align(*align));
+            return self.add_data(symbol, data);
+        }
+
+        self.push_data(DataDirective::Label(symbol));
+        self.add_data_contents(data);
+    }
+
+    fn add_data_contents(&mut self, data: &Data) {
+        match data {
+            Data::StringNullTerminated(value) => {
+                self.push_data(DataDirective::Asciz(value.clone()))
+            }
+            Data::Bytes(bytes) => {
+                if !bytes.is_empty() {
+                    self.push_data(DataDirective::Integers {
+                        directive: self.integer_directive(Size::Byte),
+                        size: Size::Byte,
+                        values: bytes.iter().map(|byte| *byte as u64).collect(),
+                    });
+                }
+            }
+            Data::Integers(values) => {
+                // One directive for every run of integers of the same size
+                for run in values.chunk_by(|a, b| a.size() == b.size()) {
+                    let size = run[0].size();
+                    self.push_data(DataDirective::Integers {
+                        directive: self.integer_directive(size),
+                        size,
+                        values: run.iter().map(|value| value.as_u64()).collect(),
+                    });
+                }
+            }
+            Data::Zeroed(size) => self.push_data(DataDirective::Zero(*size)),
+            Data::Aligned { align, data } => {
+                assert!(
+                    align.is_power_of_two(),
+                    "Data alignment {align} is not a power of 2"
+                );
+
+                self.push_data(DataDirective::Align(*align));
+                self.add_data_contents(data);
+            }
+            Data::Pointer { symbol, addend } => {
+                let symbol = match symbol {
+                    Symbol::Data(addr) => self.data_symbol_for(*addr).to_string(),
+                    Symbol::Function(name) => name.clone(),
+                };
+
+                self.push_data(DataDirective::Pointer {
+                    symbol,
+                    addend: *addend,
+                });
+            }
+            Data::Sequence(items) => {
+                for item in items {
+                    self.add_data_contents(item);
+                }
+            }
+        }
+    }
+}
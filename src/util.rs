@@ -1,8 +1,8 @@
 use std::collections::{HashMap, HashSet};
 
 use crate::{
-    arm64::{self, Register},
     ir,
+    target::{RegisterClass, Target},
 };
 
 #[derive(Clone, Copy)]
@@ -24,13 +24,19 @@ impl Iota {
 
 /// Register allocator using graph coloring algorithm
 pub(crate) struct RegisterAllocator {
-    edges: HashMap<ir::Temporary, Vec<ir::Temporary>>,
+    edges: HashMap<ir::Temporary, HashSet<ir::Temporary>>,
+    // Registers a temporary can not be assigned, e.g. caller saved registers while it is live across a call
+    clobbered: HashMap<ir::Temporary, HashSet<u8>>,
+    // Temporaries introduced by spilling, spilling them again would not help
+    unspillable: HashSet<ir::Temporary>,
 }
 
 impl RegisterAllocator {
     pub(crate) fn new() -> Self {
         Self {
             edges: HashMap::new(),
+            clobbered: HashMap::new(),
+            unspillable: HashSet::new(),
         }
     }
 
@@ -38,60 +44,98 @@ impl RegisterAllocator {
         self.edges.entry(from).or_default();
 
         for node in to {
-            if !self.edges.contains_key(node) {
-                self.edges.insert(*node, vec![from]);
-            } else {
-                self.edges.get_mut(node).unwrap().push(from);
-            }
-
-            self.edges.get_mut(&from).unwrap().push(*node);
+            self.edges.entry(*node).or_default().insert(from);
+            self.edges.get_mut(&from).unwrap().insert(*node);
         }
     }
 
-    fn generate_edges(&mut self, ir: &[ir::Instruction]) -> HashMap<ir::Temporary, arm64::Register> {
-        let mut alive_set = HashSet::new();
+    fn generate_edges(
+        &mut self,
+        ir: &[ir::Instruction],
+        liveness: &Liveness,
+        target: &dyn Target,
+    ) -> HashMap<ir::Temporary, u8> {
         let mut restricted_regs = HashMap::new();
 
-        for inst in ir.iter().rev() {
-            match inst {
-                ir::Instruction::Set { dest, .. } => {
-                    alive_set.remove(dest);
-                    self.add_edge(*dest, &alive_set);
-                }
-                ir::Instruction::Return { src } => {
-                    if let Some(src) = src {
-                        alive_set.insert(*src);
-                        restricted_regs.insert(*src, arm64::Register::r0(src.size()));
-                    }
-                }
-                ir::Instruction::Load { dest, .. } => {
-                    alive_set.remove(dest);
-                    self.add_edge(*dest, &alive_set);
+        for (index, inst) in ir.iter().enumerate() {
+            if let Some(dest) = inst.def() {
+                // The sources are included so dest never shares a register with them,
+                // temporaries of another class never share registers to begin with
+                let class = RegisterClass::of(dest.size());
+                let mut alive_set = liveness.live_after(index).clone();
+                // A call is done with its args by the time it writes dest
+                if !matches!(
+                    inst,
+                    ir::Instruction::Call { .. } | ir::Instruction::CallIndirect { .. }
+                ) {
+                    alive_set.extend(inst.uses());
                 }
-                ir::Instruction::Add { dest, src_1, src_2 } => {
-                    alive_set.remove(dest);
-                    alive_set.insert(*src_1);
-                    alive_set.insert(*src_2);
-                    self.add_edge(*dest, &alive_set);
+                alive_set.remove(&dest);
+                alive_set.retain(|tmp| RegisterClass::of(tmp.size()) == class);
+                self.add_edge(dest, &alive_set);
+            }
+
+            match inst {
+                ir::Instruction::Return {
+                    src: Some(src),
+                    aggregate: None,
+                } => {
+                    let class = RegisterClass::of(src.size());
+                    restricted_regs.insert(*src, target.return_register(class));
                 }
-                ir::Instruction::Store { src, .. } => {
-                    alive_set.insert(*src);
+                ir::Instruction::Call {
+                    dest,
+                    args,
+                    signature,
+                    ret_slot,
+                    ..
                 }
-                ir::Instruction::Call { args, .. } => {
-                    for (arg_num, arg) in args.iter().enumerate() {
-                        alive_set.insert(*arg);
-                        if let Some(arg_reg) = arm64::arg_register(arg_num as u8, arg.size()) {
+                | ir::Instruction::CallIndirect {
+                    dest,
+                    args,
+                    signature,
+                    ret_slot,
+                    ..
+                } => {
+                    // The address of a returned aggregate is not in a register
+                    if let (Some(dest), None) = (dest, ret_slot) {
+                        let class = RegisterClass::of(dest.size());
+                        restricted_regs.insert(*dest, target.return_register(class));
+                    }
+
+                    // Aggregates take registers of their own, so the hints stop at the first one
+                    let scalar_args = signature
+                        .arg_types(args)
+                        .iter()
+                        .take_while(|ty| matches!(ty, ir::Type::Scalar(..)))
+                        .count();
+
+                    let mut arg_nums = HashMap::new();
+                    for arg in &args[..scalar_args] {
+                        let class = RegisterClass::of(arg.size());
+                        let arg_num = arg_nums.entry(class).or_insert(0);
+                        if let Some(arg_reg) = target.arg_register(*arg_num, class) {
                             restricted_regs.insert(*arg, arg_reg);
                         }
+                        *arg_num += 1;
                     }
                 }
-                ir::Instruction::CallResult { dest } => {
-                    alive_set.remove(dest);
-                    self.add_edge(*dest, &alive_set);
-                }
-                ir::Instruction::LoadAddr { dest, .. } => {
-                    alive_set.remove(dest);
-                    self.add_edge(*dest, &alive_set);
+                _ => {}
+            }
+
+            // e.g. the callee of a call is free to overwrite caller saved registers. The dest
+            // is only written once they are, so it can still be assigned one of them
+            let clobbered_regs = target.clobbered_registers(inst.kind());
+            if !clobbered_regs.is_empty() {
+                for tmp in liveness.live_after(index) {
+                    if Some(*tmp) == inst.def() {
+                        continue;
+                    }
+
+                    self.clobbered
+                        .entry(*tmp)
+                        .or_default()
+                        .extend(clobbered_regs.iter().copied());
                 }
             }
         }
@@ -99,61 +143,669 @@ impl RegisterAllocator {
         restricted_regs
     }
 
+    /// Estimated cost of keeping each temporary on the stack, every use and def inside
+    /// a loop counts ten times as much as one outside of it
+    fn spill_costs(
+        &self,
+        ir: &[ir::Instruction],
+        liveness: &Liveness,
+    ) -> HashMap<ir::Temporary, f64> {
+        let mut spill_costs = HashMap::new();
+        let loop_depths = liveness.cfg().loop_depths();
+
+        for (block, info) in liveness.cfg().blocks().iter().enumerate() {
+            let weight = 10f64.powi(loop_depths[block] as i32);
+
+            for inst in &ir[info.range()] {
+                for tmp in inst.uses().into_iter().chain(inst.def()) {
+                    *spill_costs.entry(tmp).or_insert(0.0) += weight;
+                }
+            }
+        }
+
+        for tmp in &self.unspillable {
+            spill_costs.insert(*tmp, f64::INFINITY);
+        }
+
+        spill_costs
+    }
+
+    /// Colors the interference graph, returns the temporaries that have to be spilled on failure
     fn allocate_registers(
-        self,
-        regs: Vec<arm64::RegisterNumber>,
-        restricted_regs: HashMap<ir::Temporary, arm64::Register>,
-    ) -> HashMap<ir::Temporary, arm64::Register> {
-        let mut reg_map: HashMap<ir::Temporary, arm64::Register> = HashMap::new();
+        &self,
+        regs: &HashMap<RegisterClass, Vec<u8>>,
+        restricted_regs: &HashMap<ir::Temporary, u8>,
+        spill_costs: &HashMap<ir::Temporary, f64>,
+    ) -> Result<HashMap<ir::Temporary, u8>, HashSet<ir::Temporary>> {
+        let mut degrees: HashMap<ir::Temporary, usize> = self
+            .edges
+            .iter()
+            .map(|(tmp, connected_tmps)| (*tmp, connected_tmps.len()))
+            .collect();
+
+        // Number of registers each temporary could be assigned
+        let colors: HashMap<ir::Temporary, usize> = self
+            .edges
+            .keys()
+            .map(|tmp| {
+                let count = regs[&RegisterClass::of(tmp.size())]
+                    .iter()
+                    .filter(|reg| !self.is_clobbered(*tmp, **reg))
+                    .count();
+                (*tmp, count)
+            })
+            .collect();
+
+        let mut remaining: Vec<ir::Temporary> = self.edges.keys().copied().collect();
+        remaining.sort_by_key(|tmp| tmp.id());
+
+        // Simplify: remove temporaries that can surely be colored, when there are none left
+        // optimistically remove the cheapest spill candidate
+        let mut stack = Vec::with_capacity(remaining.len());
+        while !remaining.is_empty() {
+            let index = match remaining.iter().position(|tmp| degrees[tmp] < colors[tmp]) {
+                Some(index) => index,
+                None => {
+                    let metric = |tmp: &ir::Temporary| {
+                        spill_costs.get(tmp).copied().unwrap_or(0.0) / degrees[tmp] as f64
+                    };
+
+                    remaining
+                        .iter()
+                        .enumerate()
+                        .min_by(|(_, a), (_, b)| metric(a).total_cmp(&metric(b)))
+                        .map(|(index, _)| index)
+                        .unwrap()
+                }
+            };
+
+            let tmp = remaining.remove(index);
+            for connected_tmp in &self.edges[&tmp] {
+                if let Some(degree) = degrees.get_mut(connected_tmp) {
+                    *degree = degree.saturating_sub(1);
+                }
+            }
+            stack.push(tmp);
+        }
+
+        // Select: assign registers in reverse order of removal
+        let mut reg_map: HashMap<ir::Temporary, u8> = HashMap::new();
+        let mut spilled = HashSet::new();
+
+        while let Some(tmp) = stack.pop() {
+            let connected_tmps = &self.edges[&tmp];
+            let is_free = |reg: u8| {
+                !self.is_clobbered(tmp, reg)
+                    && connected_tmps.iter().all(|connected_tmp| {
+                        reg_map
+                            .get(connected_tmp)
+                            .is_none_or(|allocated_reg| *allocated_reg != reg)
+                    })
+            };
 
-        for (tmp, connected_tmps) in self.edges {
             if let Some(restricted_reg) = restricted_regs.get(&tmp) {
-                // Check if we can assign the restricted register
-                let mut can_assign = true;
-                for connected_tmp in &connected_tmps {
-                    if let Some(allocated_reg) = reg_map.get(connected_tmp) {
-                        if allocated_reg.number() == restricted_reg.number() {
-                            can_assign = false;
-                            break;
-                        }
+                // If we can assign the restricted register, then assign and move on
+                if is_free(*restricted_reg) {
+                    reg_map.insert(tmp, *restricted_reg);
+                    continue;
+                }
+            }
+
+            let regs = &regs[&RegisterClass::of(tmp.size())];
+            match regs.iter().find(|reg| is_free(**reg)) {
+                Some(reg) => {
+                    reg_map.insert(tmp, *reg);
+                }
+                None => {
+                    spilled.insert(tmp);
+                }
+            }
+        }
+
+        if spilled.is_empty() {
+            Ok(reg_map)
+        } else {
+            Err(spilled)
+        }
+    }
+
+    fn is_clobbered(&self, tmp: ir::Temporary, reg: u8) -> bool {
+        self.clobbered
+            .get(&tmp)
+            .is_some_and(|clobbered| clobbered.contains(&reg))
+    }
+
+    /// Allocates registers for every temporary of `func`, spilling temporaries to the stack
+    /// until the remaining ones fit in the registers of `target`
+    pub(crate) fn allocate(
+        mut self,
+        func: &mut ir::Function,
+        target: &dyn Target,
+    ) -> HashMap<ir::Temporary, u8> {
+        let regs: HashMap<RegisterClass, Vec<u8>> = [RegisterClass::Integer, RegisterClass::Float]
+            .into_iter()
+            .map(|class| (class, target.allocatable_registers(class)))
+            .collect();
+
+        loop {
+            let liveness = Liveness::analyze(func.instructions());
+            // Temporaries are only ever defined by instructions, so none are live on entry
+            assert!(
+                liveness.cfg().blocks().is_empty() || liveness.live_in(0).is_empty(),
+                "Temporary used before it is defined in {}",
+                func.name()
+            );
+
+            self.edges.clear();
+            self.clobbered.clear();
+            let restricted_regs = self.generate_edges(func.instructions(), &liveness, target);
+            let spill_costs = self.spill_costs(func.instructions(), &liveness);
+
+            match self.allocate_registers(&regs, &restricted_regs, &spill_costs) {
+                Ok(reg_map) => return reg_map,
+                Err(spilled) => {
+                    let spilled: HashSet<ir::Temporary> =
+                        spilled.difference(&self.unspillable).copied().collect();
+
+                    if spilled.is_empty() {
+                        panic!("Failed to allocate registers");
                     }
+
+                    let reloads = func.spill(&spilled);
+                    self.unspillable.extend(reloads);
                 }
+            }
+        }
+    }
+}
 
-                // If we can assign, then assign and move on
-                if can_assign {
-                    reg_map.insert(tmp, *restricted_reg);
+/// Basic block, spanning the instructions `start..end` of a function
+pub(crate) struct Block {
+    start: usize,
+    end: usize,
+    successors: Vec<usize>,
+}
+
+impl Block {
+    pub(crate) fn range(&self) -> std::ops::Range<usize> {
+        self.start..self.end
+    }
+
+    pub(crate) fn successors(&self) -> &[usize] {
+        &self.successors
+    }
+}
+
+/// Control flow graph over the basic blocks of a function
+pub(crate) struct Cfg {
+    blocks: Vec<Block>,
+}
+
+impl Cfg {
+    pub(crate) fn new(ir: &[ir::Instruction]) -> Self {
+        // A block starts at every label and after every terminator
+        let mut starts = Vec::new();
+        for (index, inst) in ir.iter().enumerate() {
+            let is_leader = index == 0 || matches!(inst, ir::Instruction::Label { .. });
+            let follows_terminator = index > 0 && ir[index - 1].successors().is_some();
+
+            if is_leader || follows_terminator {
+                starts.push(index);
+            }
+        }
+
+        let mut label_blocks = HashMap::new();
+        for (block, start) in starts.iter().enumerate() {
+            if let ir::Instruction::Label { label } = &ir[*start] {
+                label_blocks.insert(*label, block);
+            }
+        }
+
+        let mut blocks = Vec::with_capacity(starts.len());
+        for (block, start) in starts.iter().enumerate() {
+            let end = starts.get(block + 1).copied().unwrap_or(ir.len());
+
+            let successors = match ir[end - 1].successors() {
+                Some(labels) => labels
+                    .iter()
+                    .map(|label| {
+                        *label_blocks
+                            .get(label)
+                            .expect("Branch to a label that is never placed")
+                    })
+                    .collect(),
+                // Fall through to the next block
+                None if block + 1 < starts.len() => vec![block + 1],
+                None => Vec::new(),
+            };
+
+            blocks.push(Block {
+                start: *start,
+                end,
+                successors,
+            });
+        }
+
+        Self { blocks }
+    }
+
+    pub(crate) fn blocks(&self) -> &[Block] {
+        &self.blocks
+    }
+
+    /// Number of loops each block is nested in. Every branch back to a block that can reach
+    /// the branch again closes a loop, made up of the blocks on the paths between the two
+    pub(crate) fn loop_depths(&self) -> Vec<usize> {
+        let mut predecessors = vec![Vec::new(); self.blocks.len()];
+        for (block, info) in self.blocks.iter().enumerate() {
+            for successor in info.successors() {
+                predecessors[*successor].push(block);
+            }
+        }
+
+        let mut loop_depths = vec![0; self.blocks.len()];
+
+        for (block, info) in self.blocks.iter().enumerate() {
+            for header in info.successors().iter().filter(|header| **header <= block) {
+                // A block placed earlier is not necessarily a loop header, e.g. a shared
+                // exit block jumped back to from code laid out after it
+                let reached = reachable(*header, |block| &self.blocks[block].successors);
+                if !reached.contains(&block) {
                     continue;
                 }
+
+                // Walking back from the branch stops at the header, so only the loop is found
+                let reaching = reachable(block, |block| {
+                    if block == *header {
+                        &[]
+                    } else {
+                        &predecessors[block]
+                    }
+                });
+                for body in reached.intersection(&reaching) {
+                    loop_depths[*body] += 1;
+                }
             }
+        }
 
-            'outer: for reg in &regs {
-                for connected_tmp in &connected_tmps {
-                    if let Some(allocated_reg) = reg_map.get(connected_tmp) {
-                        if allocated_reg.number() == *reg {
-                            continue 'outer;
-                        }
+        loop_depths
+    }
+}
+
+/// Blocks reachable from `start` by following `next`, including `start` itself
+fn reachable<'a>(start: usize, next: impl Fn(usize) -> &'a [usize]) -> HashSet<usize> {
+    let mut reached = HashSet::from([start]);
+    let mut pending = vec![start];
+
+    while let Some(block) = pending.pop() {
+        for next_block in next(block) {
+            if reached.insert(*next_block) {
+                pending.push(*next_block);
+            }
+        }
+    }
+
+    reached
+}
+
+/// Live temporaries of a function, computed with iterative dataflow analysis over its `Cfg`
+pub(crate) struct Liveness {
+    cfg: Cfg,
+    live_in: Vec<HashSet<ir::Temporary>>,
+    live_out: Vec<HashSet<ir::Temporary>>,
+    live_after: Vec<HashSet<ir::Temporary>>,
+}
+
+impl Liveness {
+    pub(crate) fn analyze(ir: &[ir::Instruction]) -> Self {
+        let cfg = Cfg::new(ir);
+        let blocks = cfg.blocks();
+
+        // Temporaries used before being defined, and temporaries defined, in each block
+        let mut uses = vec![HashSet::new(); blocks.len()];
+        let mut defs = vec![HashSet::new(); blocks.len()];
+        for (block, info) in blocks.iter().enumerate() {
+            for inst in &ir[info.range()] {
+                for tmp in inst.uses() {
+                    if !defs[block].contains(&tmp) {
+                        uses[block].insert(tmp);
                     }
                 }
 
-                reg_map.insert(tmp, Register::new(*reg, tmp.size()));
-                break;
+                if let Some(dest) = inst.def() {
+                    defs[block].insert(dest);
+                }
             }
+        }
 
-            if !reg_map.contains_key(&tmp) {
-                panic!("Failed to allocate registers");
+        let mut live_in: Vec<HashSet<ir::Temporary>> = vec![HashSet::new(); blocks.len()];
+        let mut live_out: Vec<HashSet<ir::Temporary>> = vec![HashSet::new(); blocks.len()];
+
+        let mut changed = true;
+        while changed {
+            changed = false;
+
+            for block in (0..blocks.len()).rev() {
+                let mut out = HashSet::new();
+                for successor in blocks[block].successors() {
+                    out.extend(live_in[*successor].iter().copied());
+                }
+
+                let mut in_ = uses[block].clone();
+                in_.extend(out.difference(&defs[block]).copied());
+
+                if in_ != live_in[block] || out != live_out[block] {
+                    live_in[block] = in_;
+                    live_out[block] = out;
+                    changed = true;
+                }
             }
         }
 
-        reg_map
+        let mut liveness = Self {
+            cfg,
+            live_in,
+            live_out,
+            live_after: vec![HashSet::new(); ir.len()],
+        };
+
+        // Walk each block backwards to find what is live after every instruction
+        for (block, info) in liveness.cfg.blocks().iter().enumerate() {
+            let mut alive_set = liveness.live_out(block).clone();
+
+            for index in info.range().rev() {
+                liveness.live_after[index] = alive_set.clone();
+
+                if let Some(dest) = ir[index].def() {
+                    alive_set.remove(&dest);
+                }
+                alive_set.extend(ir[index].uses());
+            }
+        }
+
+        liveness
     }
 
-    pub(crate) fn allocate(
-        mut self,
-        ir: &[ir::Instruction],
-        regs: Vec<arm64::RegisterNumber>,
-    ) -> HashMap<ir::Temporary, arm64::Register> {
-        let restricted_regs = self.generate_edges(ir);
+    pub(crate) fn cfg(&self) -> &Cfg {
+        &self.cfg
+    }
+
+    pub(crate) fn live_in(&self, block: usize) -> &HashSet<ir::Temporary> {
+        &self.live_in[block]
+    }
+
+    pub(crate) fn live_out(&self, block: usize) -> &HashSet<ir::Temporary> {
+        &self.live_out[block]
+    }
+
+    /// Temporaries that are live right after the instruction at `index`
+    pub(crate) fn live_after(&self, index: usize) -> &HashSet<ir::Temporary> {
+        &self.live_after[index]
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        ir::{Function, Value},
+        target::Emitter,
+    };
+
+    /// Target with only `registers` integer registers, numbered from 0
+    struct Registers(u8);
+
+    impl Target for Registers {
+        fn allocatable_registers(&self, class: RegisterClass) -> Vec<u8> {
+            match class {
+                RegisterClass::Integer => (0..self.0).collect(),
+                RegisterClass::Float => Vec::new(),
+            }
+        }
+
+        fn arg_register(&self, _arg_num: usize, _class: RegisterClass) -> Option<u8> {
+            None
+        }
+
+        fn return_register(&self, _class: RegisterClass) -> u8 {
+            0
+        }
+
+        fn clobbered_registers(&self, _kind: ir::InstructionKind) -> Vec<u8> {
+            Vec::new()
+        }
+
+        fn callee_saved_registers(&self) -> Vec<u8> {
+            Vec::new()
+        }
+
+        fn emitter(&self) -> Box<dyn Emitter> {
+            unreachable!()
+        }
+    }
+
+    /// Sorted ids of `tmps`, as temporaries can not be printed
+    fn ids<'a>(tmps: impl IntoIterator<Item = &'a ir::Temporary>) -> Vec<usize> {
+        let mut ids: Vec<_> = tmps.into_iter().map(|tmp| tmp.id()).collect();
+        ids.sort();
+        ids
+    }
+
+    #[test]
+    fn straight_line() {
+        let mut func = Function::new("straight_line".to_string());
+        let a = func.add_inst_set(Value::I32(1));
+        let b = func.add_inst_set(Value::I32(2));
+        let c = func.add_inst_add(a, b);
+        func.add_inst_return(Some(c));
+
+        let liveness = Liveness::analyze(func.instructions());
+        assert_eq!(liveness.cfg().blocks().len(), 1);
+        assert!(liveness.live_in(0).is_empty());
+        assert!(liveness.live_out(0).is_empty());
+
+        assert_eq!(ids(liveness.live_after(0)), ids(&[a]));
+        assert_eq!(ids(liveness.live_after(1)), ids(&[a, b]));
+        assert_eq!(ids(liveness.live_after(2)), ids(&[c]));
+        assert!(liveness.live_after(3).is_empty());
+        assert_eq!(liveness.cfg().loop_depths(), [0]);
+    }
+
+    #[test]
+    fn if_else() {
+        let mut func = Function::new("if_else".to_string());
+        let then_label = func.add_label();
+        let else_label = func.add_label();
+        let end_label = func.add_label();
+
+        let a = func.add_inst_set(Value::I32(1));
+        let b = func.add_inst_set(Value::I32(2));
+        let cond = func.add_inst_slt(a, b);
+        func.add_inst_branch_if(cond, then_label, else_label);
+
+        func.add_block(then_label);
+        let sum = func.add_inst_add(a, b);
+        func.add_inst_return(Some(sum));
+
+        func.add_block(else_label);
+        func.add_inst_jump(end_label);
+
+        func.add_block(end_label);
+        func.add_inst_return(Some(b));
+
+        let liveness = Liveness::analyze(func.instructions());
+        let successors: Vec<_> = liveness
+            .cfg()
+            .blocks()
+            .iter()
+            .map(|block| block.successors().to_vec())
+            .collect();
+        assert_eq!(successors, [vec![1, 2], vec![], vec![3], vec![]]);
+
+        assert!(liveness.live_in(0).is_empty());
+        assert_eq!(ids(liveness.live_out(0)), ids(&[a, b]));
+        assert_eq!(ids(liveness.live_in(1)), ids(&[a, b]));
+        assert!(liveness.live_out(1).is_empty());
+        assert_eq!(ids(liveness.live_in(2)), ids(&[b]));
+        assert_eq!(ids(liveness.live_out(2)), ids(&[b]));
+        assert_eq!(ids(liveness.live_in(3)), ids(&[b]));
+        assert!(liveness.live_out(3).is_empty());
+        assert_eq!(liveness.cfg().loop_depths(), [0, 0, 0, 0]);
+    }
+
+    #[test]
+    fn nested_loops() {
+        let mut func = Function::new("nested_loops".to_string());
+        let outer_label = func.add_label();
+        let inner_label = func.add_label();
+        let latch_label = func.add_label();
+        let exit_label = func.add_label();
+
+        let n = func.add_inst_set(Value::I32(10));
+        func.add_inst_jump(outer_label);
+
+        func.add_block(outer_label);
+        func.add_inst_branch_if(n, inner_label, exit_label);
+
+        func.add_block(inner_label);
+        let m = func.add_inst_add(n, n);
+        func.add_inst_branch_if(n, inner_label, latch_label);
+
+        func.add_block(latch_label);
+        func.add_inst_store(m);
+        func.add_inst_jump(outer_label);
+
+        func.add_block(exit_label);
+        func.add_inst_return(Some(n));
+
+        let liveness = Liveness::analyze(func.instructions());
+        assert_eq!(liveness.cfg().blocks().len(), 5);
+
+        assert!(liveness.live_in(0).is_empty());
+        assert_eq!(ids(liveness.live_out(0)), ids(&[n]));
+        assert_eq!(ids(liveness.live_in(1)), ids(&[n]));
+        assert_eq!(ids(liveness.live_out(1)), ids(&[n]));
+        // m is redefined before every use in the inner block, but the latch reads it
+        assert_eq!(ids(liveness.live_in(2)), ids(&[n]));
+        assert_eq!(ids(liveness.live_out(2)), ids(&[n, m]));
+        assert_eq!(ids(liveness.live_in(3)), ids(&[n, m]));
+        assert_eq!(ids(liveness.live_out(3)), ids(&[n]));
+        assert_eq!(ids(liveness.live_in(4)), ids(&[n]));
+        assert!(liveness.live_out(4).is_empty());
+
+        assert_eq!(liveness.cfg().loop_depths(), [0, 1, 2, 1, 0]);
+    }
+
+    #[test]
+    fn backward_jump_without_loop() {
+        let mut func = Function::new("backward_jump".to_string());
+        let exit_label = func.add_label();
+        let body_label = func.add_label();
+
+        let a = func.add_inst_set(Value::I32(1));
+        func.add_inst_jump(body_label);
+
+        // Placed before the block jumping to it, but never reaches that block again
+        func.add_block(exit_label);
+        func.add_inst_return(Some(a));
+
+        func.add_block(body_label);
+        func.add_inst_jump(exit_label);
+
+        let liveness = Liveness::analyze(func.instructions());
+        assert_eq!(ids(liveness.live_in(2)), ids(&[a]));
+        assert_eq!(liveness.cfg().loop_depths(), [0, 0, 0]);
+    }
+
+    #[test]
+    #[should_panic(expected = "Temporary used before it is defined")]
+    fn use_before_def() {
+        let mut func = Function::new("use_before_def".to_string());
+        let body_label = func.add_label();
+        let exit_label = func.add_label();
+        func.add_inst_jump(body_label);
+
+        func.add_block(exit_label);
+        let a = func.add_inst_set(Value::I32(1));
+        func.add_inst_return(Some(a));
+
+        func.add_block(body_label);
+        let b = func.add_inst_add(a, a);
+        func.add_inst_return(Some(b));
+
+        RegisterAllocator::new().allocate(&mut func, &Registers(4));
+    }
+
+    #[test]
+    fn spills_when_out_of_registers() {
+        let mut func = Function::new("spill".to_string());
+        let a = func.add_inst_set(Value::I64(1));
+        let b = func.add_inst_set(Value::I64(2));
+        let c = func.add_inst_set(Value::I64(3));
+        let d = func.add_inst_set(Value::I64(4));
+        let sum = func.add_inst_add(a, b);
+        let sum = func.add_inst_add(sum, c);
+        let sum = func.add_inst_add(sum, d);
+        func.add_inst_return(Some(sum));
+        let original = func.instructions().len();
+
+        // A dest never shares a register with its sources, so an add alone takes all three
+        let reg_map = RegisterAllocator::new().allocate(&mut func, &Registers(3));
+        let ir = func.instructions();
+        assert!(ir.len() > original);
+
+        // Every def of a spilled temporary is stored right away, and every reload is
+        // read by the first instruction after the reloads
+        let mut stores = 0;
+        let mut reloads = 0;
+        for (index, inst) in ir.iter().enumerate() {
+            match inst {
+                ir::Instruction::Store { src, .. } => {
+                    assert!(ir[index - 1].def() == Some(*src));
+                    stores += 1;
+                }
+                ir::Instruction::Load { dest, .. } => {
+                    assert!(ir[index + 1..]
+                        .iter()
+                        .find(|inst| !matches!(inst, ir::Instruction::Load { .. }))
+                        .is_some_and(|inst| inst.uses().contains(dest)));
+                    reloads += 1;
+                }
+                _ => {}
+            }
+        }
+        assert!(stores > 0);
+        assert_eq!(stores, reloads);
+
+        // No two temporaries live at the same time share a register
+        let liveness = Liveness::analyze(ir);
+        for (index, inst) in ir.iter().enumerate() {
+            for tmp in inst.uses().into_iter().chain(inst.def()) {
+                assert!(reg_map.contains_key(&tmp));
+            }
+
+            if let Some(dest) = inst.def() {
+                for live in liveness.live_after(index) {
+                    if *live != dest {
+                        assert_ne!(reg_map[live], reg_map[&dest]);
+                    }
+                }
+            }
+        }
+    }
+
+    #[test]
+    #[should_panic(expected = "Failed to allocate registers")]
+    fn fails_with_only_unspillable_temporaries() {
+        // The add needs a register for each reload and one for its dest, and reloads
+        // are never spilled again
+        let mut func = Function::new("unspillable".to_string());
+        let a = func.add_inst_set(Value::I64(1));
+        let b = func.add_inst_set(Value::I64(2));
+        let sum = func.add_inst_add(a, b);
+        func.add_inst_store(sum);
+        func.add_inst_return(None);
 
-        self.allocate_registers(regs, restricted_regs)
+        RegisterAllocator::new().allocate(&mut func, &Registers(2));
     }
 }
@@ -0,0 +1,2253 @@
+use core::fmt;
+use std::collections::HashMap;
+
+use crate::{
+    ir::{self, Data, DataAddr, InstructionKind, Section, Size},
+    target::{
+        self, DataDirective, DataEmitter, DataSymbol, Emitter, FrameLayout, MachineRegister,
+        RegisterClass, Target,
+    },
+    util::Iota,
+};
+
+/// The x86_64 target, for the System V ABI in GAS Intel syntax
+pub struct X86_64;
+
+impl Target for X86_64 {
+    fn allocatable_registers(&self, class: RegisterClass) -> Vec<u8> {
+        let registers = match class {
+            RegisterClass::Integer => usable_registers(),
+            RegisterClass::Float => usable_float_registers(),
+        };
+
+        registers.into_iter().map(|number| number as u8).collect()
+    }
+
+    fn arg_register(&self, arg_num: usize, class: RegisterClass) -> Option<u8> {
+        let size = match class {
+            RegisterClass::Integer => Size::QuadWord,
+            RegisterClass::Float => Size::F64,
+        };
+
+        arg_register(arg_num, size).map(|reg| reg.number() as u8)
+    }
+
+    fn return_register(&self, class: RegisterClass) -> u8 {
+        Register::result(match class {
+            RegisterClass::Integer => Size::QuadWord,
+            RegisterClass::Float => Size::F64,
+        })
+        .number() as u8
+    }
+
+    fn clobbered_registers(&self, kind: InstructionKind) -> Vec<u8> {
+        use RegisterNumber::*;
+
+        let clobbered = match kind {
+            InstructionKind::Call => caller_saved_registers(),
+            // Division uses rdx:rax
+            InstructionKind::Division => vec![RAX, RDX],
+            // Shift amount goes in cl
+            InstructionKind::Shift => vec![RCX],
+            InstructionKind::Other => Vec::new(),
+        };
+
+        clobbered.into_iter().map(|number| number as u8).collect()
+    }
+
+    fn callee_saved_registers(&self) -> Vec<u8> {
+        callee_saved_registers()
+            .into_iter()
+            .map(|number| number as u8)
+            .collect()
+    }
+
+    fn emitter(&self) -> Box<dyn Emitter> {
+        Box::new(Asm::new())
+    }
+}
+
+struct Asm {
+    instructions: Vec<Instruction>,
+    lbl_iota: Iota,
+    // Labels of the function currently being generated
+    func_labels: HashMap<ir::Label, Label>,
+    local_data_iota: Iota,
+    // Local data of the function currently being generated
+    func_local_data: HashMap<DataAddr, DataSymbol>,
+    // The comparison whose flags are still set, used to branch with jcc
+    last_cmp: Option<(ir::Temporary, ir::Condition)>,
+}
+
+impl Emitter for Asm {
+    fn generate_function(
+        &mut self,
+        func: &ir::Function,
+        registers: &HashMap<ir::Temporary, u8>,
+        layout: &FrameLayout,
+    ) {
+        assert!(
+            !func.has_aggregates(),
+            "Function {} passes aggregates by value, which is only supported on arm64",
+            func.name()
+        );
+
+        let reg_map: HashMap<ir::Temporary, Register> = registers
+            .iter()
+            .map(|(tmp, reg)| {
+                (
+                    *tmp,
+                    Register::new(RegisterNumber::from_u8(*reg), tmp.size()),
+                )
+            })
+            .collect();
+
+        let offsets = &layout.slot_offsets;
+        let frame = Frame::new(func, layout);
+        let return_label = Label::new(self.lbl_iota.next());
+        self.func_labels.clear();
+        self.func_local_data.clear();
+
+        // func prologue
+        self.generate_func_prologue(func, &frame, offsets);
+
+        for inst in func.instructions() {
+            self.add_inst(inst, &frame, &reg_map, offsets, return_label);
+        }
+
+        // func epilogue
+        self.generate_func_epilogue(func, &frame, return_label);
+
+        // Spacing between functions
+        self.instructions.push(Instruction::Empty);
+    }
+
+    fn generate_data(&mut self, data: &HashMap<DataAddr, (Section, Data)>) {
+        self.add_data_items(data);
+    }
+
+    fn finish(mut self: Box<Self>) -> target::Asm {
+        // Mark the stack as non executable
+        self.instructions.push(Instruction::custom(
+            ".section .note.GNU-stack,\"\",@progbits".to_string(),
+        ));
+
+        target::Asm::new(
+            self.instructions
+                .iter()
+                .map(|inst| inst.to_string())
+                .collect(),
+        )
+    }
+}
+
+impl DataEmitter for Asm {
+    fn section_for(&self, section: Section, data: &Data) -> &'static str {
+        match section {
+            // Made read-only after the loader relocates the pointers
+            Section::ReadOnly if data.has_pointers() => ".section .data.rel.ro,\"aw\"",
+            Section::ReadOnly => ".section .rodata",
+            Section::Mutable => ".data",
+            Section::Uninitialized => ".bss",
+        }
+    }
+
+    #[rustfmt::skip]
+    fn integer_directive(&self, size: Size) -> &'static str {
+        match size {
+            Size::Byte                   => ".byte",
+            Size::Word                   => ".short",
+            Size::DoubleWord | Size::F32 => ".long",
+            Size::QuadWord | Size::F64   => ".quad",
+        }
+    }
+
+    fn data_symbol_for(&mut self, addr: DataAddr) -> DataSymbol {
+        if addr.is_global() {
+            return DataSymbol::Global(addr.id());
+        }
+
+        *self
+            .func_local_data
+            .entry(addr)
+            .or_insert_with(|| DataSymbol::Local(self.local_data_iota.next()))
+    }
+
+    fn push_section(&mut self, name: &'static str) {
+        self.instructions.push(Instruction::section(name));
+    }
+
+    fn push_data(&mut self, directive: DataDirective) {
+        self.instructions.push(Instruction::Data { directive });
+    }
+}
+
+impl Asm {
+    fn new() -> Self {
+        Self {
+            instructions: vec![
+                Instruction::custom(".intel_syntax noprefix".to_string()),
+                Instruction::section(".text"),
+                Instruction::Empty,
+            ],
+            lbl_iota: Iota::new(),
+            func_labels: HashMap::new(),
+            local_data_iota: Iota::new(),
+            func_local_data: HashMap::new(),
+            last_cmp: None,
+        }
+    }
+
+    fn generate_func_prologue(
+        &mut self,
+        func: &ir::Function,
+        frame: &Frame,
+        stack_slot_offsets: &HashMap<ir::StackSlot, u32>,
+    ) {
+        // .global func_name
+        if func.is_public() {
+            self.instructions
+                .push(Instruction::custom(format!(".global {}", func.name())));
+        }
+
+        // func_name:
+        self.instructions
+            .push(Instruction::custom(format!("{}:", func.name())));
+
+        // push rbp
+        // mov rbp, rsp
+        self.instructions.push(Instruction::push(Register::rbp()));
+        self.instructions
+            .push(Instruction::mov(Register::rbp(), Register::rsp()).unwrap());
+
+        // Store callee saved registers
+        for reg in frame.saved_regs() {
+            self.instructions.push(Instruction::push(*reg));
+        }
+
+        // sub rsp, stack size
+        if frame.size() != 0 {
+            self.instructions
+                .push(Instruction::sub_imm(Register::rsp(), frame.size()));
+        }
+
+        // Save the arg registers for va_arg, the ones holding named args are saved as well
+        if let Some(offset) = frame.va_save_offset() {
+            for arg_num in 0..ARG_REGISTERS.len() {
+                let reg = arg_register(arg_num, Size::QuadWord).unwrap();
+                let reg_offset = offset + arg_num as i32 * 8;
+                self.instructions
+                    .push(Instruction::store(reg, Register::rsp(), reg_offset));
+            }
+
+            // Each xmm register gets 16 bytes, of which the low 8 hold the double
+            for arg_num in 0..FLOAT_ARG_REGISTERS.len() {
+                let reg = arg_register(arg_num, Size::F64).unwrap();
+                let reg_offset = offset + 48 + arg_num as i32 * 16;
+                self.instructions
+                    .push(Instruction::store(reg, Register::rsp(), reg_offset));
+            }
+        }
+
+        // Store args in stack slots
+        for (arg, arg_reg) in func
+            .args()
+            .iter()
+            .zip(arg_locations(func.args().iter().map(|arg| arg.size())))
+        {
+            let offset = stack_slot_offsets.get(arg).unwrap();
+            match arg_reg {
+                Ok(arg_reg) => {
+                    self.instructions.push(Instruction::store(
+                        arg_reg,
+                        Register::rsp(),
+                        *offset as i32,
+                    ));
+                }
+                Err(stack_index) => {
+                    // Stack args start above the saved rbp and the return address
+                    let arg_offset = 16 + stack_index as i32 * 8;
+                    let scratch = Register::scratch(arg.size());
+
+                    self.instructions
+                        .push(Instruction::load(scratch, Register::rbp(), arg_offset));
+
+                    self.instructions.push(Instruction::store(
+                        scratch,
+                        Register::rsp(),
+                        *offset as i32,
+                    ));
+                }
+            }
+        }
+    }
+
+    fn generate_func_epilogue(&mut self, func: &ir::Function, frame: &Frame, return_label: Label) {
+        // return_label:
+        self.instructions.push(Instruction::label(return_label));
+
+        // add rsp, stack size
+        if frame.size() != 0 {
+            self.instructions
+                .push(Instruction::add_imm(Register::rsp(), frame.size()));
+        }
+
+        // Load callee saved registers
+        for reg in frame.saved_regs().iter().rev() {
+            self.instructions.push(Instruction::pop(*reg));
+        }
+
+        // pop rbp
+        self.instructions.push(Instruction::pop(Register::rbp()));
+
+        // ret
+        self.instructions.push(Instruction::ret());
+
+        // Empty Spacer
+        self.instructions.push(Instruction::Empty);
+
+        // Function local Data
+        if !func.data().is_empty() {
+            self.add_data_items(func.data());
+            self.instructions.push(Instruction::section(".text"));
+        }
+    }
+
+    fn add_inst(
+        &mut self,
+        inst: &ir::Instruction,
+        frame: &Frame,
+        reg_map: &HashMap<ir::Temporary, Register>,
+        stack_slot_offsets: &HashMap<ir::StackSlot, u32>,
+        return_label: Label,
+    ) {
+        let last_cmp = self.last_cmp.take();
+
+        match inst {
+            ir::Instruction::Set { dest, src } => {
+                let reg = reg_map.get(dest).unwrap();
+
+                if reg.is_float() {
+                    // Floats are built in r11 and then moved over bit for bit
+                    let scratch = Register::r11(match reg.size() {
+                        Size::F32 => Size::DoubleWord,
+                        _ => Size::QuadWord,
+                    });
+                    self.instructions
+                        .push(Instruction::mov_imm(scratch, src.as_u64()));
+                    self.instructions.push(Instruction::MovReg {
+                        dest: *reg,
+                        src: scratch,
+                    });
+                } else {
+                    let inst = Instruction::mov_imm(*reg, src.as_u64());
+                    self.instructions.push(inst);
+                }
+            }
+            ir::Instruction::Load { dest, src } => {
+                let dest_reg = reg_map.get(dest).unwrap();
+                let offset = stack_slot_offsets.get(src).unwrap();
+
+                let inst = Instruction::load(*dest_reg, Register::rsp(), *offset as i32);
+                self.instructions.push(inst);
+            }
+            ir::Instruction::Store { dest, src } => {
+                let src_reg = reg_map.get(src).unwrap();
+                let offset = stack_slot_offsets.get(dest).unwrap();
+
+                let inst = Instruction::store(*src_reg, Register::rsp(), *offset as i32);
+                self.instructions.push(inst);
+            }
+            ir::Instruction::SlotAddr { dest, slot } => {
+                let dest_reg = reg_map.get(dest).unwrap();
+                let offset = stack_slot_offsets.get(slot).unwrap();
+
+                let inst = Instruction::lea_mem(*dest_reg, Register::rsp(), *offset as i32);
+                self.instructions.push(inst);
+            }
+            ir::Instruction::LoadPtr { dest, addr, offset } => {
+                let dest_reg = *reg_map.get(dest).unwrap();
+                let addr_reg = *reg_map.get(addr).unwrap();
+
+                let inst = match *offset {
+                    ir::PtrOffset::Imm(offset) => Instruction::load(dest_reg, addr_reg, offset),
+                    ir::PtrOffset::Index { index, scaled } => {
+                        let index_reg = self.index_register(index, reg_map);
+                        let scale = if scaled {
+                            dest.size().in_bytes() as u8
+                        } else {
+                            1
+                        };
+                        Instruction::load_index(dest_reg, addr_reg, index_reg, scale)
+                    }
+                };
+                self.instructions.push(inst);
+            }
+            ir::Instruction::StorePtr { addr, src, offset } => {
+                let src_reg = *reg_map.get(src).unwrap();
+                let addr_reg = *reg_map.get(addr).unwrap();
+
+                let inst = match *offset {
+                    ir::PtrOffset::Imm(offset) => Instruction::store(src_reg, addr_reg, offset),
+                    ir::PtrOffset::Index { index, scaled } => {
+                        let index_reg = self.index_register(index, reg_map);
+                        let scale = if scaled {
+                            src.size().in_bytes() as u8
+                        } else {
+                            1
+                        };
+                        Instruction::store_index(src_reg, addr_reg, index_reg, scale)
+                    }
+                };
+                self.instructions.push(inst);
+            }
+            ir::Instruction::LoadAddr { dest, addr } => {
+                let dest_reg = reg_map.get(dest).unwrap();
+
+                // lea dest_reg, [rip + addr]
+                let symbol = self.data_symbol_for(*addr);
+                let inst = Instruction::lea(*dest_reg, symbol);
+                self.instructions.push(inst);
+            }
+            ir::Instruction::FuncAddr { dest, func } => {
+                let dest_reg = reg_map.get(dest).unwrap();
+
+                // The function can be in another module or a shared library
+                // mov dest_reg, [rip + func GOT entry]
+                let inst = Instruction::load_got(*dest_reg, func.clone());
+                self.instructions.push(inst);
+            }
+            ir::Instruction::Add { dest, src_1, src_2 }
+            | ir::Instruction::Sub { dest, src_1, src_2 }
+            | ir::Instruction::Mul { dest, src_1, src_2 }
+            | ir::Instruction::Div { dest, src_1, src_2 }
+                if dest.size().is_float() =>
+            {
+                let src_1_reg = reg_map.get(src_1).unwrap();
+                let src_2_reg = reg_map.get(src_2).unwrap();
+                let dest_reg = reg_map.get(dest).unwrap();
+
+                // Same as for integers, dest = src_1 first and dest never shares a register with src_2
+                if let Some(inst) = Instruction::mov(*dest_reg, *src_1_reg) {
+                    self.instructions.push(inst);
+                }
+
+                let op = match inst {
+                    ir::Instruction::Add { .. } => "add",
+                    ir::Instruction::Sub { .. } => "sub",
+                    ir::Instruction::Mul { .. } => "mul",
+                    _ => "div",
+                };
+                let inst = Instruction::float_op(op, *dest_reg, *src_2_reg);
+                self.instructions.push(inst);
+            }
+            ir::Instruction::Neg { dest, src } if dest.size().is_float() => {
+                let src_reg = reg_map.get(src).unwrap();
+                let dest_reg = reg_map.get(dest).unwrap();
+
+                // Flip the sign bit in r11
+                let scratch = Register::r11(match dest.size() {
+                    Size::F32 => Size::DoubleWord,
+                    _ => Size::QuadWord,
+                });
+                self.instructions.push(Instruction::MovReg {
+                    dest: scratch,
+                    src: *src_reg,
+                });
+                self.instructions.push(Instruction::btc(
+                    scratch,
+                    dest.size().in_bytes() as u8 * 8 - 1,
+                ));
+                self.instructions.push(Instruction::MovReg {
+                    dest: *dest_reg,
+                    src: scratch,
+                });
+            }
+            ir::Instruction::Sqrt { dest, src } => {
+                let src_reg = reg_map.get(src).unwrap();
+                let dest_reg = reg_map.get(dest).unwrap();
+
+                let inst = Instruction::float_op("sqrt", *dest_reg, *src_reg);
+                self.instructions.push(inst);
+            }
+            ir::Instruction::Convert { dest, src } => {
+                let src_reg = *reg_map.get(src).unwrap();
+                let dest_reg = *reg_map.get(dest).unwrap();
+
+                match (src.size().is_float(), dest.size().is_float()) {
+                    (true, true) => self.instructions.push(Instruction::cvt(dest_reg, src_reg)),
+                    (false, _) => self.int_to_float(dest_reg, src_reg, src.is_signed()),
+                    (true, false) => self.float_to_int(dest_reg, src_reg, dest.is_signed()),
+                }
+            }
+            ir::Instruction::Add { dest, src_1, src_2 }
+            | ir::Instruction::Sub { dest, src_1, src_2 }
+            | ir::Instruction::Mul { dest, src_1, src_2 }
+            | ir::Instruction::And { dest, src_1, src_2 }
+            | ir::Instruction::Or { dest, src_1, src_2 }
+            | ir::Instruction::Xor { dest, src_1, src_2 } => {
+                let src_1_reg = reg_map.get(src_1).unwrap();
+                let src_2_reg = reg_map.get(src_2).unwrap();
+                let dest_reg = reg_map.get(dest).unwrap();
+
+                // x86 only has two operands, so dest = src_1 first.
+                // dest never shares a register with src_2, the register allocator makes sure of it
+                let (dest_reg, src_1_reg, src_2_reg) = match inst {
+                    // There is no two operand imul for bytes, the low byte is the same in 32 bits
+                    ir::Instruction::Mul { .. } if dest_reg.size() == Size::Byte => (
+                        dest_reg.resize(Size::DoubleWord),
+                        src_1_reg.resize(Size::DoubleWord),
+                        src_2_reg.resize(Size::DoubleWord),
+                    ),
+                    _ => (*dest_reg, *src_1_reg, *src_2_reg),
+                };
+
+                if let Some(inst) = Instruction::mov(dest_reg, src_1_reg) {
+                    self.instructions.push(inst);
+                }
+
+                let inst = match inst {
+                    ir::Instruction::Add { .. } => Instruction::add(dest_reg, src_2_reg),
+                    ir::Instruction::Sub { .. } => Instruction::sub(dest_reg, src_2_reg),
+                    ir::Instruction::Mul { .. } => Instruction::imul(dest_reg, src_2_reg),
+                    ir::Instruction::And { .. } => Instruction::and(dest_reg, src_2_reg),
+                    ir::Instruction::Or { .. } => Instruction::or(dest_reg, src_2_reg),
+                    ir::Instruction::Xor { .. } => Instruction::xor(dest_reg, src_2_reg),
+                    _ => unreachable!(),
+                };
+                self.instructions.push(inst);
+            }
+            ir::Instruction::Div { dest, src_1, src_2 }
+            | ir::Instruction::Rem { dest, src_1, src_2 } => {
+                let src_1_reg = reg_map.get(src_1).unwrap();
+                let src_2_reg = reg_map.get(src_2).unwrap();
+                let dest_reg = reg_map.get(dest).unwrap();
+                let signed = dest.is_signed();
+
+                // Bytes and words are divided in 32 bits
+                let size = match dest_reg.size() {
+                    Size::Byte | Size::Word => Size::DoubleWord,
+                    size => size,
+                };
+
+                // The divisor goes first, it may be sitting in rax or rdx
+                let divisor = Register::r11(size);
+                let mov = Instruction::mov_extend(divisor, *src_2_reg, signed);
+                self.instructions.extend(mov);
+
+                let mov = Instruction::mov_extend(Register::rax(size), *src_1_reg, signed);
+                self.instructions.extend(mov);
+
+                // Extend rax into rdx
+                if signed {
+                    self.instructions.push(Instruction::sign_extend_rax(size));
+                } else {
+                    let rdx = Register::rdx(Size::DoubleWord);
+                    self.instructions.push(Instruction::xor(rdx, rdx));
+                }
+
+                self.instructions.push(Instruction::div(divisor, signed));
+
+                // Quotient is in rax and remainder is in rdx
+                let result = match inst {
+                    ir::Instruction::Div { .. } => Register::rax(dest_reg.size()),
+                    _ => Register::rdx(dest_reg.size()),
+                };
+
+                if let Some(inst) = Instruction::mov(*dest_reg, result) {
+                    self.instructions.push(inst);
+                }
+            }
+            ir::Instruction::Neg { dest, src } | ir::Instruction::Not { dest, src } => {
+                let src_reg = reg_map.get(src).unwrap();
+                let dest_reg = reg_map.get(dest).unwrap();
+
+                if let Some(inst) = Instruction::mov(*dest_reg, *src_reg) {
+                    self.instructions.push(inst);
+                }
+
+                let inst = match inst {
+                    ir::Instruction::Neg { .. } => Instruction::neg(*dest_reg),
+                    _ => Instruction::not(*dest_reg),
+                };
+                self.instructions.push(inst);
+            }
+            ir::Instruction::Extend { dest, src, signed } => {
+                let src_reg = *reg_map.get(src).unwrap();
+                let dest_reg = *reg_map.get(dest).unwrap();
+
+                // Writing a 32 bit register zeroes the upper half of the 64 bit one
+                let inst = if src.size() == Size::DoubleWord && !signed {
+                    Instruction::MovReg {
+                        dest: Register::new(dest_reg.number(), Size::DoubleWord),
+                        src: src_reg,
+                    }
+                } else {
+                    Instruction::MovExtend {
+                        dest: dest_reg,
+                        src: src_reg,
+                        signed: *signed,
+                    }
+                };
+                self.instructions.push(inst);
+            }
+            ir::Instruction::Trunc { dest, src } => {
+                let src_reg = *reg_map.get(src).unwrap();
+                let dest_reg = *reg_map.get(dest).unwrap();
+
+                // The low bits of a register are addressable on their own
+                let src_reg = Register::new(src_reg.number(), dest_reg.size());
+                self.instructions
+                    .extend(Instruction::mov(dest_reg, src_reg));
+            }
+            ir::Instruction::Shl { dest, src, amount }
+            | ir::Instruction::LShr { dest, src, amount }
+            | ir::Instruction::AShr { dest, src, amount } => {
+                let src_reg = reg_map.get(src).unwrap();
+                let amount_reg = reg_map.get(amount).unwrap();
+                let dest_reg = reg_map.get(dest).unwrap();
+
+                // Shift amount has to be in cl, which may hold src
+                let scratch = Register::r11(dest_reg.size());
+                if let Some(inst) = Instruction::mov(scratch, *src_reg) {
+                    self.instructions.push(inst);
+                }
+
+                let rcx = Register::rcx(Size::DoubleWord);
+                if let Some(inst) = Instruction::mov(rcx, amount_reg.resize(Size::DoubleWord)) {
+                    self.instructions.push(inst);
+                }
+
+                let inst = match inst {
+                    ir::Instruction::Shl { .. } => Instruction::shl(scratch),
+                    ir::Instruction::LShr { .. } => Instruction::shr(scratch),
+                    _ => Instruction::sar(scratch),
+                };
+                self.instructions.push(inst);
+
+                if let Some(inst) = Instruction::mov(*dest_reg, scratch) {
+                    self.instructions.push(inst);
+                }
+            }
+            ir::Instruction::Cmp {
+                dest,
+                src_1,
+                src_2,
+                cond,
+            } if src_1.size().is_float() => {
+                let src_1_reg = *reg_map.get(src_1).unwrap();
+                let src_2_reg = *reg_map.get(src_2).unwrap();
+                let dest_reg = *reg_map.get(dest).unwrap();
+
+                // ucomis sets the flags like an unsigned compare, and sets ZF, PF and CF when
+                // either side is NaN. Only above and above or equal are false for NaN,
+                // so less than swaps the operands
+                let (src_1_reg, src_2_reg, cond) = match cond {
+                    ir::Condition::Flt => (src_2_reg, src_1_reg, ir::Condition::Ugt),
+                    ir::Condition::Fle => (src_2_reg, src_1_reg, ir::Condition::Uge),
+                    ir::Condition::Fgt => (src_1_reg, src_2_reg, ir::Condition::Ugt),
+                    ir::Condition::Fge => (src_1_reg, src_2_reg, ir::Condition::Uge),
+                    cond => (src_1_reg, src_2_reg, *cond),
+                };
+
+                let inst = Instruction::ucomis(src_1_reg, src_2_reg);
+                self.instructions.push(inst);
+
+                let inst = Instruction::set_cc(dest_reg, cond);
+                self.instructions.push(inst);
+
+                // Equality also has to check PF, so it is not branched on directly
+                let scratch = Register::r11(Size::Byte);
+                match cond {
+                    ir::Condition::Eq => {
+                        self.instructions
+                            .push(Instruction::set_parity(scratch, false));
+                        self.instructions.push(Instruction::and(dest_reg, scratch));
+                    }
+                    ir::Condition::Ne => {
+                        self.instructions
+                            .push(Instruction::set_parity(scratch, true));
+                        self.instructions.push(Instruction::or(dest_reg, scratch));
+                    }
+                    _ => self.last_cmp = Some((*dest, cond)),
+                }
+            }
+            ir::Instruction::Cmp {
+                dest,
+                src_1,
+                src_2,
+                cond,
+            } => {
+                let src_1_reg = reg_map.get(src_1).unwrap();
+                let src_2_reg = reg_map.get(src_2).unwrap();
+                let dest_reg = reg_map.get(dest).unwrap();
+
+                let inst = Instruction::cmp(*src_1_reg, *src_2_reg);
+                self.instructions.push(inst);
+
+                let inst = Instruction::set_cc(*dest_reg, *cond);
+                self.instructions.push(inst);
+
+                self.last_cmp = Some((*dest, *cond));
+            }
+            ir::Instruction::Label { label } => {
+                let label = self.label_for(*label);
+
+                let inst = Instruction::label(label);
+                self.instructions.push(inst);
+            }
+            ir::Instruction::Jump { label } => {
+                let label = self.label_for(*label);
+
+                let inst = Instruction::jmp(label);
+                self.instructions.push(inst);
+            }
+            ir::Instruction::Branch {
+                cond,
+                then_label,
+                else_label,
+            } => {
+                let then_label = self.label_for(*then_label);
+                let else_label = self.label_for(*else_label);
+
+                match last_cmp {
+                    // Flags of the comparison are still set, so branch on them directly
+                    Some((cmp_dest, cmp_cond)) if cmp_dest == *cond => {
+                        let inst = Instruction::j_cc(cmp_cond, then_label);
+                        self.instructions.push(inst);
+
+                        let inst = Instruction::jmp(else_label);
+                        self.instructions.push(inst);
+                    }
+                    _ => {
+                        let cond_reg = reg_map.get(cond).unwrap();
+
+                        let inst = Instruction::test(*cond_reg, *cond_reg);
+                        self.instructions.push(inst);
+
+                        let inst = Instruction::j_cc(ir::Condition::Eq, else_label);
+                        self.instructions.push(inst);
+
+                        let inst = Instruction::jmp(then_label);
+                        self.instructions.push(inst);
+                    }
+                }
+            }
+            ir::Instruction::Return { src, .. } => {
+                if let Some(src) = src {
+                    let src_reg = reg_map.get(src).unwrap();
+
+                    let inst = Instruction::mov(Register::result(src_reg.size()), *src_reg);
+                    // Only add if it is not a NOP
+                    if let Some(inst) = inst {
+                        self.instructions.push(inst);
+                    }
+                }
+
+                let inst = Instruction::jmp(return_label);
+                self.instructions.push(inst);
+            }
+            ir::Instruction::VaStart { va_list } => {
+                let va_list_reg = *reg_map.get(va_list).unwrap();
+                let save_offset = frame.va_save_offset().unwrap();
+
+                // SysV va_list:
+                // unsigned gp_offset, unsigned fp_offset, void *overflow_arg_area, void *reg_save_area
+                // The offsets count into the register save area, past the named args
+                let scratch = Register::r11(Size::DoubleWord);
+                let gp_offset = frame.named_args.int_args * 8;
+                self.instructions
+                    .push(Instruction::mov_imm(scratch, gp_offset as u64));
+                self.instructions
+                    .push(Instruction::store(scratch, va_list_reg, 0));
+
+                let fp_offset = 48 + frame.named_args.float_args * 16;
+                self.instructions
+                    .push(Instruction::mov_imm(scratch, fp_offset as u64));
+                self.instructions
+                    .push(Instruction::store(scratch, va_list_reg, 4));
+
+                // The variadic stack args follow the named ones above the return address
+                let scratch = Register::r11(Size::QuadWord);
+                let stack_offset = 16 + frame.named_args.stack_args as i32 * 8;
+                self.instructions.push(Instruction::lea_mem(
+                    scratch,
+                    Register::rbp(),
+                    stack_offset,
+                ));
+                self.instructions
+                    .push(Instruction::store(scratch, va_list_reg, 8));
+
+                self.instructions
+                    .push(Instruction::lea_mem(scratch, Register::rsp(), save_offset));
+                self.instructions
+                    .push(Instruction::store(scratch, va_list_reg, 16));
+            }
+            ir::Instruction::VaArg { dest, va_list } => {
+                let dest_reg = *reg_map.get(dest).unwrap();
+                let va_list_reg = *reg_map.get(va_list).unwrap();
+                let (offset_field, limit, reg_size) = if dest.size().is_float() {
+                    (4, 48 + 128, 16)
+                } else {
+                    (0, 48, 8)
+                };
+                let on_stack = Label::new(self.lbl_iota.next());
+                let done = Label::new(self.lbl_iota.next());
+
+                // The arg is in the register save area until its offset reaches the end
+                // of the registers of its class, after that it is on the stack
+                // mov r11d, [va_list + offset_field]
+                // cmp r11d, limit
+                // jae on_stack
+                // add r11, [va_list + 16]
+                // add dword ptr [va_list + offset_field], reg size
+                // jmp done
+                let scratch = Register::r11(Size::DoubleWord);
+                self.instructions
+                    .push(Instruction::load(scratch, va_list_reg, offset_field));
+
+                let inst = Instruction::cmp_imm(scratch, limit);
+                self.instructions.push(inst);
+
+                let inst = Instruction::j_cc(ir::Condition::Uge, on_stack);
+                self.instructions.push(inst);
+
+                let addr = Register::r11(Size::QuadWord);
+                let inst = Instruction::add_mem(addr, va_list_reg, 16);
+                self.instructions.push(inst);
+
+                let inst =
+                    Instruction::add_imm_mem(Size::DoubleWord, va_list_reg, offset_field, reg_size);
+                self.instructions.push(inst);
+
+                let inst = Instruction::jmp(done);
+                self.instructions.push(inst);
+
+                // Every stack arg takes 8 bytes
+                // on_stack:
+                // mov r11, [va_list + 8]
+                // add qword ptr [va_list + 8], 8
+                self.instructions.push(Instruction::label(on_stack));
+
+                let inst = Instruction::load(addr, va_list_reg, 8);
+                self.instructions.push(inst);
+
+                let inst = Instruction::add_imm_mem(Size::QuadWord, va_list_reg, 8, 8);
+                self.instructions.push(inst);
+
+                // done:
+                // mov dest, [r11]
+                self.instructions.push(Instruction::label(done));
+
+                let inst = Instruction::load(dest_reg, addr, 0);
+                self.instructions.push(inst);
+            }
+            // A SysV va_list holds nothing that has to be cleaned up
+            ir::Instruction::VaEnd { .. } => {}
+            ir::Instruction::Call { dest, args, .. }
+            | ir::Instruction::CallIndirect { dest, args, .. } => {
+                let arg_locations = arg_locations(args.iter().map(|arg| arg.size()));
+
+                // Every stack arg takes 8 bytes, and rsp has to stay 16 byte aligned
+                let stack_args = arg_locations.iter().filter(|arg| arg.is_err()).count();
+                let stack_args_size = (stack_args * 8).next_multiple_of(16) as u32;
+
+                if stack_args_size != 0 {
+                    self.instructions
+                        .push(Instruction::sub_imm(Register::rsp(), stack_args_size));
+                }
+
+                // Store args in correct registers/stack
+                let mut moves = Vec::new();
+                for (arg, arg_reg) in args.iter().zip(arg_locations) {
+                    let value_reg = reg_map.get(arg).unwrap();
+                    match arg_reg {
+                        Ok(arg_reg) => moves.push((arg_reg, *value_reg)),
+                        Err(stack_index) => {
+                            let offset = stack_index as i32 * 8;
+                            let inst = Instruction::store(*value_reg, Register::rsp(), offset);
+                            self.instructions.push(inst);
+                        }
+                    }
+                }
+
+                // al holds the number of vector registers used by a variadic callee
+                let float_args = moves
+                    .iter()
+                    .filter(|(arg_reg, _)| arg_reg.is_float())
+                    .count();
+
+                // rax carries no args, so parking the callee there as part of the same
+                // parallel move keeps it from being overwritten by an arg
+                if let ir::Instruction::CallIndirect { func, .. } = inst {
+                    moves.push((Register::rax(Size::QuadWord), *reg_map.get(func).unwrap()));
+                }
+
+                // The stack args were stored straight from their temporaries, the register
+                // args follow as one parallel move as rdi, rsi, ... may hold each other's values
+                self.parallel_move(moves);
+
+                // rax is needed for al, so the function pointer moves on to r11
+                if let ir::Instruction::CallIndirect { .. } = inst {
+                    self.instructions.push(Instruction::MovReg {
+                        dest: Register::r11(Size::QuadWord),
+                        src: Register::rax(Size::QuadWord),
+                    });
+                }
+
+                let eax = Register::rax(Size::DoubleWord);
+                if float_args == 0 {
+                    self.instructions.push(Instruction::xor(eax, eax));
+                } else {
+                    self.instructions
+                        .push(Instruction::mov_imm(eax, float_args as u64));
+                }
+
+                // Call func
+                let inst = match inst {
+                    ir::Instruction::CallIndirect { .. } => {
+                        Instruction::call_reg(Register::r11(Size::QuadWord))
+                    }
+                    ir::Instruction::Call { func, .. } => Instruction::call(func.clone()),
+                    _ => unreachable!(),
+                };
+                self.instructions.push(inst);
+
+                if stack_args_size != 0 {
+                    self.instructions
+                        .push(Instruction::add_imm(Register::rsp(), stack_args_size));
+                }
+
+                if let Some(dest) = dest {
+                    let dest_reg = reg_map.get(dest).unwrap();
+
+                    // mov dest_reg, rax
+                    let inst = Instruction::mov(*dest_reg, Register::result(dest_reg.size()));
+                    // Only add if it is not a NOP
+                    if let Some(inst) = inst {
+                        self.instructions.push(inst);
+                    }
+                }
+            }
+        }
+    }
+
+    /// Converts the int in src to the float in dest
+    fn int_to_float(&mut self, dest: Register, src: Register, signed: bool) {
+        let src = match (src.size(), signed) {
+            // cvtsi2ss/sd only reads 32 or 64 bit registers
+            (Size::Byte | Size::Word, _) => {
+                let scratch = Register::r11(Size::DoubleWord);
+                let inst = Instruction::mov_extend(scratch, src, signed).unwrap();
+                self.instructions.push(inst);
+                scratch
+            }
+            // Zero extended by the mov, then converted as a 64 bit signed int
+            (Size::DoubleWord, false) => {
+                let scratch = Register::r11(Size::DoubleWord);
+                self.instructions
+                    .push(Instruction::MovReg { dest: scratch, src });
+                scratch.resize(Size::QuadWord)
+            }
+            (Size::QuadWord, false) => return self.u64_to_float(dest, src),
+            _ => src,
+        };
+
+        self.instructions.push(Instruction::cvt(dest, src));
+    }
+
+    /// Values with the top bit set are halved, keeping the lost bit so they round
+    /// the same, and then doubled
+    fn u64_to_float(&mut self, dest: Register, src: Register) {
+        let big = Label::new(self.lbl_iota.next());
+        let odd = Label::new(self.lbl_iota.next());
+        let done = Label::new(self.lbl_iota.next());
+
+        self.instructions.push(Instruction::test(src, src));
+        self.instructions
+            .push(Instruction::j_cc(ir::Condition::Slt, big));
+        self.instructions.push(Instruction::cvt(dest, src));
+        self.instructions.push(Instruction::jmp(done));
+
+        let scratch = Register::r11(Size::QuadWord);
+        self.instructions.push(Instruction::label(big));
+        self.instructions
+            .push(Instruction::MovReg { dest: scratch, src });
+        self.instructions.push(Instruction::shr_imm(scratch, 1));
+        // The shifted out bit is in CF
+        self.instructions
+            .push(Instruction::j_cc(ir::Condition::Uge, odd));
+        self.instructions.push(Instruction::bts(scratch, 0));
+        self.instructions.push(Instruction::label(odd));
+        self.instructions.push(Instruction::cvt(dest, scratch));
+        self.instructions
+            .push(Instruction::float_op("add", dest, dest));
+        self.instructions.push(Instruction::label(done));
+    }
+
+    /// Converts the float in src to the int in dest, rounding towards zero
+    fn float_to_int(&mut self, dest: Register, src: Register, signed: bool) {
+        match (dest.size(), signed) {
+            (Size::QuadWord, true) => self.instructions.push(Instruction::cvt(dest, src)),
+            (Size::QuadWord, false) => self.float_to_u64(dest, src),
+            // The 64 bit conversion covers every u32
+            (Size::DoubleWord, false) => self
+                .instructions
+                .push(Instruction::cvt(dest.resize(Size::QuadWord), src)),
+            _ => self
+                .instructions
+                .push(Instruction::cvt(dest.resize(Size::DoubleWord), src)),
+        }
+    }
+
+    /// Values from 2^63 up are converted as `-(2^63 - src)`, and then get 2^63 added back
+    fn float_to_u64(&mut self, dest: Register, src: Register) {
+        let big = Label::new(self.lbl_iota.next());
+        let done = Label::new(self.lbl_iota.next());
+
+        // 2^63
+        let (bits, int_size) = match src.size() {
+            Size::F32 => (0x5f00_0000, Size::DoubleWord),
+            _ => (0x43e0_0000_0000_0000, Size::QuadWord),
+        };
+        let limit = Register::scratch(src.size());
+        self.instructions
+            .push(Instruction::mov_imm(Register::r11(int_size), bits));
+        self.instructions.push(Instruction::MovReg {
+            dest: limit,
+            src: Register::r11(int_size),
+        });
+
+        self.instructions.push(Instruction::ucomis(src, limit));
+        self.instructions
+            .push(Instruction::j_cc(ir::Condition::Uge, big));
+        self.instructions.push(Instruction::cvt(dest, src));
+        self.instructions.push(Instruction::jmp(done));
+
+        self.instructions.push(Instruction::label(big));
+        self.instructions
+            .push(Instruction::float_op("sub", limit, src));
+        self.instructions.push(Instruction::cvt(dest, limit));
+        self.instructions.push(Instruction::neg(dest));
+        self.instructions.push(Instruction::btc(dest, 63));
+        self.instructions.push(Instruction::label(done));
+    }
+
+    /// Moves every `(dest, src)` pair as if they happened at the same time
+    fn parallel_move(&mut self, moves: Vec<(Register, Register)>) {
+        for (dest, src) in target::parallel_moves(moves) {
+            self.instructions.push(Instruction::MovReg { dest, src });
+        }
+    }
+
+    fn label_for(&mut self, label: ir::Label) -> Label {
+        *self
+            .func_labels
+            .entry(label)
+            .or_insert_with(|| Label::new(self.lbl_iota.next()))
+    }
+
+    /// Register holding `index` as 64 bits, as memory operands only take 64 bit indices
+    fn index_register(
+        &mut self,
+        index: ir::Temporary,
+        reg_map: &HashMap<ir::Temporary, Register>,
+    ) -> Register {
+        let index_reg = *reg_map.get(&index).unwrap();
+        if index_reg.size() == Size::QuadWord {
+            return index_reg;
+        }
+
+        // Writing the 32 bit register zero extends into the 64 bit one
+        let inst = if index.is_signed() {
+            Instruction::mov_extend(Register::r11(Size::QuadWord), index_reg, true)
+        } else {
+            Instruction::mov(Register::r11(Size::DoubleWord), index_reg)
+        };
+        self.instructions.extend(inst);
+
+        Register::r11(Size::QuadWord)
+    }
+}
+
+/// Stack frame of a function, below the saved rbp it holds the callee saved
+/// registers it uses and then the stack slots, which are addressed from rsp
+struct Frame {
+    slots_size: u32,
+    va_save_size: u32,
+    named_args: NamedArgs,
+    saved_regs: Vec<Register>,
+}
+
+impl Frame {
+    fn new(func: &ir::Function, layout: &FrameLayout) -> Self {
+        // The 6 int arg registers and 16 bytes for each of xmm0-xmm7
+        let va_save_size = if func.is_variadic() { 48 + 128 } else { 0 };
+
+        Self {
+            slots_size: layout.slots_size,
+            va_save_size,
+            named_args: NamedArgs::new(func),
+            saved_regs: layout
+                .saved_registers
+                .iter()
+                .map(|number| Register::new(RegisterNumber::from_u8(*number), Size::QuadWord))
+                .collect(),
+        }
+    }
+
+    fn saved_regs(&self) -> &[Register] {
+        &self.saved_regs
+    }
+
+    /// Offset from rsp where the arg registers are saved for va_arg, if they are
+    fn va_save_offset(&self) -> Option<i32> {
+        (self.va_save_size != 0).then_some(self.slots_size as i32)
+    }
+
+    /// Size of the stack slots and register save area, rsp is 16 byte aligned after pushing
+    /// rbp so an odd number of saved registers needs 8 bytes of padding
+    fn size(&self) -> u32 {
+        self.slots_size + self.va_save_size + if self.saved_regs.len() % 2 == 1 { 8 } else { 0 }
+    }
+}
+
+/// Where the named args of a function end, which is where va_arg starts reading
+struct NamedArgs {
+    int_args: u32,
+    float_args: u32,
+    stack_args: u32,
+}
+
+impl NamedArgs {
+    fn new(func: &ir::Function) -> Self {
+        let mut named_args = Self {
+            int_args: 0,
+            float_args: 0,
+            stack_args: 0,
+        };
+        for location in arg_locations(func.args().iter().map(|arg| arg.size())) {
+            match location {
+                Ok(reg) if reg.is_float() => named_args.float_args += 1,
+                Ok(_) => named_args.int_args += 1,
+                Err(_) => named_args.stack_args += 1,
+            }
+        }
+
+        named_args
+    }
+}
+
+#[derive(Clone, Copy)]
+struct Label {
+    id: usize,
+}
+
+impl Label {
+    fn new(id: usize) -> Self {
+        Self { id }
+    }
+
+    fn id(self) -> usize {
+        self.id
+    }
+}
+
+enum Instruction {
+    // Empty line in generated asm code
+    Empty,
+    Section {
+        name: &'static str,
+    },
+    Custom {
+        string: String,
+    },
+    Label {
+        label: Label,
+    },
+    MovReg {
+        dest: Register,
+        src: Register,
+    },
+    MovImm {
+        dest: Register,
+        imm: u64,
+    },
+    MovExtend {
+        dest: Register,
+        src: Register,
+        signed: bool,
+    },
+    Load {
+        dest: Register,
+        addr: Register,
+        offset: i32,
+    },
+    Store {
+        src: Register,
+        addr: Register,
+        offset: i32,
+    },
+    LoadIndex {
+        dest: Register,
+        addr: Register,
+        index: Register,
+        scale: u8,
+    },
+    StoreIndex {
+        src: Register,
+        addr: Register,
+        index: Register,
+        scale: u8,
+    },
+    Lea {
+        dest: Register,
+        symbol: DataSymbol,
+    },
+    LeaMem {
+        dest: Register,
+        addr: Register,
+        offset: i32,
+    },
+    Push {
+        src: Register,
+    },
+    Pop {
+        dest: Register,
+    },
+    Add {
+        dest: Register,
+        src: Register,
+    },
+    AddImm {
+        dest: Register,
+        imm: u32,
+    },
+    /// Adds the value at `[addr + offset]` to dest
+    AddMem {
+        dest: Register,
+        addr: Register,
+        offset: i32,
+    },
+    /// Adds imm to the `size` value at `[addr + offset]`
+    AddImmMem {
+        size: Size,
+        addr: Register,
+        offset: i32,
+        imm: u16,
+    },
+    Sub {
+        dest: Register,
+        src: Register,
+    },
+    SubImm {
+        dest: Register,
+        imm: u32,
+    },
+    IMul {
+        dest: Register,
+        src: Register,
+    },
+    Div {
+        src: Register,
+        signed: bool,
+    },
+    SignExtendRax {
+        size: Size,
+    },
+    Neg {
+        dest: Register,
+    },
+    And {
+        dest: Register,
+        src: Register,
+    },
+    Or {
+        dest: Register,
+        src: Register,
+    },
+    Xor {
+        dest: Register,
+        src: Register,
+    },
+    Not {
+        dest: Register,
+    },
+    Shl {
+        dest: Register,
+    },
+    Shr {
+        dest: Register,
+    },
+    Sar {
+        dest: Register,
+    },
+    Cmp {
+        src_1: Register,
+        src_2: Register,
+    },
+    CmpImm {
+        src: Register,
+        imm: u16,
+    },
+    Test {
+        src_1: Register,
+        src_2: Register,
+    },
+    SetCC {
+        dest: Register,
+        cond: ir::Condition,
+    },
+    SetParity {
+        dest: Register,
+        set: bool,
+    },
+    ShrImm {
+        dest: Register,
+        imm: u8,
+    },
+    Btc {
+        dest: Register,
+        bit: u8,
+    },
+    Bts {
+        dest: Register,
+        bit: u8,
+    },
+    FloatOp {
+        op: &'static str,
+        dest: Register,
+        src: Register,
+    },
+    Ucomis {
+        src_1: Register,
+        src_2: Register,
+    },
+    Cvt {
+        dest: Register,
+        src: Register,
+    },
+    Jmp {
+        label: Label,
+    },
+    Jcc {
+        cond: ir::Condition,
+        label: Label,
+    },
+    Call {
+        func: String,
+    },
+    CallReg {
+        func: Register,
+    },
+    LoadGot {
+        dest: Register,
+        func: String,
+    },
+    Ret,
+
+    Data {
+        directive: DataDirective,
+    },
+}
+
+impl Instruction {
+    fn custom(string: String) -> Self {
+        Self::Custom { string }
+    }
+
+    fn section(name: &'static str) -> Self {
+        Self::Section { name }
+    }
+
+    fn mov_imm(dest: Register, value: u64) -> Self {
+        Self::MovImm { dest, imm: value }
+    }
+
+    fn mov(dest: Register, src: Register) -> Option<Self> {
+        // No need to return the instruction if it is a NOP
+        if dest == src {
+            None
+        } else {
+            Some(Self::MovReg { dest, src })
+        }
+    }
+
+    /// Moves src into the wider dest, sign or zero extending it
+    fn mov_extend(dest: Register, src: Register, signed: bool) -> Option<Self> {
+        match src.size() {
+            Size::Byte | Size::Word => Some(Self::MovExtend { dest, src, signed }),
+            Size::DoubleWord if signed && dest.size() == Size::QuadWord => {
+                Some(Self::MovExtend { dest, src, signed })
+            }
+            _ => Self::mov(dest, src),
+        }
+    }
+
+    fn load(dest: Register, addr: Register, offset: i32) -> Self {
+        Self::Load { dest, addr, offset }
+    }
+
+    fn store(src: Register, addr: Register, offset: i32) -> Self {
+        Self::Store { src, addr, offset }
+    }
+
+    fn load_index(dest: Register, addr: Register, index: Register, scale: u8) -> Self {
+        Self::LoadIndex {
+            dest,
+            addr,
+            index,
+            scale,
+        }
+    }
+
+    fn store_index(src: Register, addr: Register, index: Register, scale: u8) -> Self {
+        Self::StoreIndex {
+            src,
+            addr,
+            index,
+            scale,
+        }
+    }
+
+    fn lea(dest: Register, symbol: DataSymbol) -> Self {
+        Self::Lea { dest, symbol }
+    }
+
+    fn lea_mem(dest: Register, addr: Register, offset: i32) -> Self {
+        Self::LeaMem { dest, addr, offset }
+    }
+
+    fn push(src: Register) -> Self {
+        Self::Push { src }
+    }
+
+    fn pop(dest: Register) -> Self {
+        Self::Pop { dest }
+    }
+
+    fn add(dest: Register, src: Register) -> Self {
+        Self::Add { dest, src }
+    }
+
+    fn add_imm(dest: Register, imm: u32) -> Self {
+        Self::AddImm { dest, imm }
+    }
+
+    fn add_mem(dest: Register, addr: Register, offset: i32) -> Self {
+        Self::AddMem { dest, addr, offset }
+    }
+
+    fn add_imm_mem(size: Size, addr: Register, offset: i32, imm: u16) -> Self {
+        Self::AddImmMem {
+            size,
+            addr,
+            offset,
+            imm,
+        }
+    }
+
+    fn sub(dest: Register, src: Register) -> Self {
+        Self::Sub { dest, src }
+    }
+
+    fn sub_imm(dest: Register, imm: u32) -> Self {
+        Self::SubImm { dest, imm }
+    }
+
+    fn imul(dest: Register, src: Register) -> Self {
+        Self::IMul { dest, src }
+    }
+
+    /// Divides rdx:rax by src
+    fn div(src: Register, signed: bool) -> Self {
+        Self::Div { src, signed }
+    }
+
+    /// Sign extends rax into rdx, cdq or cqo
+    fn sign_extend_rax(size: Size) -> Self {
+        Self::SignExtendRax { size }
+    }
+
+    fn neg(dest: Register) -> Self {
+        Self::Neg { dest }
+    }
+
+    fn and(dest: Register, src: Register) -> Self {
+        Self::And { dest, src }
+    }
+
+    fn or(dest: Register, src: Register) -> Self {
+        Self::Or { dest, src }
+    }
+
+    fn xor(dest: Register, src: Register) -> Self {
+        Self::Xor { dest, src }
+    }
+
+    fn not(dest: Register) -> Self {
+        Self::Not { dest }
+    }
+
+    /// Shifts left by cl
+    fn shl(dest: Register) -> Self {
+        Self::Shl { dest }
+    }
+
+    /// Shifts right by cl, filling in zeros
+    fn shr(dest: Register) -> Self {
+        Self::Shr { dest }
+    }
+
+    /// Shifts right by cl, filling in the sign bit
+    fn sar(dest: Register) -> Self {
+        Self::Sar { dest }
+    }
+
+    fn cmp(src_1: Register, src_2: Register) -> Self {
+        Self::Cmp { src_1, src_2 }
+    }
+
+    fn cmp_imm(src: Register, imm: u16) -> Self {
+        Self::CmpImm { src, imm }
+    }
+
+    fn test(src_1: Register, src_2: Register) -> Self {
+        Self::Test { src_1, src_2 }
+    }
+
+    fn set_cc(dest: Register, cond: ir::Condition) -> Self {
+        Self::SetCC { dest, cond }
+    }
+
+    /// Sets dest to PF, or to its inverse
+    fn set_parity(dest: Register, set: bool) -> Self {
+        Self::SetParity { dest, set }
+    }
+
+    /// Shifts right by imm, filling in zeros
+    fn shr_imm(dest: Register, imm: u8) -> Self {
+        Self::ShrImm { dest, imm }
+    }
+
+    /// Flips a single bit of dest
+    fn btc(dest: Register, bit: u8) -> Self {
+        Self::Btc { dest, bit }
+    }
+
+    /// Sets a single bit of dest
+    fn bts(dest: Register, bit: u8) -> Self {
+        Self::Bts { dest, bit }
+    }
+
+    /// Scalar float instruction, suffixed with ss or sd
+    fn float_op(op: &'static str, dest: Register, src: Register) -> Self {
+        Self::FloatOp { op, dest, src }
+    }
+
+    /// Compares two floats, setting ZF, PF and CF
+    fn ucomis(src_1: Register, src_2: Register) -> Self {
+        Self::Ucomis { src_1, src_2 }
+    }
+
+    /// Converts between floats and ints, float to int truncates
+    fn cvt(dest: Register, src: Register) -> Self {
+        Self::Cvt { dest, src }
+    }
+
+    fn jmp(label: Label) -> Self {
+        Self::Jmp { label }
+    }
+
+    fn j_cc(cond: ir::Condition, label: Label) -> Self {
+        Self::Jcc { cond, label }
+    }
+
+    fn call(func: String) -> Self {
+        Self::Call { func }
+    }
+
+    fn call_reg(func: Register) -> Self {
+        Self::CallReg { func }
+    }
+
+    /// Loads the address of func from its GOT entry
+    fn load_got(dest: Register, func: String) -> Self {
+        Self::LoadGot { dest, func }
+    }
+
+    fn label(label: Label) -> Self {
+        Self::Label { label }
+    }
+
+    fn ret() -> Self {
+        Self::Ret
+    }
+}
+
+impl fmt::Display for Instruction {
+    #[rustfmt::skip]
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Instruction::Empty                          => write!(f, ""),
+            Instruction::Custom { string }              => write!(f, "{string}"),
+            Instruction::Label { label }                => write!(f, "label_{}:", label.id()),
+            Instruction::MovReg { dest, src }           => {
+                match (dest.is_float(), src.is_float()) {
+                    (false, false)                        => write!(f, "    mov {dest}, {src}"),
+                    (true, true)                          => write!(f, "    movaps {dest}, {src}"),
+                    _ if dest.size() == Size::DoubleWord
+                        || src.size() == Size::DoubleWord => write!(f, "    movd {dest}, {src}"),
+                    _                                     => write!(f, "    movq {dest}, {src}"),
+                }
+            }
+            Instruction::MovImm { dest, imm }           => {
+                match dest.size() {
+                    Size::Byte       => write!(f, "    mov {dest}, {}", *imm as u8),
+                    Size::Word       => write!(f, "    mov {dest}, {}", *imm as u16),
+                    Size::DoubleWord => write!(f, "    mov {dest}, {}", *imm as u32),
+                    Size::QuadWord   => write!(f, "    mov {dest}, {}", *imm as i64),
+                    Size::F32 | Size::F64 => unreachable!("Floats are moved in through r11"),
+                }
+            }
+            Instruction::MovExtend { dest, src, signed } => {
+                match (src.size(), *signed) {
+                    (Size::DoubleWord, _) => write!(f, "    movsxd {dest}, {src}"),
+                    (_, true)             => write!(f, "    movsx {dest}, {src}"),
+                    (_, false)            => write!(f, "    movzx {dest}, {src}"),
+                }
+            }
+            Instruction::Load { dest, addr, offset }    => write!(f, "    {} {dest}, {}", mov_mnemonic(*dest), Memory(*addr, *offset)),
+            Instruction::Store { src, addr, offset }    => write!(f, "    {} {}, {src}", mov_mnemonic(*src), Memory(*addr, *offset)),
+            Instruction::LoadIndex { dest, addr, index, scale } => write!(f, "    {} {dest}, {}", mov_mnemonic(*dest), IndexedMemory(*addr, *index, *scale)),
+            Instruction::StoreIndex { src, addr, index, scale } => write!(f, "    {} {}, {src}", mov_mnemonic(*src), IndexedMemory(*addr, *index, *scale)),
+            Instruction::Lea { dest, symbol }           => write!(f, "    lea {dest}, [rip + {symbol}]"),
+            Instruction::LeaMem { dest, addr, offset }  => write!(f, "    lea {dest}, {}", Memory(*addr, *offset)),
+            Instruction::Push { src }                   => write!(f, "    push {src}"),
+            Instruction::Pop { dest }                   => write!(f, "    pop {dest}"),
+            Instruction::Add { dest, src }              => write!(f, "    add {dest}, {src}"),
+            Instruction::AddImm { dest, imm }           => write!(f, "    add {dest}, {imm}"),
+            Instruction::AddMem { dest, addr, offset }  => write!(f, "    add {dest}, {}", Memory(*addr, *offset)),
+            Instruction::AddImmMem { size, addr, offset, imm } => write!(f, "    add {} ptr {}, {imm}", ptr_size(*size), Memory(*addr, *offset)),
+            Instruction::Sub { dest, src }              => write!(f, "    sub {dest}, {src}"),
+            Instruction::SubImm { dest, imm }           => write!(f, "    sub {dest}, {imm}"),
+            Instruction::IMul { dest, src }             => write!(f, "    imul {dest}, {src}"),
+            Instruction::Div { src, signed }            => write!(f, "    {}div {src}", if *signed { "i" } else { "" }),
+            Instruction::SignExtendRax { size }         => write!(f, "    {}", if *size == Size::QuadWord { "cqo" } else { "cdq" }),
+            Instruction::Neg { dest }                   => write!(f, "    neg {dest}"),
+            Instruction::And { dest, src }              => write!(f, "    and {dest}, {src}"),
+            Instruction::Or { dest, src }               => write!(f, "    or {dest}, {src}"),
+            Instruction::Xor { dest, src }              => write!(f, "    xor {dest}, {src}"),
+            Instruction::Not { dest }                   => write!(f, "    not {dest}"),
+            Instruction::Shl { dest }                   => write!(f, "    shl {dest}, cl"),
+            Instruction::Shr { dest }                   => write!(f, "    shr {dest}, cl"),
+            Instruction::Sar { dest }                   => write!(f, "    sar {dest}, cl"),
+            Instruction::Cmp { src_1, src_2 }           => write!(f, "    cmp {src_1}, {src_2}"),
+            Instruction::CmpImm { src, imm }            => write!(f, "    cmp {src}, {imm}"),
+            Instruction::Test { src_1, src_2 }          => write!(f, "    test {src_1}, {src_2}"),
+            Instruction::SetCC { dest, cond }           => write!(f, "    set{} {dest}", condition_code(*cond)),
+            Instruction::SetParity { dest, set }        => write!(f, "    set{}p {dest}", if *set { "" } else { "n" }),
+            Instruction::ShrImm { dest, imm }           => write!(f, "    shr {dest}, {imm}"),
+            Instruction::Btc { dest, bit }              => write!(f, "    btc {dest}, {bit}"),
+            Instruction::Bts { dest, bit }              => write!(f, "    bts {dest}, {bit}"),
+            Instruction::FloatOp { op, dest, src }      => write!(f, "    {op}{} {dest}, {src}", float_suffix(dest.size())),
+            Instruction::Ucomis { src_1, src_2 }        => write!(f, "    ucomi{} {src_1}, {src_2}", float_suffix(src_1.size())),
+            Instruction::Cvt { dest, src }              => {
+                match (src.size(), dest.size()) {
+                    (Size::F32, Size::F64) => write!(f, "    cvtss2sd {dest}, {src}"),
+                    (Size::F64, Size::F32) => write!(f, "    cvtsd2ss {dest}, {src}"),
+                    (size, _) if size.is_float() => write!(f, "    cvtt{}2si {dest}, {src}", float_suffix(size)),
+                    (_, size)              => write!(f, "    cvtsi2{} {dest}, {src}", float_suffix(size)),
+                }
+            }
+            Instruction::Jmp { label }                  => write!(f, "    jmp label_{}", label.id()),
+            Instruction::Jcc { cond, label }            => write!(f, "    j{} label_{}", condition_code(*cond), label.id()),
+            Instruction::Call { func }                  => write!(f, "    call {func}"),
+            Instruction::CallReg { func }               => write!(f, "    call {func}"),
+            Instruction::LoadGot { dest, func }         => write!(f, "    mov {dest}, [rip + {func}@GOTPCREL]"),
+            Instruction::Ret                            => write!(f, "    ret"),
+            Instruction::Section { name }               => write!(f, "{name}"),
+
+            Instruction::Data { directive }             => write!(f, "{directive}"),
+        }
+    }
+}
+
+/// Mnemonic for moving reg to or from memory
+fn mov_mnemonic(reg: Register) -> &'static str {
+    match reg.size() {
+        Size::F32 => "movss",
+        Size::F64 => "movsd",
+        _ => "mov",
+    }
+}
+
+/// Size keyword of a memory operand with no register to take the size from
+fn ptr_size(size: Size) -> &'static str {
+    match size {
+        Size::Byte => "byte",
+        Size::Word => "word",
+        Size::DoubleWord => "dword",
+        Size::QuadWord => "qword",
+        Size::F32 | Size::F64 => unreachable!("{size:?} is not an integer"),
+    }
+}
+
+fn float_suffix(size: Size) -> &'static str {
+    match size {
+        Size::F32 => "ss",
+        Size::F64 => "sd",
+        _ => unreachable!("{size:?} is not a float"),
+    }
+}
+
+/// Memory operand at `[addr + offset]`
+struct Memory(Register, i32);
+
+impl fmt::Display for Memory {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let Memory(addr, offset) = self;
+
+        match offset {
+            0 => write!(f, "[{addr}]"),
+            offset if *offset < 0 => write!(f, "[{addr} - {}]", offset.unsigned_abs()),
+            offset => write!(f, "[{addr} + {offset}]"),
+        }
+    }
+}
+
+/// Memory operand at `[addr + index * scale]`
+struct IndexedMemory(Register, Register, u8);
+
+impl fmt::Display for IndexedMemory {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let IndexedMemory(addr, index, scale) = self;
+
+        match scale {
+            1 => write!(f, "[{addr} + {index}]"),
+            scale => write!(f, "[{addr} + {index}*{scale}]"),
+        }
+    }
+}
+
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub(crate) struct Register {
+    number: RegisterNumber,
+    size: Size,
+}
+
+impl Register {
+    pub(crate) fn new(number: RegisterNumber, size: Size) -> Self {
+        Self { number, size }
+    }
+
+    fn size(&self) -> Size {
+        self.size
+    }
+
+    fn is_float(&self) -> bool {
+        self.number.is_float()
+    }
+
+    fn rsp() -> Self {
+        Self::new(RegisterNumber::RSP, Size::QuadWord)
+    }
+
+    fn rbp() -> Self {
+        Self::new(RegisterNumber::RBP, Size::QuadWord)
+    }
+
+    fn rax(size: Size) -> Self {
+        Self::new(RegisterNumber::RAX, size)
+    }
+
+    fn rcx(size: Size) -> Self {
+        Self::new(RegisterNumber::RCX, size)
+    }
+
+    fn rdx(size: Size) -> Self {
+        Self::new(RegisterNumber::RDX, size)
+    }
+
+    fn r11(size: Size) -> Self {
+        Self::new(RegisterNumber::R11, size)
+    }
+
+    /// rax or xmm0, depending on the size
+    fn result(size: Size) -> Self {
+        match size {
+            Size::F32 | Size::F64 => Self::new(RegisterNumber::XMM0, size),
+            _ => Self::rax(size),
+        }
+    }
+
+    /// r11 or xmm15, depending on the size
+    fn scratch(size: Size) -> Self {
+        match size {
+            Size::F32 | Size::F64 => Self::new(RegisterNumber::XMM15, size),
+            _ => Self::r11(size),
+        }
+    }
+}
+
+impl MachineRegister for Register {
+    type Number = RegisterNumber;
+
+    fn number(self) -> RegisterNumber {
+        self.number
+    }
+
+    fn size(self) -> Size {
+        self.size
+    }
+
+    fn resize(self, size: Size) -> Self {
+        Register::new(self.number, size)
+    }
+
+    fn scratch(size: Size) -> Self {
+        Register::scratch(size)
+    }
+}
+
+impl fmt::Display for Register {
+    #[rustfmt::skip]
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        // Names of the first 8 registers by size, the rest follow r8b/r8w/r8d/r8
+        const LEGACY_NAMES: [[&str; 4]; 8] = [
+            ["al",  "ax", "eax", "rax"],
+            ["cl",  "cx", "ecx", "rcx"],
+            ["dl",  "dx", "edx", "rdx"],
+            ["bl",  "bx", "ebx", "rbx"],
+            ["spl", "sp", "esp", "rsp"],
+            ["bpl", "bp", "ebp", "rbp"],
+            ["sil", "si", "esi", "rsi"],
+            ["dil", "di", "edi", "rdi"],
+        ];
+
+        let reg_num = self.number as usize;
+        if self.is_float() {
+            assert!(self.size.is_float(), "Integer value in xmm{}", reg_num - RegisterNumber::XMM0 as usize);
+            return write!(f, "xmm{}", reg_num - RegisterNumber::XMM0 as usize);
+        }
+
+        if let Some(names) = LEGACY_NAMES.get(reg_num) {
+            return match self.size {
+                Size::Byte       => write!(f, "{}", names[0]),
+                Size::Word       => write!(f, "{}", names[1]),
+                Size::DoubleWord => write!(f, "{}", names[2]),
+                Size::QuadWord   => write!(f, "{}", names[3]),
+                Size::F32 | Size::F64 => unreachable!("Float value in {}", names[3]),
+            };
+        }
+
+        match self.size {
+            Size::Byte       => write!(f, "r{reg_num}b"),
+            Size::Word       => write!(f, "r{reg_num}w"),
+            Size::DoubleWord => write!(f, "r{reg_num}d"),
+            Size::QuadWord   => write!(f, "r{reg_num}"),
+            Size::F32 | Size::F64 => unreachable!("Float value in r{reg_num}"),
+        }
+    }
+}
+
+#[rustfmt::skip]
+#[allow(unused, clippy::upper_case_acronyms)]
+#[derive(Clone, Copy, PartialEq, Eq, Hash)]
+pub(crate) enum RegisterNumber {
+    RAX,  RCX,  RDX,   RBX,   RSP,   RBP,   RSI,   RDI,
+    R8,   R9,   R10,   R11,   R12,   R13,   R14,   R15,
+    XMM0, XMM1, XMM2,  XMM3,  XMM4,  XMM5,  XMM6,  XMM7,
+    XMM8, XMM9, XMM10, XMM11, XMM12, XMM13, XMM14, XMM15,
+}
+
+impl RegisterNumber {
+    #[rustfmt::skip]
+    fn from_u8(number: u8) -> Self {
+        use RegisterNumber::*;
+        [
+            RAX,  RCX,  RDX,   RBX,   RSP,   RBP,   RSI,   RDI,
+            R8,   R9,   R10,   R11,   R12,   R13,   R14,   R15,
+            XMM0, XMM1, XMM2,  XMM3,  XMM4,  XMM5,  XMM6,  XMM7,
+            XMM8, XMM9, XMM10, XMM11, XMM12, XMM13, XMM14, XMM15,
+        ][number as usize]
+    }
+
+    fn is_float(self) -> bool {
+        self as u8 >= RegisterNumber::XMM0 as u8
+    }
+}
+
+/// Every register except rsp, rbp and r11, which is kept as a scratch register
+#[rustfmt::skip]
+fn usable_registers() -> Vec<RegisterNumber> {
+    use RegisterNumber::*;
+    vec![
+        RSI, RDI, R8,  R9,  R10, RCX, RDX,
+        RAX, RBX, R12, R13, R14, R15,
+    ]
+}
+
+/// Every xmm register except xmm15, which is kept as a scratch register.
+/// Arg registers come last
+#[rustfmt::skip]
+fn usable_float_registers() -> Vec<RegisterNumber> {
+    use RegisterNumber::*;
+    vec![
+        XMM8, XMM9, XMM10, XMM11, XMM12, XMM13, XMM14,
+        XMM7, XMM6, XMM5,  XMM4,  XMM3,  XMM2,  XMM1, XMM0,
+    ]
+}
+
+/// Registers a callee does not have to preserve
+#[rustfmt::skip]
+fn caller_saved_registers() -> Vec<RegisterNumber> {
+    use RegisterNumber::*;
+    vec![
+        RAX,  RCX,  RDX,   RSI,   RDI,
+        R8,   R9,   R10,   R11,
+        XMM0, XMM1, XMM2,  XMM3,  XMM4,  XMM5,  XMM6,  XMM7,
+        XMM8, XMM9, XMM10, XMM11, XMM12, XMM13, XMM14, XMM15,
+    ]
+}
+
+fn callee_saved_registers() -> Vec<RegisterNumber> {
+    use RegisterNumber::*;
+    vec![RBX, R12, R13, R14, R15]
+}
+
+const ARG_REGISTERS: [RegisterNumber; 6] = [
+    RegisterNumber::RDI,
+    RegisterNumber::RSI,
+    RegisterNumber::RDX,
+    RegisterNumber::RCX,
+    RegisterNumber::R8,
+    RegisterNumber::R9,
+];
+
+#[rustfmt::skip]
+const FLOAT_ARG_REGISTERS: [RegisterNumber; 8] = [
+    RegisterNumber::XMM0, RegisterNumber::XMM1, RegisterNumber::XMM2, RegisterNumber::XMM3,
+    RegisterNumber::XMM4, RegisterNumber::XMM5, RegisterNumber::XMM6, RegisterNumber::XMM7,
+];
+
+/// Ints and floats are counted separately, `arg_num` is the index among args of the same class
+fn arg_register(arg_num: usize, size: Size) -> Option<Register> {
+    let registers: &[RegisterNumber] = if size.is_float() {
+        &FLOAT_ARG_REGISTERS
+    } else {
+        &ARG_REGISTERS
+    };
+
+    registers
+        .get(arg_num)
+        .map(|number| Register::new(*number, size))
+}
+
+/// Register of each arg, or its index among the args passed on the stack
+fn arg_locations(sizes: impl Iterator<Item = Size>) -> Vec<Result<Register, usize>> {
+    let mut int_args = 0;
+    let mut float_args = 0;
+    let mut stack_args = 0;
+
+    sizes
+        .map(|size| {
+            let arg_num = if size.is_float() {
+                &mut float_args
+            } else {
+                &mut int_args
+            };
+
+            let location = arg_register(*arg_num, size).ok_or(stack_args);
+            *arg_num += 1;
+            if location.is_err() {
+                stack_args += 1;
+            }
+
+            location
+        })
+        .collect()
+}
+
+fn condition_code(cond: ir::Condition) -> &'static str {
+    match cond {
+        ir::Condition::Eq => "e",
+        ir::Condition::Ne => "ne",
+        ir::Condition::Slt => "l",
+        ir::Condition::Sle => "le",
+        ir::Condition::Sgt => "g",
+        ir::Condition::Sge => "ge",
+        ir::Condition::Ult => "b",
+        ir::Condition::Ule => "be",
+        ir::Condition::Ugt => "a",
+        ir::Condition::Uge => "ae",
+        ir::Condition::Flt | ir::Condition::Fle | ir::Condition::Fgt | ir::Condition::Fge => {
+            unreachable!("Float conditions are mapped to unsigned ones")
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        ir::{Function, Module, Signature, Value},
+        util::{Liveness, RegisterAllocator},
+    };
+
+    fn generate(func: Function) -> Vec<String> {
+        let mut module = Module::new();
+        module.add_func(func);
+        module.generate_asm_for(&X86_64).lines().to_vec()
+    }
+
+    fn has_line(lines: &[String], prefix: &str) -> bool {
+        lines.iter().any(|line| line.starts_with(prefix))
+    }
+
+    /// Registers given to the temporaries live across the first instruction matching `is_inst`
+    fn live_across(
+        func: &mut Function,
+        is_inst: fn(&ir::Instruction) -> bool,
+    ) -> Vec<RegisterNumber> {
+        let reg_map = RegisterAllocator::new().allocate(func, &X86_64);
+        let ir = func.instructions();
+
+        let index = ir.iter().position(is_inst).unwrap();
+        Liveness::analyze(ir)
+            .live_after(index)
+            .iter()
+            .filter(|tmp| ir[index].def() != Some(**tmp))
+            .map(|tmp| RegisterNumber::from_u8(reg_map[tmp]))
+            .collect()
+    }
+
+    /// Function keeping `count` values live across a call
+    fn live_across_call(count: usize) -> Function {
+        let mut module = Module::new();
+        let callee = module.declare_func(
+            "callee".to_string(),
+            Signature::new(Vec::new(), Some((Size::QuadWord, true))),
+        );
+
+        let mut func = Function::new("live_across_call".to_string());
+        let values: Vec<_> = (0..count)
+            .map(|value| func.add_inst_set(Value::I64(value as i64)))
+            .collect();
+
+        let mut sum = func.add_inst_call(&callee, Vec::new()).unwrap();
+        for value in values {
+            sum = func.add_inst_add(sum, value);
+        }
+        func.add_inst_return(Some(sum));
+
+        func
+    }
+
+    #[test]
+    fn args_come_in_sysv_registers_then_the_stack() {
+        let mut func = Function::new("args".to_string());
+        for _ in 0..8 {
+            func.add_arg(Size::QuadWord, true);
+        }
+        func.add_inst_return(None);
+
+        let lines = generate(func);
+        for reg in ["rdi", "rsi", "rdx", "rcx", "r8", "r9"] {
+            assert!(lines.iter().any(
+                |line| line.starts_with("    mov [rsp") && line.ends_with(&format!(", {reg}"))
+            ));
+        }
+
+        // Stack args sit above the saved rbp and the return address
+        assert!(has_line(&lines, "    mov r11, [rbp + 16]"));
+        assert!(has_line(&lines, "    mov r11, [rbp + 24]"));
+    }
+
+    #[test]
+    fn calls_pass_args_in_sysv_registers_then_the_stack() {
+        let mut module = Module::new();
+        let callee = module.declare_func(
+            "callee".to_string(),
+            Signature::new(vec![(Size::QuadWord, true); 7], None),
+        );
+
+        let mut func = Function::new("caller".to_string());
+        let args = (0..7)
+            .map(|value| func.add_inst_set(Value::I64(value)))
+            .collect();
+        func.add_inst_call(&callee, args);
+        func.add_inst_return(None);
+
+        let lines = generate(func);
+        let call = lines
+            .iter()
+            .position(|line| line == "    call callee")
+            .unwrap();
+        for reg in ["rdi", "rsi", "rdx", "rcx", "r8", "r9"] {
+            assert!(has_line(&lines[..call], &format!("    mov {reg}, ")));
+        }
+
+        // The one stack arg still takes 16 bytes to keep rsp aligned
+        assert!(has_line(&lines, "    sub rsp, 16"));
+        assert!(has_line(&lines, "    mov [rsp], "));
+        assert!(has_line(&lines, "    add rsp, 16"));
+    }
+
+    #[test]
+    fn variadic_calls_set_al_to_the_number_of_vector_registers() {
+        let mut module = Module::new();
+        let printf = module.declare_func(
+            "printf".to_string(),
+            Signature::new_variadic(vec![(Size::QuadWord, false)], None),
+        );
+
+        let mut func = Function::new("caller".to_string());
+        let format = func.add_inst_set(Value::I64(0));
+        let x = func.add_inst_set(Value::F64(1.0));
+        let y = func.add_inst_set(Value::F64(2.0));
+        let n = func.add_inst_set(Value::I64(3));
+        func.add_inst_call(&printf, vec![format, x, y, n]);
+        func.add_inst_call(&printf, vec![format, n]);
+        func.add_inst_return(None);
+
+        let lines = generate(func);
+        let calls: Vec<_> = lines
+            .iter()
+            .enumerate()
+            .filter(|(_, line)| *line == "    call printf")
+            .map(|(index, _)| index)
+            .collect();
+        assert_eq!(calls.len(), 2);
+        assert_eq!(lines[calls[0] - 1], "    mov eax, 2");
+        assert_eq!(lines[calls[1] - 1], "    xor eax, eax");
+    }
+
+    #[test]
+    fn variadic_functions_save_the_arg_registers_in_176_bytes() {
+        let mut func = Function::new("va".to_string());
+        func.make_variadic();
+        func.add_arg(Size::DoubleWord, true);
+        let va_list = func.add_va_list();
+        let addr = func.add_inst_slot_addr(va_list);
+        func.add_inst_va_start(addr);
+        let value = func.add_inst_va_arg(addr, Size::QuadWord, true);
+        func.add_inst_va_end(addr);
+        func.add_inst_return(Some(value));
+
+        let lines = generate(func);
+        let save: i32 = lines
+            .iter()
+            .find_map(|line| line.strip_prefix("    mov [rsp + ")?.strip_suffix("], rdi"))
+            .unwrap()
+            .parse()
+            .unwrap();
+        let size: i32 = lines
+            .iter()
+            .find_map(|line| line.strip_prefix("    sub rsp, "))
+            .unwrap()
+            .parse()
+            .unwrap();
+
+        // 8 bytes for each of the 6 int arg registers, then 16 for each xmm register
+        for (index, reg) in ["rdi", "rsi", "rdx", "rcx", "r8", "r9"].iter().enumerate() {
+            let line = format!("    mov [rsp + {}], {reg}", save + index as i32 * 8);
+            assert!(has_line(&lines, &line));
+        }
+        for index in 0..8 {
+            let line = format!("    movsd [rsp + {}], xmm{index}", save + 48 + index * 16);
+            assert!(has_line(&lines, &line));
+        }
+        assert!(save + 176 <= size);
+
+        // reg_save_area points at the save area, gp_offset and fp_offset past the named arg
+        assert!(has_line(&lines, &format!("    lea r11, [rsp + {save}]")));
+        assert!(has_line(&lines, "    mov r11d, 8"));
+        assert!(has_line(&lines, "    mov r11d, 48"));
+    }
+
+    #[test]
+    fn odd_saved_registers_keep_calls_aligned() {
+        // rsp is 16 byte aligned after pushing rbp, so one more push needs 8 bytes of padding
+        let lines = generate(live_across_call(1));
+        assert!(has_line(&lines, "    push rbx"));
+        assert!(has_line(&lines, "    sub rsp, 8"));
+        assert!(has_line(&lines, "    add rsp, 8"));
+
+        let lines = generate(live_across_call(2));
+        assert!(has_line(&lines, "    push rbx"));
+        assert!(has_line(&lines, "    push r12"));
+        assert!(!has_line(&lines, "    sub rsp"));
+    }
+
+    #[test]
+    fn values_live_across_calls_avoid_caller_saved_registers() {
+        let mut func = live_across_call(3);
+        let live = live_across(&mut func, |inst| {
+            matches!(inst, ir::Instruction::Call { .. })
+        });
+
+        assert_eq!(live.len(), 3);
+        for number in live {
+            assert!(callee_saved_registers().contains(&number));
+        }
+    }
+
+    #[test]
+    fn division_clobbers_rax_and_rdx() {
+        for op in [Function::add_inst_sdiv, Function::add_inst_srem] {
+            let mut func = Function::new("division".to_string());
+            let a = func.add_inst_set(Value::I64(7));
+            let b = func.add_inst_set(Value::I64(2));
+            let c = func.add_inst_set(Value::I64(3));
+            let quotient = op(&mut func, a, b);
+            let sum = func.add_inst_add(quotient, a);
+            let sum = func.add_inst_add(sum, b);
+            let sum = func.add_inst_add(sum, c);
+            func.add_inst_return(Some(sum));
+
+            let live = live_across(&mut func, |inst| {
+                matches!(
+                    inst,
+                    ir::Instruction::Div { .. } | ir::Instruction::Rem { .. }
+                )
+            });
+
+            assert_eq!(live.len(), 3);
+            for number in live {
+                assert!(number != RegisterNumber::RAX && number != RegisterNumber::RDX);
+            }
+        }
+    }
+
+    #[test]
+    fn shifts_clobber_rcx() {
+        for op in [
+            Function::add_inst_shl,
+            Function::add_inst_lshr,
+            Function::add_inst_ashr,
+        ] {
+            let mut func = Function::new("shift".to_string());
+            let a = func.add_inst_set(Value::I64(7));
+            let b = func.add_inst_set(Value::I64(2));
+            let c = func.add_inst_set(Value::I64(3));
+            let shifted = op(&mut func, a, b);
+            let sum = func.add_inst_add(shifted, a);
+            let sum = func.add_inst_add(sum, b);
+            let sum = func.add_inst_add(sum, c);
+            func.add_inst_return(Some(sum));
+
+            let live = live_across(&mut func, |inst| {
+                matches!(
+                    inst,
+                    ir::Instruction::Shl { .. }
+                        | ir::Instruction::LShr { .. }
+                        | ir::Instruction::AShr { .. }
+                )
+            });
+
+            assert_eq!(live.len(), 3);
+            assert!(!live.contains(&RegisterNumber::RCX));
+        }
+    }
+}